@@ -16,23 +16,26 @@ async fn main() -> Result<(), GError> {
     let mail = "me@kingtous.cn";
     // http handler for compatiblity
     let server_config = RouterConfig {
-        server_name: server_name.to_string(),
-        listen_port: vec![80, 443],
-        rules: vec![RouterRule {
-            path: "/".to_string(),
-            proxy_pass: Domain::with_uri("https://cv.kingtous.cn".parse().unwrap()),
-        }],
+        rules: vec![RouterRule::new(
+            "/",
+            Domain::with_uri("https://cv.kingtous.cn".parse().unwrap()),
+        )],
         tls: Some(TlsConfig {
             mail: mail.to_string(),
             // None to use prebuilt acme support
             chain: None,
             private_key: None,
+            ..Default::default()
         }),
+        ..RouterConfig::new(server_name.to_string(), vec![80, 443])
     };
     let conf = RoutersConfig {
         configs: vec![server_config],
+        upstreams: vec![],
+        error_pages: Default::default(),
+        admin: None,
     };
-    let router = Router::build_with_config(conf);
+    let router = Router::build_with_config(conf)?;
     let gws = Gateway::from_router(router);
     let _ = gws.serve().await;
     Ok(())
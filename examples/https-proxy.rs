@@ -14,22 +14,22 @@ async fn main() -> Result<(), anyhow::Error> {
     let domain = Domain::with_uri("http://127.0.0.1:8000".parse()?);
     let server_name = "monoio-gateway.kingtous.cn".to_string();
     let router_config = RouterConfig {
-        server_name: server_name.clone(),
-        listen_port: vec![80, 443],
-        rules: vec![RouterRule {
-            path: "/".to_string(),
-            proxy_pass: domain.clone(),
-        }],
+        rules: vec![RouterRule::new("/", domain.clone())],
         tls: Some(TlsConfig {
             mail: "me@kingtous.cn".into(),
             chain: None,
             private_key: None,
+            ..Default::default()
         }),
+        ..RouterConfig::new(server_name.clone(), vec![80, 443])
     };
     let conf = RoutersConfig {
         configs: vec![router_config],
+        upstreams: vec![],
+        error_pages: Default::default(),
+        admin: None,
     };
-    let router = Router::build_with_config(conf);
+    let router = Router::build_with_config(conf)?;
     let gws = Gateway::from_router(router);
     let _ = gws.serve().await;
     Ok(())
@@ -17,18 +17,17 @@ pub async fn main() -> Result<(), GError> {
     let server_name = "python.server:5000".to_string();
     let listen_port = 5000;
     let router_config = RouterConfig {
-        server_name: server_name.clone(),
-        listen_port: vec![listen_port],
-        rules: vec![RouterRule {
-            path: "/".to_string(),
-            proxy_pass: domain.clone(),
-        }],
+        rules: vec![RouterRule::new("/", domain.clone())],
         tls: None,
+        ..RouterConfig::new(server_name.clone(), vec![listen_port])
     };
     let conf = RoutersConfig {
         configs: vec![router_config],
+        upstreams: vec![],
+        error_pages: Default::default(),
+        admin: None,
     };
-    let router = Router::build_with_config(conf);
+    let router = Router::build_with_config(conf)?;
     let gws = Gateway::from_router(router);
     let _ = gws.serve().await;
     Ok(())
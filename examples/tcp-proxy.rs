@@ -15,13 +15,9 @@ async fn main() -> Result<(), anyhow::Error> {
     let _listen_port = 5000;
     let server_name = "".to_string();
     let router_config = vec![RouterConfig {
-        server_name: server_name.to_owned(),
-        listen_port: vec![80],
-        rules: vec![RouterRule {
-            path: "".to_string(),
-            proxy_pass: target.clone(),
-        }],
+        rules: vec![RouterRule::new("", target.clone())],
         tls: None,
+        ..RouterConfig::new(server_name.to_owned(), vec![80])
     }];
     let tcp_proxy = TcpProxy::build_with_config(&router_config);
     tcp_proxy.io_loop().await?;
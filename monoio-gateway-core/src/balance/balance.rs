@@ -1,11 +1,897 @@
-use crate::{discover::Discover, service::SvcList};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
 
-// /// Load Balancer
-pub struct Balance<D, S>
+use anyhow::bail;
+use http::Request;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use super::{
+    health::{register_endpoint, unregister_endpoint, EndpointHealth, HealthCheck},
+    outlier::{now_millis, CircuitBreaker, ConnectionSlot, OutlierDetection, OutlierStats},
+    weighted::{random, sticky_hash, StickyKey, WeightedTarget},
+};
+use crate::{
+    discover::{change::DiscoverChange, Discover},
+    dns::Resolvable,
+    error::GError,
+    http::{
+        keepalive::UpstreamKeepalive,
+        ssl::{prepare_upstream_tls, UpstreamTls},
+        version::UpstreamProtocol,
+    },
+};
+
+/// points of a ring hash per unit of weight, the same for all endpoints so
+/// that adding or removing one does not move the points of the others
+const RING_POINTS_PER_WEIGHT: u64 = 160;
+/// most points of a ring hash, fewer points per weight are used above it
+const RING_MAX_POINTS: u64 = 1 << 16;
+/// size of a maglev lookup table, must be a prime
+const MAGLEV_TABLE_SIZE: usize = 65537;
+
+/// A named group of endpoints rules can proxy to with `"upstream": "<name>"`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpstreamConfig<A> {
+    pub name: String,
+    #[serde(default)]
+    pub policy: LbPolicy,
+    #[serde(default = "Vec::new")]
+    pub endpoints: Vec<WeightedTarget<A>>,
+    /// active checks taking unhealthy endpoints out of selection
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    /// ejection of endpoints failing proxied requests
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreaker,
+    /// settings of https endpoints and their health checks
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    #[serde(default)]
+    pub keepalive: UpstreamKeepalive,
+}
+
+/// Load balancing algorithm of an upstream group.
+///
+/// Endpoints with weight `0`, unhealthy and ejected endpoints are never
+/// picked. Hash policies fall back to weighted random for requests without the
+/// hash key.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LbPolicy {
+    #[default]
+    RoundRobin,
+    /// smooth weighted round robin like nginx
+    WeightedRoundRobin,
+    /// fewest requests in flight relative to weight
+    LeastRequest,
+    /// the less loaded of two random endpoints
+    #[serde(alias = "p2c")]
+    PowerOfTwoChoices,
+    /// random by weight
+    Random,
+    /// consistent hashing on a ring, `{"ring_hash": {"cookie": "session"}}`
+    RingHash(StickyKey),
+    /// consistent hashing with a maglev lookup table, `{"maglev": "client_ip"}`
+    Maglev(StickyKey),
+}
+
+/// An endpoint of an upstream group.
+pub struct Endpoint<A> {
+    pub address: A,
+    pub weight: u32,
+    /// identity of the endpoint for consistent hashing
+    key: String,
+    outstanding: AtomicUsize,
+    health: Arc<EndpointHealth>,
+    outlier: OutlierStats,
+}
+
+impl<A> Endpoint<A> {
+    /// requests picked and not finished yet, from all workers
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn health(&self) -> &Arc<EndpointHealth> {
+        &self.health
+    }
+
+    pub fn outlier(&self) -> &OutlierStats {
+        &self.outlier
+    }
+
+    #[inline]
+    pub fn is_ejected(&self) -> bool {
+        self.outlier.is_ejected(now_millis())
+    }
+
+    /// weight of lookup tables, `0` while unhealthy
+    #[inline]
+    pub fn healthy_weight(&self) -> u32 {
+        if self.health.is_healthy() {
+            self.weight
+        } else {
+            0
+        }
+    }
+
+    /// weight used for selection, `0` while unhealthy or ejected
+    #[inline]
+    pub fn available_weight(&self) -> u32 {
+        if self.is_ejected() {
+            0
+        } else {
+            self.healthy_weight()
+        }
+    }
+}
+
+/// A picked endpoint, counted as outstanding until dropped.
+pub struct EndpointGuard<A>(Arc<Endpoint<A>>);
+
+impl<A> EndpointGuard<A> {
+    fn new(endpoint: Arc<Endpoint<A>>) -> Self {
+        endpoint.outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(endpoint)
+    }
+
+    pub fn endpoint(&self) -> &Arc<Endpoint<A>> {
+        &self.0
+    }
+}
+
+impl<A> Deref for EndpointGuard<A> {
+    type Target = A;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.address
+    }
+}
+
+impl<A> Drop for EndpointGuard<A> {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Upstream selected for a request, a fixed address or a balanced endpoint.
+pub enum Upstream<'a, A> {
+    Fixed(&'a A),
+    Balanced(EndpointGuard<A>),
+}
+
+impl<'a, A> Deref for Upstream<'a, A> {
+    type Target = A;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Upstream::Fixed(address) => address,
+            Upstream::Balanced(guard) => guard,
+        }
+    }
+}
+
+/// Endpoints and lookup tables, rebuilt whenever endpoints change.
+struct Snapshot<A> {
+    endpoints: Vec<Arc<Endpoint<A>>>,
+    /// current weights of smooth weighted round robin
+    current: Mutex<Vec<i64>>,
+    /// (point, endpoint index) sorted by point, for [`LbPolicy::RingHash`]
+    ring: Vec<(u64, usize)>,
+    /// endpoint index of each slot, for [`LbPolicy::Maglev`]
+    maglev: Vec<usize>,
+}
+
+/// Runtime state of an upstream group, shared by all workers.
+pub struct UpstreamGroup<A> {
+    name: String,
+    policy: LbPolicy,
+    health_check: Option<HealthCheck>,
+    outlier_detection: Option<OutlierDetection>,
+    circuit_breaker: CircuitBreaker,
+    tls: Option<UpstreamTls>,
+    protocol: UpstreamProtocol,
+    keepalive: UpstreamKeepalive,
+    /// upstream connections open to the endpoints
+    connections: Arc<AtomicUsize>,
+    snapshot: RwLock<Arc<Snapshot<A>>>,
+    next: AtomicUsize,
+}
+
+impl<A> UpstreamGroup<A>
+where
+    A: Resolvable,
+{
+    pub fn new(config: UpstreamConfig<A>) -> Result<Self, GError> {
+        if config.name.is_empty() {
+            bail!("upstream name is empty");
+        }
+        if let Some(check) = &config.health_check {
+            if let Err(err) = check.validate() {
+                bail!("upstream {}: {}", config.name, err);
+            }
+        }
+        if let Some(detection) = &config.outlier_detection {
+            if let Err(err) = detection.validate() {
+                bail!("upstream {}: {}", config.name, err);
+            }
+        }
+        if let Err(err) = prepare_upstream_tls(config.tls.as_ref(), config.protocol) {
+            bail!("upstream {}: {}", config.name, err);
+        }
+        let endpoints = config
+            .endpoints
+            .into_iter()
+            .map(|target| new_endpoint(&config.name, target))
+            .collect();
+        Ok(Self {
+            snapshot: RwLock::new(Arc::new(Snapshot::new(&config.policy, endpoints))),
+            name: config.name,
+            policy: config.policy,
+            health_check: config.health_check,
+            outlier_detection: config.outlier_detection,
+            circuit_breaker: config.circuit_breaker,
+            tls: config.tls,
+            protocol: config.protocol,
+            keepalive: config.keepalive,
+            connections: Default::default(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Add an endpoint, or update the weight of an existing one.
+    pub fn add(&self, target: WeightedTarget<A>) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        let mut endpoints = snapshot.endpoints.clone();
+        match endpoints
+            .iter()
+            .position(|e| e.address == target.proxy_pass)
+        {
+            // outstanding requests of the old one are not moved
+            Some(index) => endpoints[index] = new_endpoint(&self.name, target),
+            None => endpoints.push(new_endpoint(&self.name, target)),
+        }
+        *snapshot = Arc::new(Snapshot::new(&self.policy, endpoints));
+    }
+
+    pub fn remove(&self, address: &A) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        let mut endpoints = snapshot.endpoints.clone();
+        endpoints.retain(|endpoint| {
+            if &endpoint.address == address {
+                unregister_endpoint(&self.name, &endpoint.key);
+                return false;
+            }
+            true
+        });
+        *snapshot = Arc::new(Snapshot::new(&self.policy, endpoints));
+    }
+
+    pub fn apply<K>(&self, change: DiscoverChange<K, WeightedTarget<A>>) {
+        match change {
+            DiscoverChange::Add(_, target) => {
+                info!("upstream {}: add {}", self.name, target.proxy_pass);
+                self.add(target);
+            }
+            DiscoverChange::Remove(_, target) => {
+                info!("upstream {}: remove {}", self.name, target.proxy_pass);
+                self.remove(&target.proxy_pass);
+            }
+            DiscoverChange::None => {}
+        }
+    }
+}
+
+impl<A> UpstreamGroup<A> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn policy(&self) -> &LbPolicy {
+        &self.policy
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub fn tls(&self) -> Option<&UpstreamTls> {
+        self.tls.as_ref()
+    }
+
+    pub fn protocol(&self) -> UpstreamProtocol {
+        self.protocol
+    }
+
+    pub fn keepalive(&self) -> UpstreamKeepalive {
+        self.keepalive
+    }
+
+    /// Rebuild lookup tables after the health of endpoints changed.
+    pub fn refresh(&self) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        let endpoints = snapshot.endpoints.clone();
+        *snapshot = Arc::new(Snapshot::new(&self.policy, endpoints));
+    }
+
+    pub fn endpoints(&self) -> Vec<Arc<Endpoint<A>>> {
+        self.snapshot.read().unwrap().endpoints.clone()
+    }
+
+    /// requests in flight to all endpoints
+    pub fn pending(&self) -> usize {
+        let snapshot = self.snapshot.read().unwrap().clone();
+        snapshot.endpoints.iter().map(|e| e.outstanding()).sum()
+    }
+
+    /// upstream connections open to all endpoints
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Take a connection slot of the circuit breaker, `None` if it is open.
+    pub fn acquire_connection(&self) -> Option<ConnectionSlot> {
+        let slot = ConnectionSlot::acquire(&self.connections, self.circuit_breaker.max_connections);
+        if slot.is_none() {
+            debug!("upstream {}: too many connections", self.name);
+        }
+        slot
+    }
+
+    /// Feed the result of a proxied request to outlier detection.
+    pub fn report(&self, endpoint: &Endpoint<A>, success: bool, latency: Duration) {
+        let detection = match &self.outlier_detection {
+            Some(detection) => detection,
+            None => return,
+        };
+        let now = now_millis();
+        if !endpoint.outlier.record(success, latency, detection, now) {
+            return;
+        }
+        let endpoints = self.endpoints();
+        let ejected = endpoints
+            .iter()
+            .filter(|e| e.outlier.is_ejected(now))
+            .count();
+        if (ejected + 1) * 100 > endpoints.len() * detection.max_ejection_percent as usize {
+            debug!(
+                "upstream {}: {} is failing, but {} endpoints are ejected already",
+                self.name, endpoint.key, ejected
+            );
+            return;
+        }
+        let duration = endpoint.outlier.eject(detection, now);
+        warn!(
+            "upstream {}: eject {} for {}ms",
+            self.name, endpoint.key, duration
+        );
+    }
+
+    /// Pick an endpoint for `req`, `None` if no endpoint is available or the
+    /// circuit breaker is open.
+    pub fn select<B>(&self, req: &Request<B>, client_ip: IpAddr) -> Option<EndpointGuard<A>> {
+        if let Some(max) = self.circuit_breaker.max_pending_requests {
+            let pending = self.pending();
+            if pending >= max {
+                debug!("upstream {}: {} requests pending", self.name, pending);
+                return None;
+            }
+        }
+        let snapshot = self.snapshot.read().unwrap().clone();
+        let index = match &self.policy {
+            LbPolicy::RoundRobin => self.round_robin(&snapshot),
+            LbPolicy::WeightedRoundRobin => snapshot.weighted_round_robin(),
+            LbPolicy::LeastRequest => self.least_request(&snapshot),
+            LbPolicy::PowerOfTwoChoices => snapshot.power_of_two_choices(),
+            LbPolicy::Random => snapshot.random(),
+            LbPolicy::RingHash(key) => match sticky_hash(key, req, client_ip) {
+                Some(hash) => snapshot.ring_hash(hash),
+                None => snapshot.random(),
+            },
+            LbPolicy::Maglev(key) => match sticky_hash(key, req, client_ip) {
+                Some(hash) => snapshot.maglev(hash),
+                None => snapshot.random(),
+            },
+        }?;
+        let endpoint = snapshot.endpoints.get(index)?.clone();
+        Some(EndpointGuard::new(endpoint))
+    }
+
+    /// Pick an endpoint for a retry of `req`, one not `tried` yet if any is
+    /// available, randomly by weight when the policy picks a tried one.
+    pub fn select_retry<B>(
+        &self,
+        req: &Request<B>,
+        client_ip: IpAddr,
+        tried: &[Arc<Endpoint<A>>],
+    ) -> Option<EndpointGuard<A>> {
+        let picked = self.select(req, client_ip)?;
+        let is_tried = |endpoint: &Arc<Endpoint<A>>| tried.iter().any(|t| Arc::ptr_eq(t, endpoint));
+        if !is_tried(picked.endpoint()) {
+            return Some(picked);
+        }
+        let snapshot = self.snapshot.read().unwrap().clone();
+        match snapshot.random_where(|endpoint| !is_tried(endpoint)) {
+            Some(index) => Some(EndpointGuard::new(snapshot.endpoints[index].clone())),
+            // every available endpoint was tried
+            None => Some(picked),
+        }
+    }
+
+    fn round_robin(&self, snapshot: &Snapshot<A>) -> Option<usize> {
+        let len = snapshot.endpoints.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|index| snapshot.endpoints[*index].available_weight() > 0)
+    }
+
+    fn least_request(&self, snapshot: &Snapshot<A>) -> Option<usize> {
+        let len = snapshot.endpoints.len();
+        // start from a moving offset so ties are spread
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .filter(|index| snapshot.endpoints[*index].available_weight() > 0)
+            .reduce(|best, index| snapshot.less_loaded(best, index))
+    }
+}
+
+impl<A> Snapshot<A> {
+    fn new(policy: &LbPolicy, endpoints: Vec<Arc<Endpoint<A>>>) -> Self {
+        let ring = match policy {
+            LbPolicy::RingHash(_) => build_ring(&endpoints),
+            _ => vec![],
+        };
+        let maglev = match policy {
+            LbPolicy::Maglev(_) => build_maglev(&endpoints),
+            _ => vec![],
+        };
+        Self {
+            current: Mutex::new(vec![0; endpoints.len()]),
+            endpoints,
+            ring,
+            maglev,
+        }
+    }
+
+    fn weighted_round_robin(&self) -> Option<usize> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            let weight = endpoint.available_weight() as i64;
+            if weight == 0 {
+                continue;
+            }
+            current[index] += weight;
+            total += weight;
+            match best {
+                Some(best) if current[best] >= current[index] => {}
+                _ => best = Some(index),
+            }
+        }
+        let best = best?;
+        current[best] -= total;
+        Some(best)
+    }
+
+    fn random(&self) -> Option<usize> {
+        self.random_where(|_| true)
+    }
+
+    /// random by weight among the endpoints passing `filter`
+    fn random_where(&self, filter: impl Fn(&Arc<Endpoint<A>>) -> bool) -> Option<usize> {
+        let weight_of = |endpoint: &Arc<Endpoint<A>>| {
+            if filter(endpoint) {
+                endpoint.available_weight() as u64
+            } else {
+                0
+            }
+        };
+        let total: u64 = self.endpoints.iter().map(weight_of).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = random() % total;
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            let weight = weight_of(endpoint);
+            if point < weight {
+                return Some(index);
+            }
+            point -= weight;
+        }
+        None
+    }
+
+    fn power_of_two_choices(&self) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.endpoints.len())
+            .filter(|index| self.endpoints[*index].available_weight() > 0)
+            .collect();
+        match candidates.len() {
+            0 => None,
+            1 => Some(candidates[0]),
+            len => {
+                let first = (random() % len as u64) as usize;
+                // a different second choice
+                let second = (first + 1 + (random() % (len as u64 - 1)) as usize) % len;
+                Some(self.less_loaded(candidates[first], candidates[second]))
+            }
+        }
+    }
+
+    /// the one of `a` and `b` with fewer outstanding requests per weight
+    fn less_loaded(&self, a: usize, b: usize) -> usize {
+        let (ea, eb) = (&self.endpoints[a], &self.endpoints[b]);
+        let load_a = ea.outstanding() as u64 * eb.weight as u64;
+        let load_b = eb.outstanding() as u64 * ea.weight as u64;
+        if load_b < load_a {
+            b
+        } else {
+            a
+        }
+    }
+
+    fn ring_hash(&self, hash: u64) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let len = self.ring.len();
+        let position = self.ring.partition_point(|(point, _)| *point < hash);
+        // clockwise to the next endpoint not ejected
+        (0..len)
+            .map(|offset| self.ring[(position + offset) % len].1)
+            .find(|index| !self.endpoints[*index].is_ejected())
+    }
+
+    fn maglev(&self, hash: u64) -> Option<usize> {
+        if self.maglev.is_empty() {
+            return None;
+        }
+        let len = self.maglev.len();
+        let slot = (hash % len as u64) as usize;
+        (0..len)
+            .map(|offset| self.maglev[(slot + offset) % len])
+            .find(|index| !self.endpoints[*index].is_ejected())
+    }
+}
+
+fn new_endpoint<A: Resolvable>(group: &str, target: WeightedTarget<A>) -> Arc<Endpoint<A>> {
+    let key = target.proxy_pass.to_string();
+    Arc::new(Endpoint {
+        health: register_endpoint(group, &key),
+        key,
+        address: target.proxy_pass,
+        weight: target.weight,
+        outstanding: AtomicUsize::new(0),
+        outlier: Default::default(),
+    })
+}
+
+fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn build_ring<A>(endpoints: &[Arc<Endpoint<A>>]) -> Vec<(u64, usize)> {
+    let total: u64 = endpoints.iter().map(|e| e.healthy_weight() as u64).sum();
+    if total == 0 {
+        return vec![];
+    }
+    let points_per_weight = (RING_MAX_POINTS / total).min(RING_POINTS_PER_WEIGHT);
+    let mut ring = vec![];
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        let weight = endpoint.healthy_weight() as u64;
+        if weight == 0 {
+            continue;
+        }
+        let points = match points_per_weight {
+            // weights too large for a point per unit, spread the maximum
+            0 => (RING_MAX_POINTS * weight / total).max(1),
+            points_per_weight => weight * points_per_weight,
+        };
+        for replica in 0..points {
+            ring.push((hash_of((&endpoint.key, replica)), index));
+        }
+    }
+    ring.sort_unstable();
+    ring
+}
+
+/// Maglev lookup table, endpoints take turns in proportion to their weights.
+fn build_maglev<A>(endpoints: &[Arc<Endpoint<A>>]) -> Vec<usize> {
+    // health may change while building, weights are read once
+    let weights: Vec<u64> = endpoints
+        .iter()
+        .map(|endpoint| endpoint.healthy_weight() as u64)
+        .collect();
+    let candidates: Vec<usize> = (0..endpoints.len())
+        .filter(|index| weights[*index] > 0)
+        .collect();
+    let max_weight = match candidates.iter().map(|index| weights[*index]).max() {
+        Some(max_weight) => max_weight,
+        None => return vec![],
+    };
+    let size = MAGLEV_TABLE_SIZE as u64;
+    // (offset, skip) of each candidate's permutation
+    let permutations: Vec<(u64, u64)> = candidates
+        .iter()
+        .map(|index| {
+            let key = &endpoints[*index].key;
+            (hash_of((key, 0)) % size, hash_of((key, 1)) % (size - 1) + 1)
+        })
+        .collect();
+    let mut next = vec![0_u64; candidates.len()];
+    let mut credit = vec![0_u64; candidates.len()];
+    let mut table = vec![usize::MAX; MAGLEV_TABLE_SIZE];
+    let mut filled = 0;
+    loop {
+        for (i, index) in candidates.iter().enumerate() {
+            credit[i] += weights[*index];
+            if credit[i] < max_weight {
+                continue;
+            }
+            credit[i] -= max_weight;
+            let (offset, skip) = permutations[i];
+            loop {
+                let slot = ((offset + next[i] * skip) % size) as usize;
+                next[i] += 1;
+                if table[slot] == usize::MAX {
+                    table[slot] = *index;
+                    filled += 1;
+                    break;
+                }
+            }
+            if filled == MAGLEV_TABLE_SIZE {
+                return table;
+            }
+        }
+    }
+}
+
+/// Feeds endpoint changes of a [`Discover`] into an [`UpstreamGroup`].
+pub struct Balance<D, A>
 where
-    D: Discover,
-    S: IntoIterator,
+    D: Discover<Service = WeightedTarget<A>>,
 {
     pub discover: D,
-    pub services: SvcList<S>,
+    pub group: Arc<UpstreamGroup<A>>,
+}
+
+impl<D, A> Balance<D, A>
+where
+    D: Discover<Service = WeightedTarget<A>>,
+    A: Resolvable,
+{
+    pub fn new(discover: D, group: Arc<UpstreamGroup<A>>) -> Self {
+        Self { discover, group }
+    }
+
+    /// Apply the next change of `discover`, `false` if there is none.
+    pub async fn poll_discover(&self) -> Result<bool, GError> {
+        match self.discover.discover().await {
+            Ok(Some(change)) => {
+                self.group.apply(change);
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => bail!("discover upstream {}: {}", self.group.name(), err),
+        }
+    }
+
+    /// Apply the changes of `discover` until it has none left, returns how
+    /// many were applied.
+    pub async fn drain(&self) -> Result<usize, GError> {
+        let mut applied = 0;
+        while self.poll_discover().await? {
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, future::Future};
+
+    use super::*;
+    use crate::dns::http::Domain;
+
+    type Change = DiscoverChange<(), WeightedTarget<Domain>>;
+
+    /// discovers the changes it was given, then fails
+    struct Changes(RefCell<VecDeque<Result<Option<Change>, GError>>>);
+
+    impl Discover for Changes {
+        type Key = ();
+
+        type Service = WeightedTarget<Domain>;
+
+        type Error = GError;
+
+        type DiscoverFuture<'a> = impl Future<Output = Result<Option<Change>, GError>> + 'a
+        where
+            Self: 'a;
+
+        fn discover(&self) -> Self::DiscoverFuture<'_> {
+            async move {
+                self.0
+                    .borrow_mut()
+                    .pop_front()
+                    .unwrap_or_else(|| Err(anyhow::anyhow!("no more changes")))
+            }
+        }
+    }
+
+    fn target(host: &str, weight: u32) -> WeightedTarget<Domain> {
+        WeightedTarget::new(Domain::new("http", host, "/"), weight)
+    }
+
+    fn endpoints(weights: &[u32]) -> Vec<Arc<Endpoint<Domain>>> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                let domain = Domain::new("http", &format!("10.0.0.{}:80", i + 1), "/");
+                new_endpoint("balance-test", WeightedTarget::new(domain, *weight))
+            })
+            .collect()
+    }
+
+    /// endpoint key of each slot, comparable across tables
+    fn owners(table: &[usize], endpoints: &[Arc<Endpoint<Domain>>]) -> Vec<String> {
+        table.iter().map(|i| endpoints[*i].key.clone()).collect()
+    }
+
+    fn counts(table: &[usize], len: usize) -> Vec<usize> {
+        let mut counts = vec![0; len];
+        table.iter().for_each(|index| counts[*index] += 1);
+        counts
+    }
+
+    #[test]
+    fn maglev_is_even() {
+        let table = build_maglev(&endpoints(&[1; 5]));
+        assert_eq!(table.len(), MAGLEV_TABLE_SIZE);
+        let fair = MAGLEV_TABLE_SIZE / 5;
+        for count in counts(&table, 5) {
+            assert!(count.abs_diff(fair) <= fair / 100, "{}", count);
+        }
+
+        let counts = counts(&build_maglev(&endpoints(&[1, 3])), 2);
+        assert!(counts[1].abs_diff(counts[0] * 3) <= MAGLEV_TABLE_SIZE / 100);
+    }
+
+    #[test]
+    fn maglev_removal_moves_little() {
+        let all = endpoints(&[1; 5]);
+        let before = owners(&build_maglev(&all), &all);
+        let rest = all[..4].to_vec();
+        let after = owners(&build_maglev(&rest), &rest);
+        let removed = &all[4].key;
+        let kept = before.iter().filter(|key| *key != removed).count();
+        let moved = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| *before != removed && before != after)
+            .count();
+        // slots of the removed endpoint move, few others do
+        assert!(moved * 10 < kept, "{} of {} moved", moved, kept);
+    }
+
+    #[test]
+    fn ring_hash_is_even() {
+        let all = endpoints(&[1; 5]);
+        let snapshot = Snapshot::new(&LbPolicy::RingHash(StickyKey::ClientIp), all);
+        let picks: Vec<usize> = (0..50_000_u64)
+            .map(|i| snapshot.ring_hash(hash_of(i)).unwrap())
+            .collect();
+        let fair = picks.len() / 5;
+        for count in counts(&picks, 5) {
+            assert!(count.abs_diff(fair) <= fair / 5, "{}", count);
+        }
+    }
+
+    #[test]
+    fn ring_hash_removal_keeps_others() {
+        let policy = LbPolicy::RingHash(StickyKey::ClientIp);
+        let all = endpoints(&[1, 2, 1, 1, 3]);
+        let before = Snapshot::new(&policy, all.clone());
+        let after = Snapshot::new(&policy, all[..4].to_vec());
+        let removed = &all[4].key;
+        for i in 0..10_000_u64 {
+            let hash = hash_of(i);
+            let was = &before.endpoints[before.ring_hash(hash).unwrap()].key;
+            let is = &after.endpoints[after.ring_hash(hash).unwrap()].key;
+            if was != removed {
+                assert_eq!(was, is);
+            }
+        }
+    }
+
+    #[monoio::test]
+    async fn feeds_discovered_endpoints() {
+        let config = UpstreamConfig {
+            endpoints: vec![target("10.0.1.1:80", 1)],
+            ..serde_json::from_str(r#"{"name": "discovered"}"#).unwrap()
+        };
+        let group = Arc::new(UpstreamGroup::new(config).unwrap());
+        let changes = vec![
+            Ok(Some(DiscoverChange::Add((), target("10.0.1.2:80", 1)))),
+            Ok(Some(DiscoverChange::Add((), target("10.0.1.3:80", 1)))),
+            Ok(Some(DiscoverChange::None)),
+            Ok(Some(DiscoverChange::Remove((), target("10.0.1.1:80", 1)))),
+            // an existing endpoint gets its new weight
+            Ok(Some(DiscoverChange::Add((), target("10.0.1.3:80", 5)))),
+            Ok(None),
+            Ok(Some(DiscoverChange::Remove((), target("10.0.1.2:80", 1)))),
+            Ok(None),
+        ];
+        let balance = Balance::new(Changes(RefCell::new(changes.into())), group.clone());
+
+        assert_eq!(balance.drain().await.unwrap(), 5);
+        let endpoints: Vec<(String, u32)> = group
+            .endpoints()
+            .iter()
+            .map(|endpoint| (endpoint.key().to_owned(), endpoint.weight))
+            .collect();
+        let expected = vec![
+            (target("10.0.1.2:80", 1).proxy_pass.to_string(), 1),
+            (target("10.0.1.3:80", 1).proxy_pass.to_string(), 5),
+        ];
+        assert_eq!(endpoints, expected);
+
+        // changes after a pause are applied by the next drain
+        assert_eq!(balance.drain().await.unwrap(), 1);
+        assert_eq!(group.endpoints().len(), 1);
+        assert!(balance.poll_discover().await.is_err());
+    }
+
+    #[test]
+    fn circuit_breaker_limits() {
+        let config: UpstreamConfig<Domain> = serde_json::from_str(
+            r#"{
+                "name": "circuit-breaker-test",
+                "endpoints": [{"proxy_pass": {"uri": "http://10.0.0.1:80"}}],
+                "circuit_breaker": {"max_pending_requests": 2, "max_connections": 1}
+            }"#,
+        )
+        .unwrap();
+        let group = UpstreamGroup::new(config).unwrap();
+        let (req, ip) = (Request::new(()), "10.1.0.1".parse().unwrap());
+        let first = group.select(&req, ip).unwrap();
+        let second = group.select(&req, ip).unwrap();
+        assert_eq!(group.pending(), 2);
+        assert!(group.select(&req, ip).is_none());
+        drop(first);
+        assert!(group.select(&req, ip).is_some());
+        drop(second);
+        assert_eq!(group.pending(), 0);
+
+        let slot = group.acquire_connection().unwrap();
+        assert!(group.acquire_connection().is_none());
+        assert_eq!(group.connections(), 1);
+        drop(slot);
+        assert!(group.acquire_connection().is_some());
+        assert_eq!(group.connections(), 0);
+    }
 }
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::bail;
+use log::{debug, info, warn};
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt},
+    net::UnixStream,
+};
+use rustls::ServerName;
+use serde_derive::{Deserialize, Serialize};
+
+use super::balance::UpstreamGroup;
+use crate::{
+    dns::{http::Domain, resolver::SocketAddrs, Resolvable},
+    error::GError,
+    http::{
+        ssl::{get_tls_connector, UpstreamTls},
+        version::Type,
+    },
+    UPSTREAM_HEALTH,
+};
+
+/// bytes of a probe response read at most
+const MAX_PROBE_RESPONSE: usize = 64 * 1024;
+
+/// Health of the endpoints of a group, by endpoint key.
+pub type HealthMap = HashMap<String, Arc<EndpointHealth>>;
+
+/// Active health check of the endpoints of an upstream group.
+///
+/// ```json
+/// {
+///   "probe": {"http": {"path": "/healthz", "expected_status": [200], "body": "ok"}},
+///   "interval": 5000,
+///   "timeout": 1000,
+///   "healthy_threshold": 2,
+///   "unhealthy_threshold": 3
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HealthCheck {
+    #[serde(default)]
+    pub probe: Probe,
+    /// milliseconds between two rounds of checks
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// milliseconds a probe may take, resolving and connecting included
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// consecutive successes to mark an unhealthy endpoint healthy
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    /// consecutive failures to mark a healthy endpoint unhealthy
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_interval() -> u64 {
+    5000
+}
+
+fn default_timeout() -> u64 {
+    2000
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+/// How an endpoint is probed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    /// the endpoint accepts connections, on its unix socket if it has one
+    #[default]
+    Tcp,
+    /// the endpoint answers a `GET` as expected
+    Http(HttpProbe),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpProbe {
+    #[serde(default = "default_probe_path")]
+    pub path: String,
+    /// `Host` of the probe, the authority of the endpoint by default
+    #[serde(default)]
+    pub host: Option<String>,
+    /// accepted status codes, any `2xx` if empty
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    /// text the response body must contain
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+fn default_probe_path() -> String {
+    "/".to_owned()
+}
+
+impl HealthCheck {
+    pub fn validate(&self) -> Result<(), GError> {
+        if self.interval == 0 || self.timeout == 0 {
+            bail!("health check interval and timeout must be positive");
+        }
+        if self.healthy_threshold == 0 || self.unhealthy_threshold == 0 {
+            bail!("health check thresholds must be positive");
+        }
+        if let Probe::Http(http) = &self.probe {
+            if !http.path.starts_with('/') {
+                bail!("health check path {} must start with /", http.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Probe `endpoint` once, `Err` tells why it is considered down.
+    ///
+    /// Https endpoints are reached with `tls`, the default settings if `None`.
+    pub async fn probe(&self, endpoint: &Domain, tls: Option<&UpstreamTls>) -> Result<(), GError> {
+        let probe = async {
+            match endpoint.unix_path() {
+                Some(socket) => self.probe_unix(endpoint, socket).await,
+                None => {
+                    let addr = match endpoint.resolve().await? {
+                        Some(addr) => addr,
+                        None => bail!("{} is not resolved", endpoint),
+                    };
+                    self.probe_addr(endpoint, addr, tls).await
+                }
+            }
+        };
+        match monoio::time::timeout(Duration::from_millis(self.timeout), probe).await {
+            Ok(result) => result,
+            Err(_) => bail!("timeout after {}ms", self.timeout),
+        }
+    }
+
+    /// unix socket endpoints speak plain http
+    async fn probe_unix(&self, endpoint: &Domain, socket: &Path) -> Result<(), GError> {
+        let stream = UnixStream::connect(socket).await?;
+        match &self.probe {
+            Probe::Tcp => Ok(()),
+            Probe::Http(http) => http.exchange(stream, endpoint).await,
+        }
+    }
+
+    async fn probe_addr(
+        &self,
+        endpoint: &Domain,
+        addr: SocketAddrs,
+        tls: Option<&UpstreamTls>,
+    ) -> Result<(), GError> {
+        let stream = addr.connect().await?;
+        let http = match &self.probe {
+            Probe::Tcp => return Ok(()),
+            Probe::Http(http) => http,
+        };
+        match endpoint.version() {
+            Type::HTTP => http.exchange(stream, endpoint).await,
+            Type::HTTPS => {
+                let server_name = match tls {
+                    Some(tls) => tls.server_name(endpoint.host())?,
+                    None => ServerName::try_from(endpoint.host())?,
+                };
+                let stream = get_tls_connector(tls)?.connect(server_name, stream).await?;
+                http.exchange(stream, endpoint).await
+            }
+        }
+    }
+}
+
+impl HttpProbe {
+    fn request(&self, endpoint: &Domain) -> Vec<u8> {
+        let host = match &self.host {
+            Some(host) => host.as_str(),
+            None => endpoint
+                .authority()
+                .map_or("", |authority| authority.as_str()),
+        };
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: monoio-gateway\r\nConnection: close\r\n\r\n",
+            self.path, host
+        )
+        .into_bytes()
+    }
+
+    /// send the probe and read the response until it is verified
+    async fn exchange<S>(&self, mut stream: S, endpoint: &Domain) -> Result<(), GError>
+    where
+        S: AsyncReadRent + AsyncWriteRent,
+    {
+        let (res, _) = stream.write_all(self.request(endpoint)).await;
+        res?;
+        let mut response = Vec::new();
+        let mut buf = vec![0; 4096];
+        while response.len() < MAX_PROBE_RESPONSE {
+            let (res, read) = stream.read(buf).await;
+            buf = read;
+            let len = res?;
+            if len == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..len]);
+            buf.clear();
+            if let Some(result) = self.verify(&response) {
+                return result;
+            }
+        }
+        match self.verify(&response) {
+            Some(result) => result,
+            None if response.is_empty() => bail!("connection closed without response"),
+            None => bail!("response does not match"),
+        }
+    }
+
+    /// Check a raw response, `None` if more of it is needed.
+    pub fn verify(&self, response: &[u8]) -> Option<Result<(), GError>> {
+        let line_end = response.windows(2).position(|w| w == b"\r\n")?;
+        let status = match parse_status(&response[..line_end]) {
+            Some(status) => status,
+            None => return Some(Err(anyhow::anyhow!("malformed status line"))),
+        };
+        let expected = if self.expected_status.is_empty() {
+            (200..300).contains(&status)
+        } else {
+            self.expected_status.contains(&status)
+        };
+        if !expected {
+            return Some(Err(anyhow::anyhow!("unexpected status {}", status)));
+        }
+        let body = match &self.body {
+            Some(body) if !body.is_empty() => body.as_bytes(),
+            _ => return Some(Ok(())),
+        };
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+        response[head_end + 4..]
+            .windows(body.len())
+            .any(|w| w == body)
+            .then_some(Ok(()))
+    }
+}
+
+/// status code of `HTTP/1.1 200 OK`
+fn parse_status(line: &[u8]) -> Option<u16> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.splitn(3, ' ');
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Health state of an endpoint, shared by all workers.
+///
+/// Endpoints start healthy so traffic flows before the first check.
+#[derive(Debug)]
+pub struct EndpointHealth {
+    healthy: AtomicBool,
+    successes: AtomicU32,
+    failures: AtomicU32,
+}
+
+impl Default for EndpointHealth {
+    fn default() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            successes: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+}
+
+impl EndpointHealth {
+    #[inline]
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Record a probe result, `true` if the endpoint changed state.
+    pub fn record(&self, success: bool, check: &HealthCheck) -> bool {
+        let (count, reset, threshold) = if success {
+            (&self.successes, &self.failures, check.healthy_threshold)
+        } else {
+            (&self.failures, &self.successes, check.unhealthy_threshold)
+        };
+        reset.store(0, Ordering::Relaxed);
+        let count = count.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+        count >= threshold && self.healthy.swap(success, Ordering::Relaxed) != success
+    }
+}
+
+/// Health of an endpoint of `group`, registered on first use.
+pub(crate) fn register_endpoint(group: &str, key: &str) -> Arc<EndpointHealth> {
+    let mut registry = UPSTREAM_HEALTH.write().unwrap();
+    registry
+        .entry(group.to_owned())
+        .or_default()
+        .entry(key.to_owned())
+        .or_default()
+        .clone()
+}
+
+pub(crate) fn unregister_endpoint(group: &str, key: &str) {
+    if let Some(endpoints) = UPSTREAM_HEALTH.write().unwrap().get_mut(group) {
+        endpoints.remove(key);
+    }
+}
+
+/// Endpoint keys of the upstream group `name` and whether they are healthy.
+pub fn upstream_health(name: &str) -> Option<Vec<(String, bool)>> {
+    UPSTREAM_HEALTH.read().unwrap().get(name).map(|endpoints| {
+        endpoints
+            .iter()
+            .map(|(key, health)| (key.to_owned(), health.is_healthy()))
+            .collect()
+    })
+}
+
+/// Probe the endpoints of `group` forever, needs a runtime with timer enabled.
+pub async fn check_upstream(group: Arc<UpstreamGroup<Domain>>) {
+    let check = match group.health_check() {
+        Some(check) => check.clone(),
+        None => return,
+    };
+    info!(
+        "upstream {}: checking endpoints every {}ms",
+        group.name(),
+        check.interval
+    );
+    loop {
+        let probes: Vec<_> = group
+            .endpoints()
+            .into_iter()
+            .map(|endpoint| {
+                let check = check.clone();
+                let group = group.clone();
+                monoio::spawn(async move {
+                    let result = check.probe(&endpoint.address, group.tls()).await;
+                    if let Err(err) = &result {
+                        debug!("probe {} failed: {}", endpoint.address, err);
+                    }
+                    (endpoint, result.is_ok())
+                })
+            })
+            .collect();
+        let mut changed = false;
+        for probe in probes {
+            let (endpoint, success) = probe.await;
+            if endpoint.health().record(success, &check) {
+                if success {
+                    info!("upstream {}: {} is healthy", group.name(), endpoint.key());
+                } else {
+                    warn!("upstream {}: {} is unhealthy", group.name(), endpoint.key());
+                }
+                changed = true;
+            }
+        }
+        if changed {
+            group.refresh();
+        }
+        monoio::time::sleep(Duration::from_millis(check.interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_probe(expected_status: &[u16], body: Option<&str>) -> HttpProbe {
+        HttpProbe {
+            path: default_probe_path(),
+            host: None,
+            expected_status: expected_status.to_vec(),
+            body: body.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn verifies_status() {
+        let probe = http_probe(&[], None);
+        let passes = |response: &[u8]| probe.verify(response).map(|result| result.is_ok());
+        assert_eq!(passes(b"HTTP/1.1 204 No Content\r\n"), Some(true));
+        assert_eq!(passes(b"HTTP/1.1 301 Moved\r\n"), Some(false));
+        assert_eq!(passes(b"SSH-2.0-OpenSSH\r\n"), Some(false));
+        // the status line is not complete yet
+        assert_eq!(passes(b"HTTP/1.1 200"), None);
+
+        let probe = http_probe(&[200, 503], None);
+        let passes = |response: &[u8]| probe.verify(response).map(|result| result.is_ok());
+        assert_eq!(passes(b"HTTP/1.1 503 Unavailable\r\n"), Some(true));
+        assert_eq!(passes(b"HTTP/1.1 204 No Content\r\n"), Some(false));
+    }
+
+    #[test]
+    fn verifies_body() {
+        let probe = http_probe(&[], Some("ok"));
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n";
+        assert!(probe.verify(head).is_none());
+        let response = [&head[..], b"status=ok"].concat();
+        assert!(probe.verify(&response).unwrap().is_ok());
+        // the body is looked for in the body only
+        let response = b"HTTP/1.1 200 ok\r\nX-Status: ok\r\n\r\nstatus=down";
+        assert!(probe.verify(response).is_none());
+        // the status is checked first
+        let response = b"HTTP/1.1 500 Error\r\n\r\nok";
+        assert!(probe.verify(response).unwrap().is_err());
+    }
+
+    #[test]
+    fn crosses_thresholds() {
+        let check: HealthCheck =
+            serde_json::from_str(r#"{"healthy_threshold": 2, "unhealthy_threshold": 3}"#).unwrap();
+        let health = EndpointHealth::default();
+        assert!(health.is_healthy());
+        assert!(!health.record(false, &check));
+        assert!(!health.record(false, &check));
+        // a success resets the failures
+        assert!(!health.record(true, &check));
+        assert!(!health.record(false, &check));
+        assert!(!health.record(false, &check));
+        assert!(health.is_healthy());
+        assert!(health.record(false, &check));
+        assert!(!health.is_healthy());
+        assert!(!health.record(false, &check));
+
+        assert!(!health.record(true, &check));
+        assert!(!health.is_healthy());
+        assert!(health.record(true, &check));
+        assert!(health.is_healthy());
+        assert!(!health.record(true, &check));
+    }
+}
@@ -1 +1,4 @@
 pub mod balance;
+pub mod health;
+pub mod outlier;
+pub mod weighted;
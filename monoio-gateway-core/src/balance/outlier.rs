@@ -0,0 +1,356 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::GError;
+
+/// Passive detection of failing endpoints from proxied requests.
+///
+/// Connect errors, upstream resets and `5xx` responses are failures. An
+/// endpoint is ejected after `consecutive_failures` of them in a row, or when
+/// the failure rate or the mean latency of an `interval` is over the limits.
+/// Each ejection of the same endpoint lasts `base_ejection_time` longer than
+/// the previous one, up to `max_ejection_time`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutlierDetection {
+    /// `0` disables ejection by consecutive failures
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    /// percent of failed requests of an interval
+    #[serde(default)]
+    pub failure_rate: Option<u32>,
+    /// mean latency of an interval in milliseconds
+    #[serde(default)]
+    pub max_latency: Option<u64>,
+    /// milliseconds of a rate and latency window
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// requests of a window needed to judge rate and latency
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32,
+    /// milliseconds of the first ejection
+    #[serde(default = "default_base_ejection_time")]
+    pub base_ejection_time: u64,
+    #[serde(default = "default_max_ejection_time")]
+    pub max_ejection_time: u64,
+    /// percent of the endpoints of a group ejected at most
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_interval() -> u64 {
+    10000
+}
+
+fn default_min_requests() -> u32 {
+    20
+}
+
+fn default_base_ejection_time() -> u64 {
+    30000
+}
+
+fn default_max_ejection_time() -> u64 {
+    300000
+}
+
+fn default_max_ejection_percent() -> u32 {
+    50
+}
+
+impl OutlierDetection {
+    pub fn validate(&self) -> Result<(), GError> {
+        if self.interval == 0 || self.base_ejection_time == 0 {
+            bail!("outlier detection interval and ejection time must be positive");
+        }
+        if self.max_ejection_time < self.base_ejection_time {
+            bail!("max_ejection_time is less than base_ejection_time");
+        }
+        if matches!(self.failure_rate, Some(rate) if rate == 0 || rate > 100) {
+            bail!("failure_rate must be a percent between 1 and 100");
+        }
+        if self.max_ejection_percent > 100 {
+            bail!("max_ejection_percent must not be over 100");
+        }
+        Ok(())
+    }
+}
+
+/// Limits of an upstream group, requests over them fail fast with `503`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    /// requests in flight to all endpoints of the group
+    #[serde(default)]
+    pub max_pending_requests: Option<usize>,
+    /// upstream connections open to all endpoints of the group
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+}
+
+/// Failure counters and ejection state of an endpoint, shared by all workers.
+#[derive(Debug, Default)]
+pub struct OutlierStats {
+    consecutive_failures: AtomicU32,
+    window_start: AtomicU64,
+    requests: AtomicU32,
+    failures: AtomicU32,
+    /// sum of request latencies of the window in milliseconds
+    latency: AtomicU64,
+    ejected_until: AtomicU64,
+    ejections: AtomicU32,
+}
+
+impl OutlierStats {
+    #[inline]
+    pub fn is_ejected(&self, now: u64) -> bool {
+        self.ejected_until.load(Ordering::Relaxed) > now
+    }
+
+    /// times the endpoint was ejected lately, decays while it behaves
+    pub fn ejections(&self) -> u32 {
+        self.ejections.load(Ordering::Relaxed)
+    }
+
+    /// Record a request, `true` if the endpoint should be ejected now.
+    pub fn record(
+        &self,
+        success: bool,
+        latency: Duration,
+        detection: &OutlierDetection,
+        now: u64,
+    ) -> bool {
+        if self.is_ejected(now) {
+            // late results of requests sent before the ejection
+            return false;
+        }
+        let consecutive = if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            0
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        let outlier = self.roll_window(detection, now);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency
+            .fetch_add(latency.as_millis() as u64, Ordering::Relaxed);
+        outlier
+            || (detection.consecutive_failures > 0 && consecutive >= detection.consecutive_failures)
+    }
+
+    /// Judge the window once `interval` passed and start a new one.
+    fn roll_window(&self, detection: &OutlierDetection, now: u64) -> bool {
+        let start = self.window_start.load(Ordering::Relaxed);
+        if now < start + detection.interval {
+            return false;
+        }
+        // only one worker judges a window
+        if self
+            .window_start
+            .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        let requests = self.requests.swap(0, Ordering::Relaxed);
+        let failures = self.failures.swap(0, Ordering::Relaxed) as u64;
+        let latency = self.latency.swap(0, Ordering::Relaxed);
+        if requests == 0 || requests < detection.min_requests {
+            self.forgive();
+            return false;
+        }
+        let requests = requests as u64;
+        let failing = matches!(
+            detection.failure_rate,
+            Some(rate) if failures * 100 >= rate as u64 * requests
+        );
+        let slow = matches!(
+            detection.max_latency,
+            Some(max_latency) if latency / requests > max_latency
+        );
+        if !failing && !slow {
+            self.forgive();
+        }
+        failing || slow
+    }
+
+    fn forgive(&self) {
+        let _ = self
+            .ejections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    /// Eject the endpoint, returns the milliseconds it is out of selection.
+    pub fn eject(&self, detection: &OutlierDetection, now: u64) -> u64 {
+        let ejections = self.ejections.fetch_add(1, Ordering::Relaxed) as u64 + 1;
+        let duration = (detection.base_ejection_time * ejections).min(detection.max_ejection_time);
+        self.ejected_until.store(now + duration, Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        duration
+    }
+}
+
+/// A connection slot of a [`CircuitBreaker`], released on drop.
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Take a slot of `open` if fewer than `max` are taken.
+    pub(crate) fn acquire(open: &Arc<AtomicUsize>, max: Option<usize>) -> Option<Self> {
+        let taken = open.fetch_add(1, Ordering::Relaxed);
+        if matches!(max, Some(max) if taken >= max) {
+            open.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Self(open.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// milliseconds since unix epoch, the clock of ejections
+#[inline]
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// some time after the epoch, windows start at `0`
+    const T: u64 = 1_000_000;
+
+    fn detection(json: &str) -> OutlierDetection {
+        let detection: OutlierDetection = serde_json::from_str(json).unwrap();
+        detection.validate().unwrap();
+        detection
+    }
+
+    #[test]
+    fn ejects_consecutive_failures() {
+        let detection = detection(r#"{"consecutive_failures": 3}"#);
+        let stats = OutlierStats::default();
+        let fail = |now| stats.record(false, Duration::ZERO, &detection, now);
+        assert!(!fail(T));
+        assert!(!fail(T));
+        // a success resets the count
+        assert!(!stats.record(true, Duration::ZERO, &detection, T));
+        assert!(!fail(T));
+        assert!(!fail(T));
+        assert!(fail(T));
+
+        stats.eject(&detection, T);
+        assert!(stats.is_ejected(T));
+        // failures of requests sent before the ejection do not count
+        assert!(!fail(T + 1));
+        let back = T + detection.base_ejection_time;
+        assert!(!stats.is_ejected(back));
+        assert!(!fail(back));
+        assert!(!fail(back));
+        assert!(fail(back));
+    }
+
+    #[test]
+    fn ejects_failure_rate() {
+        let detection = detection(
+            r#"{"consecutive_failures": 0, "failure_rate": 50, "min_requests": 4, "interval": 1000}"#,
+        );
+        let stats = OutlierStats::default();
+        for success in [false, true, false, true] {
+            assert!(!stats.record(success, Duration::ZERO, &detection, T));
+        }
+        // judged once the window is over
+        assert!(!stats.record(false, Duration::ZERO, &detection, T + 999));
+        assert!(stats.record(true, Duration::ZERO, &detection, T + 1000));
+
+        // too few requests to judge
+        let stats = OutlierStats::default();
+        for _ in 0..3 {
+            assert!(!stats.record(false, Duration::ZERO, &detection, T));
+        }
+        assert!(!stats.record(true, Duration::ZERO, &detection, T + 1000));
+
+        let stats = OutlierStats::default();
+        for success in [false, true, true, true] {
+            stats.record(success, Duration::ZERO, &detection, T);
+        }
+        assert!(!stats.record(true, Duration::ZERO, &detection, T + 1000));
+    }
+
+    #[test]
+    fn ejects_slow_endpoints() {
+        let detection = detection(
+            r#"{"consecutive_failures": 0, "max_latency": 100, "min_requests": 2, "interval": 1000}"#,
+        );
+        let slow = OutlierStats::default();
+        let fast = OutlierStats::default();
+        for latency in [150, 80] {
+            slow.record(true, Duration::from_millis(latency), &detection, T);
+        }
+        for latency in [150, 50] {
+            fast.record(true, Duration::from_millis(latency), &detection, T);
+        }
+        // the mean latency of the window counts
+        assert!(slow.record(true, Duration::ZERO, &detection, T + 1000));
+        assert!(!fast.record(true, Duration::ZERO, &detection, T + 1000));
+    }
+
+    #[test]
+    fn ejections_grow_up_to_max() {
+        let detection = detection(r#"{"base_ejection_time": 1000, "max_ejection_time": 3500}"#);
+        let stats = OutlierStats::default();
+        assert_eq!(stats.eject(&detection, T), 1000);
+        assert!(stats.is_ejected(T + 999));
+        assert!(!stats.is_ejected(T + 1000));
+        assert_eq!(stats.eject(&detection, T + 1000), 2000);
+        assert_eq!(stats.eject(&detection, T + 3000), 3000);
+        assert_eq!(stats.eject(&detection, T + 6000), 3500);
+        assert!(stats.is_ejected(T + 9499));
+        assert_eq!(stats.ejections(), 4);
+
+        // windows without enough failures shorten the next ejection
+        let now = T + 9500;
+        stats.record(true, Duration::ZERO, &detection, now);
+        stats.record(true, Duration::ZERO, &detection, now + detection.interval);
+        assert_eq!(stats.ejections(), 2);
+        assert_eq!(stats.eject(&detection, now + detection.interval), 3000);
+    }
+
+    #[test]
+    fn connection_slots() {
+        let open = Arc::new(AtomicUsize::new(0));
+        let first = ConnectionSlot::acquire(&open, Some(2)).unwrap();
+        let second = ConnectionSlot::acquire(&open, Some(2)).unwrap();
+        assert!(ConnectionSlot::acquire(&open, Some(2)).is_none());
+        assert_eq!(open.load(Ordering::Relaxed), 2);
+        drop(first);
+        let third = ConnectionSlot::acquire(&open, Some(2)).unwrap();
+        drop((second, third));
+        assert_eq!(open.load(Ordering::Relaxed), 0);
+
+        let slots: Vec<_> = (0..100)
+            .map(|_| ConnectionSlot::acquire(&open, None).unwrap())
+            .collect();
+        assert_eq!(open.load(Ordering::Relaxed), slots.len());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::bail;
+use http::Request;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{error::GError, http::predicate::cookies, SPLIT_WEIGHTS};
+
+/// Weighted upstreams of a rule, for canary and blue/green releases.
+///
+/// ```json
+/// {
+///   "name": "web",
+///   "targets": [
+///     {"proxy_pass": {"uri": "http://blue:8000"}, "weight": 95},
+///     {"proxy_pass": {"uri": "http://green:8000"}, "weight": 5}
+///   ],
+///   "sticky": {"cookie": "session"}
+/// }
+/// ```
+///
+/// Weights of a named split can be changed at runtime by [`set_split_weights`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrafficSplit<A> {
+    /// key of the runtime weights, splits sharing a name share weights
+    #[serde(default)]
+    pub name: Option<String>,
+    pub targets: Vec<WeightedTarget<A>>,
+    /// pick the same target for the same client, random otherwise
+    #[serde(default)]
+    pub sticky: Option<StickyKey>,
+    /// runtime weights, initialized by [`TrafficSplit::compile`]
+    #[serde(skip)]
+    weights: Arc<SplitWeights>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WeightedTarget<A> {
+    pub proxy_pass: A,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl<A> WeightedTarget<A> {
+    pub fn new(proxy_pass: A, weight: u32) -> Self {
+        Self { proxy_pass, weight }
+    }
+}
+
+/// What a client is recognized by for sticky selection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyKey {
+    Cookie(String),
+    Header(String),
+    ClientIp,
+}
+
+/// Weights of the targets of a split, shared by all workers.
+#[derive(Debug, Default)]
+pub struct SplitWeights {
+    weights: Vec<AtomicU32>,
+}
+
+impl SplitWeights {
+    fn new(weights: impl Iterator<Item = u32>) -> Self {
+        Self {
+            weights: weights.map(AtomicU32::new).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+
+    pub fn get(&self) -> Vec<u32> {
+        self.weights
+            .iter()
+            .map(|weight| weight.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn set(&self, weights: &[u32]) -> Result<(), GError> {
+        if weights.len() != self.weights.len() {
+            bail!(
+                "expect {} weights, got {}",
+                self.weights.len(),
+                weights.len()
+            );
+        }
+        for (current, weight) in self.weights.iter().zip(weights) {
+            current.store(*weight, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// index of the target at `point` of the cumulative weights
+    fn pick(&self, point: impl FnOnce(u64) -> u64) -> Option<usize> {
+        let weights = self.get();
+        let total: u64 = weights.iter().map(|w| *w as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = point(total);
+        for (index, weight) in weights.iter().enumerate() {
+            let weight = *weight as u64;
+            if point < weight {
+                return Some(index);
+            }
+            point -= weight;
+        }
+        None
+    }
+}
+
+impl<A> TrafficSplit<A> {
+    pub fn new(targets: Vec<WeightedTarget<A>>) -> Self {
+        let weights = Arc::new(SplitWeights::new(targets.iter().map(|t| t.weight)));
+        Self {
+            name: None,
+            targets,
+            sticky: None,
+            weights,
+        }
+    }
+
+    /// Initialize runtime weights and register them by `name`.
+    pub fn compile(&mut self) -> Result<(), GError> {
+        if self.targets.is_empty() {
+            bail!("split has no targets");
+        }
+        let weights = SplitWeights::new(self.targets.iter().map(|target| target.weight));
+        self.weights = match &self.name {
+            Some(name) => {
+                let mut registry = SPLIT_WEIGHTS.write().unwrap();
+                match registry.get(name) {
+                    Some(shared) if shared.len() == weights.len() => shared.clone(),
+                    Some(_) => bail!("split {} is declared with different targets", name),
+                    None => {
+                        let weights = Arc::new(weights);
+                        registry.insert(name.to_owned(), weights.clone());
+                        weights
+                    }
+                }
+            }
+            None => Arc::new(weights),
+        };
+        Ok(())
+    }
+
+    pub fn weights(&self) -> &Arc<SplitWeights> {
+        &self.weights
+    }
+
+    /// Pick a target by weight, `None` if all weights are zero.
+    pub fn select<B>(&self, req: &Request<B>, client_ip: IpAddr) -> Option<&A> {
+        let sticky = self
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky_hash(sticky, req, client_ip));
+        let index = match sticky {
+            Some(hash) => self.weights.pick(|total| hash % total),
+            None => self.weights.pick(|total| random() % total),
+        }?;
+        self.targets.get(index).map(|target| &target.proxy_pass)
+    }
+}
+
+/// stable hash of the client, `None` if the request has no such key
+pub(crate) fn sticky_hash<B>(
+    sticky: &StickyKey,
+    req: &Request<B>,
+    client_ip: IpAddr,
+) -> Option<u64> {
+    // `DefaultHasher::new` uses fixed keys, the hash is the same across workers
+    let mut hasher = DefaultHasher::new();
+    match sticky {
+        StickyKey::Cookie(name) => cookies(req)
+            .find(|(key, _)| key == name)?
+            .1
+            .hash(&mut hasher),
+        StickyKey::Header(name) => req.headers().get(name)?.as_bytes().hash(&mut hasher),
+        StickyKey::ClientIp => client_ip.hash(&mut hasher),
+    }
+    Some(hasher.finish())
+}
+
+thread_local! {
+    static RANDOM: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// xorshift64, good enough to spread requests
+pub(crate) fn random() -> u64 {
+    RANDOM.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+/// Change the weights of the split registered as `name`, in target order.
+pub fn set_split_weights(name: &str, weights: &[u32]) -> Result<(), GError> {
+    match SPLIT_WEIGHTS.read().unwrap().get(name) {
+        Some(split) => split.set(weights),
+        None => bail!("no split named {}", name),
+    }
+}
+
+/// Current weights of the split registered as `name`, in target order.
+pub fn split_weights(name: &str) -> Option<Vec<u32>> {
+    SPLIT_WEIGHTS
+        .read()
+        .unwrap()
+        .get(name)
+        .map(|split| split.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::COOKIE;
+
+    use super::*;
+    use crate::dns::http::Domain;
+
+    fn split(weights: &[u32]) -> TrafficSplit<usize> {
+        let targets = weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| WeightedTarget::new(index, *weight))
+            .collect();
+        let mut split = TrafficSplit::new(targets);
+        split.compile().unwrap();
+        split
+    }
+
+    fn request(cookie: Option<&str>) -> Request<()> {
+        let mut req = Request::builder();
+        if let Some(cookie) = cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn client(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_doc_example() {
+        let split: TrafficSplit<Domain> = serde_json::from_str(
+            r#"{
+                "name": "web",
+                "targets": [
+                    {"proxy_pass": {"uri": "http://blue:8000"}, "weight": 95},
+                    {"proxy_pass": {"uri": "http://green:8000"}, "weight": 5}
+                ],
+                "sticky": {"cookie": "session"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(split.targets[0].proxy_pass.host(), "blue");
+        assert_eq!(split.targets[1].weight, 5);
+        assert!(matches!(&split.sticky, Some(StickyKey::Cookie(name)) if name == "session"));
+    }
+
+    #[test]
+    fn splits_by_weight() {
+        let split = split(&[90, 0, 10]);
+        let (req, ip) = (request(None), client("10.0.0.1"));
+        let mut picked = [0; 3];
+        for _ in 0..10000 {
+            picked[*split.select(&req, ip).unwrap()] += 1;
+        }
+        assert_eq!(picked[1], 0);
+        assert!((8500..9500).contains(&picked[0]), "{:?}", picked);
+
+        // runtime weights apply to the next requests
+        split.weights().set(&[0, 1, 0]).unwrap();
+        assert!((0..100).all(|_| split.select(&req, ip) == Some(&1)));
+        assert!(split.weights().set(&[1, 1]).is_err());
+        split.weights().set(&[0, 0, 0]).unwrap();
+        assert_eq!(split.select(&req, ip), None);
+    }
+
+    #[test]
+    fn sticky_by_cookie() {
+        let mut split = split(&[1, 1]);
+        split.sticky = Some(StickyKey::Cookie("session".to_string()));
+        let ip = client("10.0.0.1");
+        let req = request(Some("theme=dark; session=abc"));
+        let first = split.select(&req, ip).unwrap();
+        assert!((0..100).all(|_| split.select(&req, ip) == Some(first)));
+
+        // other sessions spread over the targets
+        let mut picked = [0; 2];
+        for session in 0..100 {
+            let req = request(Some(&format!("session={}", session)));
+            picked[*split.select(&req, ip).unwrap()] += 1;
+        }
+        assert!(picked.iter().all(|count| *count > 20), "{:?}", picked);
+        // a request without the cookie is not sticky
+        assert!(sticky_hash(split.sticky.as_ref().unwrap(), &request(None), ip).is_none());
+        assert!(split.select(&request(None), ip).is_some());
+    }
+
+    #[test]
+    fn sticky_by_client_ip() {
+        let mut split = split(&[1, 1]);
+        split.sticky = Some(StickyKey::ClientIp);
+        let req = request(None);
+        let mut picked = [0; 2];
+        for host in 0..100 {
+            let ip = client(&format!("10.0.0.{}", host));
+            let first = split.select(&req, ip).unwrap();
+            assert!((0..10).all(|_| split.select(&req, ip) == Some(first)));
+            picked[*first] += 1;
+        }
+        assert!(picked.iter().all(|count| *count > 20), "{:?}", picked);
+    }
+}
@@ -1,17 +1,68 @@
 use std::{
     fmt::Display,
     future::Future,
+    path::{Path, PathBuf},
 };
 
-use http::{uri::Authority, Uri};
+use anyhow::bail;
+use http::{
+    uri::{Authority, PathAndQuery},
+    Uri,
+};
 use serde::{Deserialize, Serialize};
 
-use super::Resolvable;
+use super::{
+    resolver::{resolver, SocketAddrs},
+    Resolvable,
+};
+use crate::error::GError;
 
+/// An endpoint url, or a unix socket like `unix:/run/app.sock`.
+///
+/// Like nginx, the path of a unix socket endpoint follows the socket after
+/// a colon: `unix:/run/app.sock:/v2/`.
 #[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "RawDomain", into = "RawDomain")]
 pub struct Domain {
-    #[serde(with = "http_serde::uri")]
+    /// `http://localhost` with the path of a unix socket endpoint
     uri: Uri,
+    unix: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RawDomain {
+    uri: String,
+}
+
+impl TryFrom<RawDomain> for Domain {
+    type Error = GError;
+
+    fn try_from(raw: RawDomain) -> Result<Self, Self::Error> {
+        let socket = match raw.uri.strip_prefix("unix:") {
+            Some(socket) => socket,
+            None => return Ok(Self::with_uri(raw.uri.parse()?)),
+        };
+        let (socket, path) = match socket.split_once(':') {
+            Some((socket, path)) => (socket, path),
+            None => (socket, ""),
+        };
+        if socket.is_empty() {
+            bail!("{} has no socket path", raw.uri);
+        }
+        if !path.is_empty() && !path.starts_with('/') {
+            bail!("path of {} must start with '/'", raw.uri);
+        }
+        PathAndQuery::try_from(path)?;
+        Ok(Self::unix(socket, path))
+    }
+}
+
+impl From<Domain> for RawDomain {
+    fn from(domain: Domain) -> Self {
+        Self {
+            uri: domain.to_string(),
+        }
+    }
 }
 
 impl Domain {
@@ -23,11 +74,33 @@ impl Domain {
                 .path_and_query(path)
                 .build()
                 .unwrap(),
+            unix: None,
         }
     }
 
     pub fn with_uri(uri: Uri) -> Self {
-        Self { uri }
+        Self { uri, unix: None }
+    }
+
+    /// Endpoint at the unix socket `socket`, spoken to in plain http.
+    ///
+    /// `path` is a path and query, like in [`Domain::new`], or empty for none.
+    pub fn unix(socket: impl Into<PathBuf>, path: &str) -> Self {
+        Self {
+            uri: Uri::builder()
+                .scheme("http")
+                .authority("localhost")
+                .path_and_query(path)
+                .build()
+                .unwrap(),
+            unix: Some(socket.into()),
+        }
+    }
+
+    /// path of the socket of a unix socket endpoint
+    #[inline]
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.unix.as_deref()
     }
 
     pub fn version(&self) -> crate::http::version::Type {
@@ -50,6 +123,21 @@ impl Domain {
         self.uri.authority()
     }
 
+    /// path of the uri, `/` if it has none
+    #[inline]
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    /// The path of the url if it has one, `http://backend/` has `/` while
+    /// `http://backend` has none.
+    pub fn base_path(&self) -> Option<&str> {
+        let path_and_query = self.uri.path_and_query()?;
+        // `Uri` reports `/` as the path of a url without one, its
+        // path and query is empty though
+        (*path_and_query != PathAndQuery::from_static("")).then(|| self.uri.path())
+    }
+
     #[inline]
     pub fn host(&self) -> &str {
         self.uri.authority().unwrap().host()
@@ -67,19 +155,67 @@ impl Domain {
 impl Resolvable for Domain {
     type Error = anyhow::Error;
 
-    type Address = String;
+    type Address = SocketAddrs;
 
     type ResolveFuture<'a> = impl Future<Output = Result<Option<Self::Address>, Self::Error>> + 'a
     where
         Self: 'a;
 
     fn resolve(&self) -> Self::ResolveFuture<'_> {
-        async { Ok(Some(format!("{}:{}", self.host(), self.port()))) }
+        async move {
+            if self.unix.is_some() {
+                bail!("{} is a unix socket", self);
+            }
+            Ok(Some(resolver().resolve(self.host(), self.port()).await?))
+        }
     }
 }
 
 impl Display for Domain {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.uri)
+        let path = self
+            .base_path()
+            .and_then(|_| self.uri.path_and_query())
+            .map(|path| path.as_str());
+        match (
+            &self.unix,
+            path,
+            self.uri.scheme_str(),
+            self.uri.authority(),
+        ) {
+            (Some(socket), None, ..) => write!(f, "unix:{}", socket.display()),
+            (Some(socket), Some(path), ..) => write!(f, "unix:{}:{}", socket.display(), path),
+            // `Uri` would add a `/`
+            (None, None, Some(scheme), Some(authority)) => write!(f, "{}://{}", scheme, authority),
+            (None, ..) => write!(f, "{}", self.uri),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(uri: &str) -> Domain {
+        Domain::try_from(RawDomain {
+            uri: uri.to_owned(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn base_path_of_urls() {
+        assert_eq!(domain("http://backend").base_path(), None);
+        assert_eq!(domain("http://backend/").base_path(), Some("/"));
+        assert_eq!(domain("http://backend:8000/v2/").base_path(), Some("/v2/"));
+        assert_eq!(domain("unix:/run/app.sock").base_path(), None);
+        assert_eq!(domain("unix:/run/app.sock:/v2").base_path(), Some("/v2"));
+
+        assert_eq!(domain("http://backend").to_string(), "http://backend");
+        assert_eq!(domain("http://backend/").to_string(), "http://backend/");
+        assert_eq!(
+            domain("unix:/run/app.sock").to_string(),
+            "unix:/run/app.sock"
+        );
     }
 }
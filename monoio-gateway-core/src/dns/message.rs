@@ -0,0 +1,324 @@
+//! Just enough of the DNS wire format (RFC 1035) to ask for A and AAAA
+//! records of a name.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, bail};
+
+use crate::error::GError;
+
+const HEADER_LEN: usize = 12;
+const CLASS_IN: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+/// recursion desired
+const FLAG_RD: u16 = 0x0100;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const RCODE_NXDOMAIN: u16 = 3;
+/// compression pointers followed in a name at most
+const MAX_POINTERS: usize = 16;
+
+/// Record types asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+/// Addresses of a response with their ttl in seconds.
+#[derive(Debug, Default)]
+pub struct Answer {
+    pub records: Vec<(IpAddr, u32)>,
+    /// the response did not fit a datagram, ask again over tcp
+    pub truncated: bool,
+    /// the name does not exist
+    pub nx_domain: bool,
+}
+
+/// Encode a recursive query for `qtype` records of `name`.
+pub fn encode_query(id: u16, name: &str, qtype: RecordType) -> Result<Vec<u8>, GError> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        bail!("invalid dns name {:?}", name);
+    }
+    let mut buf = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // one question, no records
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid dns name {:?}", name);
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.code().to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+/// Decode the response to query `id` for `qtype` records of `name`.
+///
+/// Only records of `name` and of the names it is a CNAME of are taken.
+pub fn decode_answer(buf: &[u8], id: u16, name: &str, qtype: RecordType) -> Result<Answer, GError> {
+    let mut reader = Reader { buf, pos: 0 };
+    if reader.u16()? != id {
+        bail!("dns response id mismatch");
+    }
+    let flags = reader.u16()?;
+    if flags & FLAG_QR == 0 {
+        bail!("dns message is not a response");
+    }
+    let mut answer = Answer {
+        truncated: flags & FLAG_TC != 0,
+        ..Default::default()
+    };
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => {
+            answer.nx_domain = true;
+            return Ok(answer);
+        }
+        rcode => bail!("dns server replied rcode {}", rcode),
+    }
+    if answer.truncated {
+        return Ok(answer);
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    // authority and additional records are not needed
+    reader.skip(4)?;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.skip(4)?;
+    }
+    let mut names = vec![name.trim_end_matches('.').to_ascii_lowercase()];
+    for _ in 0..answers {
+        let owner = reader.name()?;
+        let rtype = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        if end > buf.len() {
+            bail!("dns record out of bounds");
+        }
+        if class == CLASS_IN && names.contains(&owner) {
+            match rtype {
+                TYPE_CNAME => names.push(reader.name()?),
+                rtype if rtype == qtype.code() => {
+                    let data = &buf[reader.pos..end];
+                    let addr = match (qtype, data.len()) {
+                        (RecordType::A, 4) => {
+                            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap()))
+                        }
+                        (RecordType::Aaaa, 16) => {
+                            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap()))
+                        }
+                        _ => bail!("invalid address record of {}", owner),
+                    };
+                    answer.records.push((addr, ttl));
+                }
+                _ => {}
+            }
+        }
+        reader.pos = end;
+    }
+    Ok(answer)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], GError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("truncated dns message"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), GError> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Result<u16, GError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, GError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a possibly compressed name, lowercased and without the root dot.
+    fn name(&mut self) -> Result<String, GError> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut pointers = 0;
+        // where reading goes on once the name is done
+        let mut resume = None;
+        loop {
+            let len = *self
+                .buf
+                .get(pos)
+                .ok_or_else(|| anyhow!("truncated dns name"))? as usize;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self
+                        .buf
+                        .get(pos + 1)
+                        .ok_or_else(|| anyhow!("truncated dns name"))?
+                        as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        bail!("dns name pointer loop");
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = (len & 0x3f) << 8 | low;
+                }
+                len if len <= 63 => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or_else(|| anyhow!("truncated dns name"))?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+                    pos += 1 + len;
+                }
+                _ => bail!("invalid dns label"),
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// query of `www.example.com` with id 7
+    fn query() -> Vec<u8> {
+        encode_query(7, "www.example.com", RecordType::A).unwrap()
+    }
+
+    /// response to `query` with `flags` and `answers`
+    fn response(query: &[u8], flags: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = query[..2].to_vec();
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&[0, 1]);
+        buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(&query[HEADER_LEN..]);
+        answers
+            .iter()
+            .for_each(|answer| buf.extend_from_slice(answer));
+        buf
+    }
+
+    /// answer to `query`
+    fn decode(buf: &[u8]) -> Result<Answer, GError> {
+        decode_answer(buf, 7, "www.example.com", RecordType::A)
+    }
+
+    fn record(owner: &[u8], rtype: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = owner.to_vec();
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn encodes_query() {
+        let query = encode_query(0x1234, "www.Example.com.", RecordType::Aaaa).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x03www\x07Example\x03com\x00");
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        assert_eq!(query, expected);
+
+        assert!(encode_query(1, "", RecordType::A).is_err());
+        assert!(encode_query(1, "a..b", RecordType::A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), RecordType::A).is_err());
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert!(encode_query(1, &long, RecordType::A).is_err());
+    }
+
+    #[test]
+    fn follows_cnames() {
+        let query = query();
+        // web.example.com, its domain pointing into the question
+        let cname = record(&[0xc0, 12], TYPE_CNAME, 300, b"\x03web\xc0\x10");
+        // owned by the target of the cname, pointing at its data
+        let owner = (query.len() + 12) as u16 | 0xc000;
+        let answers = [
+            cname,
+            record(&owner.to_be_bytes(), 1, 60, &[10, 0, 0, 1]),
+            record(b"\x05other\x00", 1, 60, &[10, 6, 6, 6]),
+        ];
+        let buf = response(&query, 0x8180, &answers);
+        let answer = decode_answer(&buf, 7, "WWW.example.com.", RecordType::A).unwrap();
+        assert_eq!(answer.records, vec![(IpAddr::from([10, 0, 0, 1]), 60)]);
+        assert!(!answer.truncated && !answer.nx_domain);
+    }
+
+    #[test]
+    fn nx_domain_and_truncated() {
+        let query = query();
+        assert!(decode(&response(&query, 0x8183, &[])).unwrap().nx_domain);
+        assert!(decode(&response(&query, 0x8380, &[])).unwrap().truncated);
+        // server failure
+        assert!(decode(&response(&query, 0x8182, &[])).is_err());
+        // not a response
+        assert!(decode(&query).is_err());
+
+        let mut other = response(&query, 0x8180, &[]);
+        other[1] = 8;
+        assert!(decode(&other).is_err());
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let query = query();
+        // an owner name pointing at itself
+        let at = query.len() as u16 | 0xc000;
+        let buf = response(
+            &query,
+            0x8180,
+            &[record(&at.to_be_bytes(), 1, 60, &[10, 0, 0, 1])],
+        );
+        let err = decode(&buf).unwrap_err();
+        assert!(err.to_string().contains("loop"), "{}", err);
+
+        // and a label pointing back at its start
+        let mut owner = b"\x01a".to_vec();
+        owner.extend_from_slice(&at.to_be_bytes());
+        let buf = response(&query, 0x8180, &[record(&owner, 1, 60, &[10, 0, 0, 1])]);
+        assert!(decode(&buf).is_err());
+    }
+}
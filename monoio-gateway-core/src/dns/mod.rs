@@ -6,6 +6,8 @@ use std::{
 };
 
 pub mod http;
+pub mod message;
+pub mod resolver;
 pub mod tcp;
 
 pub trait Resolvable: Clone + Display + PartialEq + Eq + Hash {
@@ -0,0 +1,557 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    rc::Rc,
+    time::{Duration, Instant},
+    vec,
+};
+
+use anyhow::{anyhow, bail};
+use log::{debug, warn};
+use monoio::{
+    io::{AsyncReadRentExt, AsyncWriteRentExt},
+    net::{udp::UdpSocket, TcpStream},
+};
+
+use super::message::{decode_answer, encode_query, Answer, RecordType};
+use crate::{balance::weighted::random, error::GError};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const HOSTS: &str = "/etc/hosts";
+const DNS_PORT: u16 = 53;
+/// responses over this are truncated, no EDNS is sent
+const MAX_UDP_LEN: usize = 512;
+/// cached names, expired ones are dropped when it is full
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+thread_local! {
+    static RESOLVER: RefCell<Rc<Resolver>> = RefCell::new(Rc::new(Resolver::new(ResolverConfig::default())));
+}
+
+/// Resolver of the current worker, one of the default config until
+/// [`set_resolver`] is called when the worker starts.
+pub fn resolver() -> Rc<Resolver> {
+    RESOLVER.with(|resolver| resolver.borrow().clone())
+}
+
+/// Replace the resolver of the current worker, its cache is dropped.
+pub fn set_resolver(resolver: Resolver) {
+    RESOLVER.with(|current| *current.borrow_mut() = Rc::new(resolver));
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolverConfig {
+    /// asked in order until one answers
+    pub nameservers: Vec<SocketAddr>,
+    /// of a single query to a nameserver
+    pub timeout: Duration,
+    /// rounds over all nameservers
+    pub attempts: u32,
+    /// addresses of the hosts file, looked up before the nameservers
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    /// records are cached for their ttl, but this long at most
+    pub max_ttl: Duration,
+    /// names without addresses are cached this long
+    pub negative_ttl: Duration,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DNS_PORT)],
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            hosts: HashMap::new(),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ResolverConfig {
+    /// Config of `/etc/resolv.conf` with the hosts of `/etc/hosts`, the
+    /// defaults for what can not be read.
+    ///
+    /// The files are read with blocking calls, load it once before starting
+    /// the workers and hand each a clone.
+    pub fn system() -> Self {
+        let mut config = match std::fs::read_to_string(RESOLV_CONF) {
+            Ok(conf) => Self::from_resolv_conf(&conf),
+            Err(err) => {
+                warn!("failed to read {}: {}, using defaults", RESOLV_CONF, err);
+                Self::default()
+            }
+        };
+        match std::fs::read_to_string(HOSTS) {
+            Ok(hosts) => config.hosts = parse_hosts(&hosts),
+            Err(err) => warn!("failed to read {}: {}", HOSTS, err),
+        }
+        config
+    }
+
+    /// Parse `nameserver` lines and the `timeout` and `attempts` options of a
+    /// resolv.conf, other settings are ignored.
+    pub fn from_resolv_conf(conf: &str) -> Self {
+        let mut config = Self::default();
+        let mut nameservers = vec![];
+        for line in conf.lines() {
+            let mut words = line.split(['#', ';']).next().unwrap().split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    // drop the zone of a link local address
+                    let addr = words.next().and_then(|addr| addr.split('%').next());
+                    match addr.and_then(|addr| addr.parse::<IpAddr>().ok()) {
+                        Some(ip) => nameservers.push(SocketAddr::new(ip, DNS_PORT)),
+                        None => warn!("invalid nameserver in {}: {}", RESOLV_CONF, line),
+                    }
+                }
+                Some("options") => {
+                    for option in words {
+                        match option.split_once(':') {
+                            Some(("timeout", secs)) => {
+                                if let Ok(secs) = secs.parse::<u64>() {
+                                    config.timeout = Duration::from_secs(secs.max(1));
+                                }
+                            }
+                            Some(("attempts", attempts)) => {
+                                if let Ok(attempts) = attempts.parse::<u32>() {
+                                    config.attempts = attempts.max(1);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !nameservers.is_empty() {
+            config.nameservers = nameservers;
+        }
+        config
+    }
+
+    pub fn with_nameservers(mut self, nameservers: Vec<SocketAddr>) -> Self {
+        self.nameservers = nameservers;
+        self
+    }
+
+    pub fn with_hosts(mut self, hosts: HashMap<String, Vec<IpAddr>>) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Addresses of a name, tried in order by [`SocketAddrs::connect`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocketAddrs(Vec<SocketAddr>);
+
+impl SocketAddrs {
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        Self(addrs)
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[SocketAddr] {
+        &self.0
+    }
+
+    /// Connect the first address accepting a connection.
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in self.0.iter() {
+            match TcpStream::connect(*addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!("failed to connect {}: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect")
+        }))
+    }
+}
+
+impl ToSocketAddrs for SocketAddrs {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        Ok(self.0.clone().into_iter())
+    }
+}
+
+impl Display for SocketAddrs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, addr) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Non blocking stub resolver with a cache of A and AAAA records.
+///
+/// Names are looked up in the hosts file first, then both record types are
+/// asked of the nameservers over udp, or tcp for truncated answers. Search
+/// domains are not applied, upstream names should be fully qualified.
+pub struct Resolver {
+    config: ResolverConfig,
+    cache: RefCell<HashMap<String, CacheEntry>>,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        Self {
+            config,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Addresses of `host` with `port`.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<SocketAddrs, GError> {
+        let addrs = self.lookup(host).await?;
+        Ok(SocketAddrs(
+            addrs
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect(),
+        ))
+    }
+
+    /// Addresses of `host`, IPv4 ones first.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, GError> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.config.hosts.get(&name) {
+            return Ok(addrs.clone());
+        }
+        if let Some(entry) = self.cache.borrow().get(&name) {
+            if entry.expires > Instant::now() {
+                if entry.addrs.is_empty() {
+                    bail!("{} has no address", name);
+                }
+                return Ok(entry.addrs.clone());
+            }
+        }
+        let (v4, v6) = monoio::join!(
+            self.query(&name, RecordType::A),
+            self.query(&name, RecordType::Aaaa)
+        );
+        let answered = v4.is_ok() && v6.is_ok();
+        let records = match (v4, v6) {
+            (Err(err), Err(_)) => return Err(err),
+            (v4, v6) => {
+                let mut records = v4.unwrap_or_default();
+                records.extend(v6.unwrap_or_default());
+                records
+            }
+        };
+        let addrs: Vec<IpAddr> = records.iter().map(|(ip, _)| *ip).collect();
+        let ttl = match records.iter().map(|(_, ttl)| *ttl).min() {
+            Some(ttl) => Duration::from_secs(ttl as u64).min(self.config.max_ttl),
+            None if answered => self.config.negative_ttl,
+            // a failed query is asked again by the next lookup
+            None => bail!("failed to resolve {}", name),
+        };
+        debug!("resolved {} to {:?} for {:?}", name, addrs, ttl);
+        self.insert(name.clone(), addrs.clone(), ttl);
+        if addrs.is_empty() {
+            bail!("{} has no address", name);
+        }
+        Ok(addrs)
+    }
+
+    fn insert(&self, name: String, addrs: Vec<IpAddr>, ttl: Duration) {
+        let now = Instant::now();
+        let mut cache = self.cache.borrow_mut();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        cache.insert(
+            name,
+            CacheEntry {
+                addrs,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    /// Records of `name`, empty if it does not exist.
+    async fn query(&self, name: &str, qtype: RecordType) -> Result<Vec<(IpAddr, u32)>, GError> {
+        let mut last_err = anyhow!("no nameserver configured");
+        for _ in 0..self.config.attempts {
+            for nameserver in self.config.nameservers.iter() {
+                let exchange = exchange(*nameserver, name, qtype);
+                match monoio::time::timeout(self.config.timeout, exchange).await {
+                    Ok(Ok(answer)) => return Ok(answer.records),
+                    Ok(Err(err)) => {
+                        debug!(
+                            "{:?} query of {} to {} failed: {}",
+                            qtype, name, nameserver, err
+                        );
+                        last_err = err;
+                    }
+                    Err(_) => {
+                        last_err =
+                            anyhow!("{:?} query of {} to {} timed out", qtype, name, nameserver)
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// Ask `nameserver` over udp, and again over tcp if the answer is truncated.
+async fn exchange(nameserver: SocketAddr, name: &str, qtype: RecordType) -> Result<Answer, GError> {
+    let id = random() as u16;
+    let query = encode_query(id, name, qtype)?;
+    let response = exchange_udp(nameserver, query.clone(), id).await?;
+    let answer = decode_answer(&response, id, name, qtype)?;
+    if !answer.truncated {
+        return Ok(answer);
+    }
+    let response = exchange_tcp(nameserver, query).await?;
+    decode_answer(&response, id, name, qtype)
+}
+
+async fn exchange_udp(nameserver: SocketAddr, query: Vec<u8>, id: u16) -> Result<Vec<u8>, GError> {
+    let local = match nameserver {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(nameserver).await?;
+    let (res, _) = socket.send(query).await;
+    res?;
+    loop {
+        let (res, response) = socket.recv(Vec::with_capacity(MAX_UDP_LEN)).await;
+        res?;
+        // a late answer to an earlier query
+        if response.len() >= 2 && response[..2] != id.to_be_bytes() {
+            continue;
+        }
+        return Ok(response);
+    }
+}
+
+async fn exchange_tcp(nameserver: SocketAddr, query: Vec<u8>) -> Result<Vec<u8>, GError> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    // messages over tcp are prefixed by their length
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&query);
+    let (res, _) = stream.write_all(message).await;
+    res?;
+    let (res, len) = stream.read_exact(vec![0; 2]).await;
+    res?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let (res, response) = stream.read_exact(vec![0; len]).await;
+    res?;
+    Ok(response)
+}
+
+/// Addresses of each name of a hosts file, in file order.
+pub fn parse_hosts(hosts: &str) -> HashMap<String, Vec<IpAddr>> {
+    let mut map: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in hosts.lines() {
+        let mut words = line.split('#').next().unwrap().split_whitespace();
+        let ip = match words
+            .next()
+            .map(|ip| ip.split('%').next().unwrap().parse::<IpAddr>())
+        {
+            Some(Ok(ip)) => ip,
+            _ => continue,
+        };
+        for name in words {
+            let addrs = map.entry(name.to_ascii_lowercase()).or_default();
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    // IPv4 first like answers of the nameservers
+    for addrs in map.values_mut() {
+        addrs.sort_by_key(|ip| ip.is_ipv6());
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, UdpSocket as StdUdpSocket},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+
+    /// Reply of the stub nameserver to `query`:
+    /// - `a.test` has 10.0.0.1 and no IPv6 address
+    /// - `big.test` is truncated over udp, 10.0.0.2 over tcp
+    /// - `short.test` is 10.0.0.3 for no time
+    /// - other names do not exist
+    fn reply(query: &[u8], tcp: bool) -> Vec<u8> {
+        let mut end = 12;
+        let mut labels = vec![];
+        while query[end] != 0 {
+            let len = query[end] as usize;
+            labels.push(String::from_utf8_lossy(&query[end + 1..end + 1 + len]).into_owned());
+            end += 1 + len;
+        }
+        let qtype = u16::from_be_bytes([query[end + 1], query[end + 2]]);
+        let mut flags: u16 = 0x8180;
+        let mut records = vec![];
+        match (labels.join(".").as_str(), qtype) {
+            ("a.test", 1) => records.push(([10, 0, 0, 1], 300)),
+            // truncated
+            ("big.test", 1) if !tcp => flags |= 0x0200,
+            ("big.test", 1) => records.push(([10, 0, 0, 2], 300)),
+            ("short.test", 1) => records.push(([10, 0, 0, 3], 0)),
+            ("a.test" | "big.test" | "short.test", _) => {}
+            _ => flags |= 3,
+        }
+        let mut buf = query[..2].to_vec();
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&[0, 1, 0, records.len() as u8, 0, 0, 0, 0]);
+        buf.extend_from_slice(&query[12..end + 5]);
+        for (ip, ttl) in records {
+            // owned by the name of the question
+            buf.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+            buf.extend_from_slice(&(ttl as u32).to_be_bytes());
+            buf.extend_from_slice(&[0, 4]);
+            buf.extend_from_slice(&ip);
+        }
+        buf
+    }
+
+    /// Serve `reply` over udp and tcp of the same local port, counting the
+    /// udp queries.
+    fn stub_nameserver() -> (SocketAddr, Arc<AtomicUsize>) {
+        let udp = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; MAX_UDP_LEN];
+            while let Ok((len, from)) = udp.recv_from(&mut buf) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = udp.send_to(&reply(&buf[..len], false), from);
+            }
+        });
+        std::thread::spawn(move || {
+            for mut stream in tcp.incoming().flatten() {
+                let mut len = [0; 2];
+                if stream.read_exact(&mut len).is_err() {
+                    continue;
+                }
+                let mut query = vec![0; u16::from_be_bytes(len) as usize];
+                if stream.read_exact(&mut query).is_err() {
+                    continue;
+                }
+                let response = reply(&query, true);
+                let _ = stream.write_all(&(response.len() as u16).to_be_bytes());
+                let _ = stream.write_all(&response);
+            }
+        });
+        (addr, queries)
+    }
+
+    #[test]
+    fn parses_system_files() {
+        let conf = ResolverConfig::from_resolv_conf(
+            "# comment\nnameserver 10.1.1.1\nnameserver fe80::1%eth0\nnameserver bad\noptions timeout:3 attempts:4 ndots:2\n",
+        );
+        let nameservers: Vec<SocketAddr> = vec![
+            "10.1.1.1:53".parse().unwrap(),
+            "[fe80::1]:53".parse().unwrap(),
+        ];
+        assert_eq!(conf.nameservers, nameservers);
+        assert_eq!((conf.timeout, conf.attempts), (Duration::from_secs(3), 4));
+
+        let hosts = parse_hosts("::1 localhost\n127.0.0.1 localhost Loop # comment\n#10.0.0.1 x\n");
+        let localhost: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(hosts["localhost"], localhost);
+        assert_eq!(hosts["loop"].len(), 1);
+        assert!(!hosts.contains_key("x"));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn resolves_with_stub_nameserver() {
+        let (addr, queries) = stub_nameserver();
+        let hosts = parse_hosts("10.9.9.9 pinned.test\n");
+        let config = ResolverConfig::default()
+            .with_nameservers(vec![addr])
+            .with_hosts(hosts)
+            .with_timeout(Duration::from_secs(2));
+        let resolver = Resolver::new(config);
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // an A and an AAAA query, then cached
+        assert_eq!(
+            resolver.lookup("A.test.").await.unwrap(),
+            vec![ip("10.0.0.1")]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            resolver.lookup("a.test").await.unwrap(),
+            vec![ip("10.0.0.1")]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // truncated over udp, asked again over tcp
+        let addrs = resolver.resolve("big.test", 8080).await.unwrap();
+        assert_eq!(addrs.as_slice(), &["10.0.0.2:8080".parse().unwrap()]);
+
+        // expired records are asked again
+        let before = queries.load(Ordering::SeqCst);
+        assert_eq!(
+            resolver.lookup("short.test").await.unwrap(),
+            vec![ip("10.0.0.3")]
+        );
+        assert_eq!(
+            resolver.lookup("short.test").await.unwrap(),
+            vec![ip("10.0.0.3")]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), before + 4);
+
+        // names that do not exist are cached for the negative ttl
+        assert!(resolver.lookup("none.test").await.is_err());
+        let before = queries.load(Ordering::SeqCst);
+        assert!(resolver.lookup("none.test").await.is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), before);
+
+        // hosts and addresses are never asked
+        assert_eq!(
+            resolver.lookup("pinned.test").await.unwrap(),
+            vec![ip("10.9.9.9")]
+        );
+        assert_eq!(resolver.lookup("[::1]").await.unwrap(), vec![ip("::1")]);
+        assert_eq!(queries.load(Ordering::SeqCst), before);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::bail;
+use bytes::Bytes;
+use http::{
+    header::{HeaderName, CONTENT_TYPE},
+    HeaderValue, Method, Request, StatusCode,
+};
+use monoio_http::{common::response::Response, h1::payload::Payload};
+use serde_derive::{Deserialize, Serialize};
+
+use super::{router::PathCaptures, static_files::StaticFiles, template::render};
+use crate::{error::GError, transfer::generate_body_response};
+
+/// What a rule does instead of proxying to `proxy_pass`.
+///
+/// `{"redirect": {"to": "https://example.com$request_uri", "status": 301}}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Redirect(Redirect),
+    /// `{"static": {"root": "/var/www/html"}}`
+    Static(StaticFiles),
+    /// `{"return": {"status": 503, "headers": {"Retry-After": "120"}, "body": "maintenance"}}`
+    Return(DirectResponse),
+}
+
+impl RuleAction {
+    pub fn validate(&self) -> Result<(), GError> {
+        match self {
+            RuleAction::Redirect(redirect) => redirect.validate(),
+            RuleAction::Static(files) => files.validate(),
+            RuleAction::Return(response) => response.validate(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Redirect {
+    /// `Location` template, see [`RequestVars`] for variables
+    pub to: String,
+    /// one of 301, 302, 303, 307 and 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+impl Redirect {
+    pub fn new(to: impl Into<String>, status: StatusCode) -> Self {
+        Self {
+            to: to.into(),
+            status: status.as_u16(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), GError> {
+        if !matches!(self.status, 301 | 302 | 303 | 307 | 308) {
+            bail!("{} is not a redirect status", self.status);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::FOUND)
+    }
+
+    /// `Location` of `vars`
+    #[inline]
+    pub fn location(&self, vars: &RequestVars) -> String {
+        vars.render(&self.to)
+    }
+}
+
+/// Fixed response from config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirectResponse {
+    #[serde(default = "default_return_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// `text/plain` unless `Content-Type` is in `headers`
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_return_status() -> u16 {
+    200
+}
+
+impl DirectResponse {
+    pub fn new(status: StatusCode, body: impl Into<String>) -> Self {
+        Self {
+            status: status.as_u16(),
+            headers: HashMap::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), GError> {
+        StatusCode::from_u16(self.status)?;
+        for (name, value) in self.headers.iter() {
+            HeaderName::from_bytes(name.as_bytes())?;
+            HeaderValue::from_str(value)?;
+        }
+        Ok(())
+    }
+
+    /// the configured response, without body for `HEAD`
+    pub fn response<B>(&self, req: &Request<B>) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut resp = generate_body_response(status, Bytes::from(self.body.clone()));
+        if req.method() == Method::HEAD {
+            *resp.body_mut() = Payload::None;
+        }
+        let headers = resp.headers_mut();
+        if !self.body.is_empty() {
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+        }
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes());
+            let value = HeaderValue::from_str(value);
+            if let (Ok(name), Ok(value)) = (name, value) {
+                headers.insert(name, value);
+            }
+        }
+        resp
+    }
+}
+
+/// Variables of a matched request for action templates:
+///
+/// - `$scheme`: `http` or `https`
+/// - `$host`: host lowercased, with its port unless it is the default one of
+///   `$scheme`
+/// - `$path`: request path
+/// - `$query`: query string without `?`, empty if there is none
+/// - `$request_uri`: path and query
+/// - `$1`, `${name}`: captures of a regex rule
+pub struct RequestVars<'a> {
+    pub scheme: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub captures: &'a PathCaptures,
+}
+
+impl<'a> RequestVars<'a> {
+    pub fn render(&self, template: &str) -> String {
+        render(template, |var| match var {
+            "scheme" => Some(Cow::Borrowed(self.scheme)),
+            "host" => Some(Cow::Borrowed(self.host)),
+            "path" => Some(Cow::Borrowed(self.path)),
+            "query" => Some(Cow::Borrowed(self.query.unwrap_or(""))),
+            "request_uri" => Some(match self.query {
+                Some(query) => Cow::Owned(format!("{}?{}", self.path, query)),
+                None => Cow::Borrowed(self.path),
+            }),
+            _ => self.captures.lookup(var).map(Cow::Borrowed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::host::normalize_host;
+
+    #[test]
+    fn redirect_keeps_port() {
+        let captures = PathCaptures::default();
+        let redirect = Redirect::new("$scheme://$host$request_uri", StatusCode::FOUND);
+        // the `Host` of a request normalized like the router does
+        let location = |scheme, default_port, raw_host| {
+            let host = normalize_host(raw_host, default_port).unwrap();
+            redirect.location(&RequestVars {
+                scheme,
+                host: &host,
+                path: "/a",
+                query: Some("b=1"),
+                captures: &captures,
+            })
+        };
+        assert_eq!(
+            location("http", 80, "example.com:80"),
+            "http://example.com/a?b=1"
+        );
+        assert_eq!(
+            location("http", 80, "example.com:443"),
+            "http://example.com:443/a?b=1"
+        );
+        assert_eq!(
+            location("https", 443, "Example.com:443"),
+            "https://example.com/a?b=1"
+        );
+        assert_eq!(
+            location("https", 443, "example.com:80"),
+            "https://example.com:80/a?b=1"
+        );
+        assert_eq!(
+            location("https", 443, "example.com:8443"),
+            "https://example.com:8443/a?b=1"
+        );
+    }
+}
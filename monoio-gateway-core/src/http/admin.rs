@@ -0,0 +1,194 @@
+use std::net::SocketAddr;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use http::{
+    header::{ALLOW, CONTENT_TYPE},
+    HeaderValue, Method, StatusCode,
+};
+use log::{debug, info};
+use monoio::{
+    io::{sink::SinkExt, stream::Stream, Splitable},
+    net::TcpListener,
+};
+use monoio_http::{
+    common::{request::Request, response::Response},
+    h1::{
+        codec::{
+            decoder::{FillPayload, RequestDecoder},
+            encoder::GenericEncoder,
+        },
+        payload::Payload,
+    },
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    balance::weighted::{set_split_weights, split_weights},
+    error::GError,
+    transfer::{generate_body_response, generate_response},
+};
+
+/// Endpoint for operators to change the gateway at runtime.
+///
+/// `GET /splits/<name>` replies the weights of a named split as a json
+/// array in target order, `PUT /splits/<name>` with a body like `[90, 10]`
+/// sets them for every worker. It has no authentication, listen on a
+/// loopback or private address only.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// address to listen on, like `127.0.0.1:9901`
+    pub listen: String,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> Result<(), GError> {
+        self.listen
+            .parse::<SocketAddr>()
+            .map_err(|err| anyhow!("admin listen {}: {}", self.listen, err))?;
+        Ok(())
+    }
+}
+
+/// Serve the admin endpoint, returns if it can not listen or accept.
+pub async fn serve_admin(config: AdminConfig) -> Result<(), GError> {
+    let listener = TcpListener::bind(&config.listen)?;
+    info!("admin endpoint listening on {}", config.listen);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        monoio::spawn(async move {
+            let (read, write) = stream.into_split();
+            let mut decoder = RequestDecoder::new(read);
+            let mut encoder = GenericEncoder::new(write);
+            while let Some(Ok(req)) = decoder.next().await {
+                let req: Request<Payload> = req;
+                let (parts, payload) = req.into_parts();
+                let (_, body) = monoio::join!(decoder.fill_payload(), read_body(payload));
+                let resp = match body {
+                    Some(body) => admin_response(&parts.method, parts.uri.path(), &body),
+                    None => text_response(StatusCode::LENGTH_REQUIRED, "body needs a length"),
+                };
+                debug!(
+                    "admin {} {} from {}: {}",
+                    parts.method,
+                    parts.uri,
+                    peer,
+                    resp.status()
+                );
+                let close = resp.status() == StatusCode::LENGTH_REQUIRED;
+                if encoder.send_and_flush(resp).await.is_err() || close {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// body of an admin request, `None` for a chunked one
+async fn read_body(payload: Payload) -> Option<Bytes> {
+    match payload {
+        Payload::None => Some(Bytes::new()),
+        Payload::Fixed(payload) => payload.get().await.ok(),
+        Payload::Stream(_) => None,
+    }
+}
+
+/// reply of an admin request
+fn admin_response(method: &Method, path: &str, body: &[u8]) -> Response {
+    let name = match path.strip_prefix("/splits/") {
+        Some(name) if !name.is_empty() => name,
+        _ => return generate_response(StatusCode::NOT_FOUND),
+    };
+    match *method {
+        Method::GET => match split_weights(name) {
+            Some(weights) => json_response(&weights),
+            None => generate_response(StatusCode::NOT_FOUND),
+        },
+        Method::PUT => {
+            if split_weights(name).is_none() {
+                return generate_response(StatusCode::NOT_FOUND);
+            }
+            let weights: Vec<u32> = match serde_json::from_slice(body) {
+                Ok(weights) => weights,
+                Err(err) => return text_response(StatusCode::BAD_REQUEST, &err.to_string()),
+            };
+            match set_split_weights(name, &weights) {
+                Ok(()) => {
+                    info!("split {} weights set to {:?}", name, weights);
+                    json_response(&weights)
+                }
+                Err(err) => text_response(StatusCode::BAD_REQUEST, &err.to_string()),
+            }
+        }
+        _ => {
+            let mut resp = generate_response(StatusCode::METHOD_NOT_ALLOWED);
+            resp.headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("GET, PUT"));
+            resp
+        }
+    }
+}
+
+fn json_response(weights: &[u32]) -> Response {
+    let body = serde_json::to_vec(weights).unwrap_or_default();
+    let mut resp = generate_body_response(StatusCode::OK, Bytes::from(body));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+fn text_response(status: StatusCode, message: &str) -> Response {
+    let mut resp = generate_body_response(status, Bytes::from(format!("{}\n", message)));
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::weighted::{TrafficSplit, WeightedTarget};
+
+    #[test]
+    fn sets_split_weights() {
+        let mut split = TrafficSplit::new(vec![
+            WeightedTarget::new("blue", 95),
+            WeightedTarget::new("green", 5),
+        ]);
+        split.name = Some("admin-test".to_owned());
+        split.compile().unwrap();
+
+        let resp = admin_response(&Method::GET, "/splits/admin-test", b"");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()[http::header::CONTENT_LENGTH], "6");
+
+        let resp = admin_response(&Method::PUT, "/splits/admin-test", b"[50, 50]");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(split.weights().get(), vec![50, 50]);
+
+        let resp = admin_response(&Method::PUT, "/splits/admin-test", b"[1, 2, 3]");
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = admin_response(&Method::PUT, "/splits/admin-test", b"fifty");
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(split.weights().get(), vec![50, 50]);
+
+        let resp = admin_response(&Method::PUT, "/splits/missing", b"[1]");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = admin_response(&Method::GET, "/", b"");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = admin_response(&Method::DELETE, "/splits/admin-test", b"");
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn validates_listen() {
+        let config = |listen: &str| AdminConfig {
+            listen: listen.to_owned(),
+        };
+        assert!(config("127.0.0.1:9901").validate().is_ok());
+        assert!(config("localhost").validate().is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::bail;
+use bytes::Bytes;
+use http::{header::CONTENT_TYPE, HeaderValue, Request, StatusCode};
+use monoio_http::{common::response::Response, h1::payload::Payload};
+use serde_derive::{Deserialize, Serialize};
+
+use super::template::render;
+use crate::{balance::weighted::random, error::GError, transfer::generate_body_response};
+
+/// header of the id of a request, sent to upstreams and with error pages
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// ids of requests longer than this are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Pages of the replies of the gateway itself by status code.
+pub type ErrorPages = HashMap<u16, ErrorPage>;
+
+/// Body of a reply of the gateway itself, like the `502` of an upstream
+/// that can not be connected.
+///
+/// `{"body": "<h1>$status $reason</h1><p>request $request_id</p>"}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorPage {
+    /// body template, see [`ErrorVars`] for variables
+    pub body: String,
+    /// variables are html escaped if it is html
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+impl ErrorPage {
+    pub fn new(body: impl Into<String>) -> Self {
+        Self {
+            body: body.into(),
+            content_type: default_content_type(),
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    /// the body of `vars`
+    pub fn render(&self, vars: &ErrorVars) -> String {
+        let html = self.content_type.contains("html");
+        render(&self.body, |var| {
+            let value = vars.get(var)?;
+            if html {
+                Some(escape_html(value))
+            } else {
+                Some(value)
+            }
+        })
+    }
+}
+
+/// Check that `pages` are of error statuses and their content types are valid.
+pub fn validate_error_pages(pages: &ErrorPages) -> Result<(), GError> {
+    for (status, page) in pages.iter() {
+        match StatusCode::from_u16(*status) {
+            Ok(code) if code.is_client_error() || code.is_server_error() => {}
+            _ => bail!("{} is not an error status", status),
+        }
+        HeaderValue::from_str(&page.content_type)?;
+    }
+    Ok(())
+}
+
+/// Variables of error page templates:
+///
+/// - `$status`: status code
+/// - `$reason`: reason phrase of the status
+/// - `$request_id`: id of the request, also sent as `X-Request-Id`
+/// - `$host`: host of the request without port, empty if there is none
+/// - `$path`: request path
+pub struct ErrorVars<'a> {
+    pub status: StatusCode,
+    pub request_id: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> ErrorVars<'a> {
+    fn get(&self, var: &str) -> Option<Cow<'a, str>> {
+        match var {
+            "status" => Some(Cow::Owned(self.status.as_str().to_string())),
+            "reason" => Some(Cow::Borrowed(
+                self.status.canonical_reason().unwrap_or_default(),
+            )),
+            "request_id" => Some(Cow::Borrowed(self.request_id)),
+            "host" => Some(Cow::Borrowed(self.host)),
+            "path" => Some(Cow::Borrowed(self.path)),
+            _ => None,
+        }
+    }
+}
+
+/// Reply of the gateway with the status of `vars`, rendered from `page` or
+/// empty, without body if `head`.
+pub fn error_response(page: Option<&ErrorPage>, vars: &ErrorVars, head: bool) -> Response {
+    let body = page.map(|page| page.render(vars)).unwrap_or_default();
+    let mut resp = generate_body_response(vars.status, Bytes::from(body));
+    let headers = resp.headers_mut();
+    if let Some(page) = page {
+        if let Ok(content_type) = HeaderValue::from_str(&page.content_type) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+    }
+    if let Ok(request_id) = HeaderValue::from_str(vars.request_id) {
+        headers.insert(REQUEST_ID, request_id);
+    }
+    if head {
+        *resp.body_mut() = Payload::None;
+    }
+    resp
+}
+
+/// The id of `req`, its `X-Request-Id` if it has a sane one. A new id
+/// replaces it otherwise, so that upstreams see the same id.
+pub fn request_id<B>(req: &mut Request<B>) -> String {
+    let sent = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'))
+        });
+    if let Some(id) = sent {
+        return id.to_string();
+    }
+    let id = format!("{:016x}{:016x}", random(), random());
+    req.headers_mut()
+        .insert(REQUEST_ID, HeaderValue::from_str(&id).unwrap());
+    id
+}
+
+fn escape_html(value: Cow<str>) -> Cow<str> {
+    if !value.contains(['&', '<', '>', '"', '\'']) {
+        return value;
+    }
+    let mut escaped = String::with_capacity(value.len() + 16);
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars<'a>(host: &'a str, path: &'a str) -> ErrorVars<'a> {
+        ErrorVars {
+            status: StatusCode::BAD_GATEWAY,
+            request_id: "abc",
+            host,
+            path,
+        }
+    }
+
+    fn with_id(id: &str) -> Request<()> {
+        Request::builder().header(REQUEST_ID, id).body(()).unwrap()
+    }
+
+    #[test]
+    fn renders_escaped_html() {
+        let page = ErrorPage::new("$status $reason $request_id $host$path ${nope}!");
+        let vars = vars("<x>", "/a&b\"'");
+        assert_eq!(
+            page.render(&vars),
+            "502 Bad Gateway abc &lt;x&gt;/a&amp;b&quot;&#39; !"
+        );
+        // only variables are escaped, the template is trusted
+        let page = ErrorPage::new("<h1>$path</h1>");
+        assert_eq!(page.render(&vars), "<h1>/a&amp;b&quot;&#39;</h1>");
+        let text = ErrorPage::new("$host$path").with_content_type("text/plain");
+        assert_eq!(text.render(&vars), "<x>/a&b\"'");
+    }
+
+    #[test]
+    fn responds_with_page() {
+        let vars = vars("example.com", "/");
+        let page = ErrorPage::new("$status").with_content_type("text/plain");
+        let resp = error_response(Some(&page), &vars, false);
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(resp.headers()[REQUEST_ID], "abc");
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/plain");
+        assert!(matches!(resp.body(), Payload::Fixed(_)));
+        let resp = error_response(None, &vars, true);
+        assert_eq!(resp.headers()["content-length"], "0");
+        assert!(matches!(resp.body(), Payload::None));
+    }
+
+    #[test]
+    fn keeps_valid_request_ids() {
+        for id in ["a-1.b_2", &"x".repeat(MAX_REQUEST_ID_LEN)] {
+            let mut req = with_id(id);
+            assert_eq!(request_id(&mut req), id);
+            assert_eq!(req.headers()[REQUEST_ID], id);
+        }
+
+        let oversize = "x".repeat(MAX_REQUEST_ID_LEN + 1);
+        for id in ["<script>", "a b", "", &oversize] {
+            let mut req = with_id(id);
+            let new = request_id(&mut req);
+            assert_eq!(new.len(), 32);
+            assert!(new.bytes().all(|c| c.is_ascii_hexdigit()));
+            // upstreams see the new id
+            assert_eq!(req.headers()[REQUEST_ID], new.as_str());
+        }
+        let mut req = Request::new(());
+        let id = request_id(&mut req);
+        assert_eq!(req.headers()[REQUEST_ID], id.as_str());
+        assert_ne!(request_id(&mut Request::new(())), id);
+    }
+
+    #[test]
+    fn pages_of_error_statuses_only() {
+        let pages: ErrorPages =
+            serde_json::from_str(r#"{"404": {"body": "gone"}, "503": {"body": "down"}}"#).unwrap();
+        validate_error_pages(&pages).unwrap();
+        assert_eq!(pages[&503].content_type, "text/html; charset=utf-8");
+        for status in [200, 302, 999] {
+            let mut pages = ErrorPages::new();
+            pages.insert(status, ErrorPage::new("page"));
+            let err = validate_error_pages(&pages).unwrap_err();
+            assert!(err.to_string().contains("not an error status"), "{}", err);
+        }
+        let mut pages = ErrorPages::new();
+        pages.insert(500, ErrorPage::new("page").with_content_type("text/\nhtml"));
+        assert!(validate_error_pages(&pages).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use http::uri::Authority;
+use log::warn;
+use regex::Regex;
+
+use super::{http2::Http2Settings, router::RouterConfig, timeout::Timeouts};
+use crate::error::GError;
+
+/// Server lookup of one listen port, similar to nginx `server_name`.
+///
+/// A name is one of:
+/// - exact: `example.com`
+/// - leading wildcard: `*.example.com`, matches any subdomain of `example.com`
+/// - regex: `~^api\d+\.example\.com$`
+///
+/// Lookup order is exact, longest wildcard, regex in config order and
+/// finally the `default_server` of the port.
+pub struct VirtualHosts<A> {
+    servers: Vec<RouterConfig<A>>,
+    exact: HashMap<String, usize>,
+    /// (`.example.com`, server index), longest suffix first
+    wildcard: Vec<(String, usize)>,
+    regex: Vec<(Regex, usize)>,
+    default: Option<usize>,
+    /// whether connections of the port start with a PROXY protocol header
+    proxy_protocol: bool,
+}
+
+impl<A> VirtualHosts<A> {
+    pub fn new(mut servers: Vec<RouterConfig<A>>, listen_port: u16) -> Result<Self, GError> {
+        let mut exact = HashMap::new();
+        let mut wildcard = vec![];
+        let mut regex = vec![];
+        let mut default = None;
+        for server in servers.iter_mut().filter(|server| !server.is_compiled()) {
+            server.compile()?;
+        }
+        for (index, server) in servers.iter().enumerate() {
+            for name in server.names() {
+                if let Some(re) = name.strip_prefix('~') {
+                    regex.push((Regex::new(re)?, index));
+                } else if let Some(suffix) = name.strip_prefix('*') {
+                    wildcard.push((suffix.to_ascii_lowercase(), index));
+                } else if exact.insert(name.to_ascii_lowercase(), index).is_some() {
+                    warn!("duplicated server name {} on port {}", name, listen_port);
+                }
+            }
+            if server.default_server.contains(&listen_port) {
+                match default {
+                    Some(_) => warn!(
+                        "{} is ignored, port {} already has a default server",
+                        server.server_name, listen_port
+                    ),
+                    None => default = Some(index),
+                }
+            }
+        }
+        wildcard.sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+        let proxy_protocol = servers
+            .iter()
+            .any(|server| server.proxy_protocol.contains(&listen_port));
+        Ok(Self {
+            servers,
+            exact,
+            wildcard,
+            regex,
+            default,
+            proxy_protocol,
+        })
+    }
+
+    /// Find the server of a host normalized by [`normalize_host`].
+    ///
+    /// A host with a port is also served by names without port.
+    pub fn get(&self, host: &str) -> Option<&RouterConfig<A>> {
+        let index = self
+            .find(host)
+            .or_else(|| {
+                let hostname = strip_port(host);
+                (hostname.len() < host.len())
+                    .then(|| self.find(hostname))
+                    .flatten()
+            })
+            .or(self.default)?;
+        self.servers.get(index)
+    }
+
+    fn find(&self, host: &str) -> Option<usize> {
+        if let Some(index) = self.exact.get(host) {
+            return Some(*index);
+        }
+        let wildcard = self
+            .wildcard
+            .iter()
+            .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()));
+        if let Some((_, index)) = wildcard {
+            return Some(*index);
+        }
+        self.regex
+            .iter()
+            .find(|(re, _)| re.is_match(host))
+            .map(|(_, index)| *index)
+    }
+
+    pub fn servers(&self) -> &Vec<RouterConfig<A>> {
+        &self.servers
+    }
+
+    /// The server of the port before one is selected by host, the default
+    /// server or the first one.
+    pub fn fallback(&self) -> Option<&RouterConfig<A>> {
+        self.default
+            .and_then(|index| self.servers.get(index))
+            .or_else(|| self.servers.first())
+    }
+
+    /// Timeouts of the port before a server is selected, those of
+    /// [`VirtualHosts::fallback`].
+    pub fn timeouts(&self) -> Timeouts {
+        self.fallback()
+            .map(|server| server.timeouts)
+            .unwrap_or_default()
+    }
+
+    /// HTTP/2 settings of the port, taken like [`VirtualHosts::timeouts`].
+    pub fn http2(&self) -> Http2Settings {
+        self.fallback()
+            .map(|server| server.http2)
+            .unwrap_or_default()
+    }
+
+    /// Whether a PROXY protocol header is read before anything else, any
+    /// server of the port may enable it for the whole port.
+    #[inline]
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+}
+
+/// `host` without a trailing `:port`
+pub fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((hostname, port)) if port.bytes().all(|b| b.is_ascii_digit()) => hostname,
+        _ => host,
+    }
+}
+
+/// Normalize a `Host` header or request uri authority for server lookup.
+///
+/// The host is lowercased, a trailing dot and `default_port` are removed.
+/// `None` is returned for a malformed host.
+pub fn normalize_host(raw: &str, default_port: u16) -> Option<String> {
+    let authority: Authority = raw.parse().ok()?;
+    if authority.as_str().contains('@') {
+        // userinfo is not allowed in Host
+        return None;
+    }
+    let host = authority.host();
+    let port = match authority.as_str()[host.len()..].strip_prefix(':') {
+        None | Some("") => None,
+        Some(port) => Some(port.parse::<u16>().ok()?),
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.is_empty() {
+        return None;
+    }
+    match port {
+        Some(port) if port != default_port => Some(format!("{}:{}", host, port)),
+        _ => Some(host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::http::Domain;
+
+    /// a server without rules, `default_server` on `defaults`
+    fn server(name: &str, aliases: &[&str], defaults: &[u16]) -> RouterConfig<Domain> {
+        serde_json::from_value(serde_json::json!({
+            "server_name": name,
+            "server_names": aliases,
+            "listen_port": [80, 443],
+            "default_server": defaults,
+            "rules": [],
+        }))
+        .unwrap()
+    }
+
+    /// `server_name` of the server of `host`
+    fn lookup<'a>(hosts: &'a VirtualHosts<Domain>, host: &str) -> Option<&'a str> {
+        hosts.get(host).map(|server| server.server_name.as_str())
+    }
+
+    #[test]
+    fn name_precedence() {
+        let servers = vec![
+            server(r"~^api\d+\.example\.(com|net)$", &[], &[]),
+            server("*.example.com", &[], &[]),
+            server("*.api.example.com", &[], &[]),
+            server("api.example.com", &[], &[]),
+            server(r"~^v\d+\.api\.example\.com$", &[], &[]),
+        ];
+        let hosts = VirtualHosts::new(servers, 80).unwrap();
+        // exact first
+        assert_eq!(lookup(&hosts, "api.example.com"), Some("api.example.com"));
+        // then the longest wildcard, before any regex
+        assert_eq!(
+            lookup(&hosts, "v1.api.example.com"),
+            Some("*.api.example.com")
+        );
+        assert_eq!(lookup(&hosts, "api1.example.com"), Some("*.example.com"));
+        // then regexes in config order
+        assert_eq!(
+            lookup(&hosts, "api1.example.net"),
+            Some(r"~^api\d+\.example\.(com|net)$")
+        );
+        // a wildcard does not match its bare domain
+        assert_eq!(lookup(&hosts, "example.com"), None);
+        // names without port serve hosts with one
+        assert_eq!(
+            lookup(&hosts, "api.example.com:8080"),
+            Some("api.example.com")
+        );
+    }
+
+    #[test]
+    fn server_names_are_aliases() {
+        let servers = vec![
+            server("example.com", &["www.example.com", "*.example.org"], &[]),
+            server("other.com", &[], &[]),
+        ];
+        let hosts = VirtualHosts::new(servers, 80).unwrap();
+        for host in ["example.com", "www.example.com", "shop.example.org"] {
+            assert_eq!(lookup(&hosts, host), Some("example.com"), "{}", host);
+        }
+        assert_eq!(lookup(&hosts, "other.com"), Some("other.com"));
+        assert_eq!(lookup(&hosts, "example.org"), None);
+    }
+
+    #[test]
+    fn default_server_of_port() {
+        let servers = || {
+            vec![
+                server("a.com", &[], &[]),
+                server("b.com", &[], &[443]),
+                server("c.com", &[], &[80]),
+                // a second default of port 80 is ignored
+                server("d.com", &[], &[80]),
+            ]
+        };
+        let http = VirtualHosts::new(servers(), 80).unwrap();
+        let https = VirtualHosts::new(servers(), 443).unwrap();
+        assert_eq!(lookup(&http, "unknown.com"), Some("c.com"));
+        assert_eq!(lookup(&https, "unknown.com"), Some("b.com"));
+        assert_eq!(lookup(&https, "a.com"), Some("a.com"));
+        assert_eq!(http.fallback().unwrap().server_name, "c.com");
+
+        // without a default unknown hosts are not served
+        let hosts = VirtualHosts::new(servers(), 8080).unwrap();
+        assert_eq!(lookup(&hosts, "unknown.com"), None);
+        assert_eq!(lookup(&hosts, "d.com"), Some("d.com"));
+        // settings of the port are those of its first server
+        assert_eq!(hosts.fallback().unwrap().server_name, "a.com");
+    }
+
+    #[test]
+    fn normalize_drops_default_port() {
+        assert_eq!(
+            normalize_host("Example.COM.:443", 443).unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            normalize_host("example.com:8443", 443).unwrap(),
+            "example.com:8443"
+        );
+        assert_eq!(
+            normalize_host("example.com:80", 443).unwrap(),
+            "example.com:80"
+        );
+        assert_eq!(normalize_host("[::1]:8080", 80).unwrap(), "[::1]:8080");
+        assert_eq!(normalize_host("user@example.com", 80), None);
+        assert_eq!(strip_port("example.com:8443"), "example.com");
+    }
+}
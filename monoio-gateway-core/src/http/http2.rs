@@ -0,0 +1,102 @@
+use anyhow::bail;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::GError;
+
+/// largest flow control window allowed by RFC 9113
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+const MIN_FRAME_SIZE: u32 = 1 << 14;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// HTTP/2 of the clients of a server, offered by ALPN on tls ports and
+/// detected by the connection preface on plain ones.
+///
+/// Like `client_header`, a listener takes these from its default server, or
+/// its first server without one.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Http2Settings {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// streams a client may have open at once
+    #[serde(default = "default_max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+    /// flow control window of each stream in bytes
+    #[serde(default = "default_stream_window")]
+    pub initial_stream_window: u32,
+    /// flow control window of a connection in bytes, shared by its streams
+    #[serde(default = "default_connection_window")]
+    pub initial_connection_window: u32,
+    /// largest frame payload accepted in bytes
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: u32,
+    /// largest header block accepted in bytes, uncompressed
+    #[serde(default = "default_max_header_list_size")]
+    pub max_header_list_size: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_concurrent_streams() -> u32 {
+    128
+}
+
+fn default_stream_window() -> u32 {
+    1 << 20
+}
+
+fn default_connection_window() -> u32 {
+    4 << 20
+}
+
+fn default_max_frame_size() -> u32 {
+    MIN_FRAME_SIZE
+}
+
+fn default_max_header_list_size() -> u32 {
+    64 << 10
+}
+
+impl Default for Http2Settings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            max_concurrent_streams: default_max_concurrent_streams(),
+            initial_stream_window: default_stream_window(),
+            initial_connection_window: default_connection_window(),
+            max_frame_size: default_max_frame_size(),
+            max_header_list_size: default_max_header_list_size(),
+        }
+    }
+}
+
+impl Http2Settings {
+    pub fn validate(&self) -> Result<(), GError> {
+        if self.max_concurrent_streams == 0 {
+            bail!("max_concurrent_streams must be positive");
+        }
+        if self.initial_stream_window > MAX_WINDOW_SIZE
+            || self.initial_connection_window > MAX_WINDOW_SIZE
+        {
+            bail!("flow control windows are at most {} bytes", MAX_WINDOW_SIZE);
+        }
+        if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&self.max_frame_size) {
+            bail!(
+                "max_frame_size must be between {} and {}",
+                MIN_FRAME_SIZE,
+                MAX_FRAME_SIZE
+            );
+        }
+        Ok(())
+    }
+
+    /// ALPN protocols offered by tls listeners
+    pub fn alpn(&self) -> Vec<Vec<u8>> {
+        if self.enabled {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Reuse of the connections of a worker to the endpoints of an upstream.
+///
+/// How long an idle connection is kept is the `upstream_idle` timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamKeepalive {
+    /// idle HTTP/1 connections a worker keeps to each endpoint, `0` closes
+    /// a connection once its response is forwarded
+    #[serde(default = "default_max_idle")]
+    pub max_idle: usize,
+    /// requests sent on a connection before it is closed, `0` for no limit
+    #[serde(default = "default_max_requests")]
+    pub max_requests: u64,
+}
+
+fn default_max_idle() -> usize {
+    32
+}
+
+fn default_max_requests() -> u64 {
+    1000
+}
+
+impl Default for UpstreamKeepalive {
+    fn default() -> Self {
+        Self {
+            max_idle: default_max_idle(),
+            max_requests: default_max_requests(),
+        }
+    }
+}
+
+impl UpstreamKeepalive {
+    /// whether a connection that sent `requests` may send another one
+    #[inline]
+    pub fn reusable(&self, requests: u64) -> bool {
+        self.max_requests == 0 || requests < self.max_requests
+    }
+}
@@ -1,12 +1,25 @@
 use std::future::Future;
 
+pub mod action;
+pub mod admin;
 pub mod detect;
+pub mod error_page;
+pub mod host;
+pub mod http2;
+pub mod keepalive;
+pub mod predicate;
+pub mod proxy_protocol;
+pub mod retry;
+pub mod route_table;
 pub mod router;
 pub mod ssl;
+pub mod static_files;
+pub mod template;
+pub mod timeout;
 pub mod version;
 
 mod rewrite;
-pub use rewrite::Rewrite;
+pub use rewrite::{Rewrite, RewriteRule};
 
 pub trait Detect<I> {
     type Protocol;
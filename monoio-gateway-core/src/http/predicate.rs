@@ -0,0 +1,210 @@
+use std::borrow::Cow;
+
+use http::{header::COOKIE, Request};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::util::{pattern::Pattern, percent::percent_decode};
+
+/// Optional `match` block of a [`super::router::RouterRule`].
+///
+/// Every listed condition must hold for the rule to be selected, an empty
+/// predicate matches any request.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RoutePredicate {
+    /// allowed methods, e.g. `["GET", "HEAD"]`
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<KeyMatch>,
+    #[serde(default)]
+    pub query: Vec<KeyMatch>,
+    #[serde(default)]
+    pub cookies: Vec<KeyMatch>,
+}
+
+/// `{"name": "X-Canary", "equals": "1"}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyMatch {
+    pub name: String,
+    #[serde(flatten)]
+    pub value: ValueMatch,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueMatch {
+    Equals(String),
+    Regex(Pattern),
+    /// `true` requires the key to exist, `false` requires it to be absent
+    Present(bool),
+}
+
+impl ValueMatch {
+    fn matches<'a>(&self, mut values: impl Iterator<Item = Cow<'a, str>>) -> bool {
+        match self {
+            ValueMatch::Equals(expected) => values.any(|v| v == expected.as_str()),
+            ValueMatch::Regex(re) => values.any(|v| re.is_match(&v)),
+            ValueMatch::Present(present) => values.next().is_some() == *present,
+        }
+    }
+}
+
+impl RoutePredicate {
+    pub fn matches<B>(&self, req: &Request<B>) -> bool {
+        if !self.methods.is_empty()
+            && !self
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(req.method().as_str()))
+        {
+            return false;
+        }
+        let headers = self.headers.iter().all(|h| {
+            h.value.matches(
+                req.headers()
+                    .get_all(h.name.as_str())
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .map(Cow::Borrowed),
+            )
+        });
+        if !headers {
+            return false;
+        }
+        let query = req.uri().query().unwrap_or("");
+        let query = self.query.iter().all(|q| {
+            q.value.matches(
+                query_pairs(query)
+                    .filter(|(k, _)| *k == q.name)
+                    .map(|(_, v)| v),
+            )
+        });
+        if !query {
+            return false;
+        }
+        self.cookies.iter().all(|c| {
+            c.value.matches(
+                cookies(req)
+                    .filter(|(k, _)| *k == c.name)
+                    .map(|(_, v)| Cow::Borrowed(v)),
+            )
+        })
+    }
+}
+
+/// decoded `key=value` pairs of a query string
+pub fn query_pairs(query: &str) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+    query.split('&').filter(|p| !p.is_empty()).map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (decode_query(k), decode_query(v))
+    })
+}
+
+/// `name=value` pairs of all `Cookie` headers
+pub fn cookies<B>(req: &Request<B>) -> impl Iterator<Item = (&str, &str)> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| {
+            let (k, v) = pair.trim().split_once('=')?;
+            Some((k, v.trim_matches('"')))
+        })
+}
+
+/// `s` of a query string decoded, invalid utf-8 replaced
+fn decode_query(s: &str) -> Cow<'_, str> {
+    match percent_decode(s, true) {
+        Cow::Borrowed(_) => Cow::Borrowed(s),
+        Cow::Owned(bytes) => Cow::Owned(String::from_utf8_lossy(&bytes).into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use super::*;
+
+    fn predicate(json: &str) -> RoutePredicate {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn request(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn get(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        request(Method::GET, uri, headers)
+    }
+
+    #[test]
+    fn empty_matches_any() {
+        let any = RoutePredicate::default();
+        assert!(any.matches(&request(Method::DELETE, "/x?a=1", &[("cookie", "a=1")])));
+    }
+
+    #[test]
+    fn matches_methods() {
+        let p = predicate(r#"{"methods": ["GET", "head"]}"#);
+        assert!(p.matches(&get("/", &[])));
+        assert!(p.matches(&request(Method::HEAD, "/", &[])));
+        assert!(!p.matches(&request(Method::POST, "/", &[])));
+    }
+
+    #[test]
+    fn matches_headers() {
+        let p = predicate(r#"{"headers": [{"name": "X-Canary", "equals": "1"}]}"#);
+        assert!(p.matches(&get("/", &[("x-canary", "1")])));
+        assert!(!p.matches(&get("/", &[("x-canary", "0")])));
+        assert!(!p.matches(&get("/", &[])));
+        // any of the values of a repeated header
+        assert!(p.matches(&get("/", &[("x-canary", "0"), ("x-canary", "1")])));
+
+        let p = predicate(r#"{"headers": [{"name": "User-Agent", "regex": "(?i)mobile"}]}"#);
+        assert!(p.matches(&get("/", &[("user-agent", "Foo Mobile/1.0")])));
+        assert!(!p.matches(&get("/", &[("user-agent", "Foo/1.0")])));
+
+        let p = predicate(
+            r#"{"headers": [
+                {"name": "Authorization", "present": true},
+                {"name": "X-Debug", "present": false}
+            ]}"#,
+        );
+        assert!(p.matches(&get("/", &[("authorization", "Bearer x")])));
+        assert!(!p.matches(&get("/", &[])));
+        assert!(!p.matches(&get("/", &[("authorization", "Bearer x"), ("x-debug", "")])));
+    }
+
+    #[test]
+    fn matches_query() {
+        let p = predicate(
+            r#"{"query": [{"name": "tag", "equals": "a b"}, {"name": "v", "regex": "^[0-9]+$"}]}"#,
+        );
+        assert!(p.matches(&get("/?tag=a%20b&v=2", &[])));
+        assert!(p.matches(&get("/?v=10&tag=a+b", &[])));
+        assert!(!p.matches(&get("/?tag=a%20b&v=x", &[])));
+        assert!(!p.matches(&get("/?tag=a%20b", &[])));
+        assert!(!p.matches(&get("/", &[])));
+
+        let p = predicate(r#"{"query": [{"name": "debug", "present": false}]}"#);
+        assert!(p.matches(&get("/?x=1", &[])));
+        assert!(!p.matches(&get("/?debug", &[])));
+    }
+
+    #[test]
+    fn matches_cookies() {
+        let p = predicate(r#"{"cookies": [{"name": "beta", "equals": "on"}]}"#);
+        assert!(p.matches(&get("/", &[("cookie", "session=1; beta=\"on\"")])));
+        // cookies of several headers
+        assert!(p.matches(&get("/", &[("cookie", "session=1"), ("cookie", "beta=on")])));
+        assert!(!p.matches(&get("/", &[("cookie", "beta=off")])));
+        assert!(!p.matches(&get("/", &[("cookie", "nobeta=on")])));
+        assert!(!p.matches(&get("/", &[])));
+    }
+}
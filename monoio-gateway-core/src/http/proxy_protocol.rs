@@ -0,0 +1,337 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::bail;
+
+use crate::error::GError;
+
+/// signature of a v1 header
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// longest v1 header, `\r\n` included
+const V1_MAX_LEN: usize = 107;
+/// signature of a v2 header
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// signature, version and command, family and length
+const V2_HEADER_LEN: usize = 16;
+
+const V2_VERSION: u8 = 0x20;
+const V2_LOCAL: u8 = 0x00;
+const V2_PROXY: u8 = 0x01;
+const V2_INET: u8 = 0x10;
+const V2_INET6: u8 = 0x20;
+
+/// `PP2_TYPE_ALPN`, protocol negotiated with the balancer
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+/// `PP2_TYPE_AUTHORITY`, host name sent by the client, usually its SNI
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// `PP2_TYPE_UNIQUE_ID`, id of the connection given by the balancer
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+/// `PP2_TYPE_SSL`, tls facts of the client, with sub-TLVs
+pub const PP2_TYPE_SSL: u8 = 0x20;
+
+/// What a load balancer in front sent in the PROXY protocol header of a
+/// connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// address of the client, `None` for connections of the balancer itself,
+    /// like its health checks, and for unknown families
+    pub source: Option<SocketAddr>,
+    /// address the client connected to
+    pub destination: Option<SocketAddr>,
+    /// type-length-value fields of a v2 header, in the order they were sent
+    pub tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    /// value of the first TLV of `kind`
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|(ty, _)| *ty == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    #[inline]
+    pub fn authority(&self) -> Option<&str> {
+        self.tlv(PP2_TYPE_AUTHORITY)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+}
+
+/// Parse the PROXY protocol v1 or v2 header at the start of `buf`.
+///
+/// Returns the header and its length, or `None` if more bytes are needed. A
+/// connection not starting with a header is an error, its peer can not be
+/// trusted to be the balancer.
+pub fn parse_proxy_header(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, GError> {
+    if starts_like(buf, V2_SIGNATURE) {
+        if buf.len() < V2_HEADER_LEN {
+            return Ok(None);
+        }
+        let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < len {
+            return Ok(None);
+        }
+        return Ok(Some((parse_v2(&buf[..len])?, len)));
+    }
+    if starts_like(buf, V1_PREFIX) {
+        let end = buf.iter().take(V1_MAX_LEN).position(|b| *b == b'\n');
+        return match end {
+            Some(end) => Ok(Some((parse_v1(&buf[..end + 1])?, end + 1))),
+            None if buf.len() < V1_MAX_LEN => Ok(None),
+            None => bail!("PROXY protocol v1 header is too long"),
+        };
+    }
+    bail!("connection does not start with a PROXY protocol header")
+}
+
+/// whether `buf` is `signature` so far
+#[inline]
+fn starts_like(buf: &[u8], signature: &[u8]) -> bool {
+    let len = buf.len().min(signature.len());
+    buf[..len] == signature[..len]
+}
+
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`
+fn parse_v1(line: &[u8]) -> Result<ProxyHeader, GError> {
+    let line = match line.strip_suffix(b"\r\n") {
+        Some(line) => std::str::from_utf8(line)?,
+        None => bail!("PROXY protocol v1 header does not end with CRLF"),
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let (src, dst) = match family {
+                "TCP4" => (
+                    IpAddr::V4(Ipv4Addr::from_str(src)?),
+                    IpAddr::V4(Ipv4Addr::from_str(dst)?),
+                ),
+                _ => (
+                    IpAddr::V6(Ipv6Addr::from_str(src)?),
+                    IpAddr::V6(Ipv6Addr::from_str(dst)?),
+                ),
+            };
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(src, parse_port(src_port)?)),
+                destination: Some(SocketAddr::new(dst, parse_port(dst_port)?)),
+                tlvs: vec![],
+            })
+        }
+        _ => bail!("malformed PROXY protocol v1 header: {}", line),
+    }
+}
+
+/// ports of v1 headers are without leading zeros
+fn parse_port(port: &str) -> Result<u16, GError> {
+    if port.len() > 1 && port.starts_with('0') {
+        bail!("malformed port {} in PROXY protocol v1 header", port);
+    }
+    Ok(port.parse()?)
+}
+
+/// `header` is a whole v2 header, its length already checked
+fn parse_v2(header: &[u8]) -> Result<ProxyHeader, GError> {
+    let version_command = header[12];
+    if version_command & 0xf0 != V2_VERSION {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    let body = &header[V2_HEADER_LEN..];
+    match version_command & 0x0f {
+        // addresses of a local connection are ignored, so are its TLVs
+        V2_LOCAL => return Ok(ProxyHeader::default()),
+        V2_PROXY => {}
+        command => bail!("unsupported PROXY protocol command {}", command),
+    }
+    let (source, destination, tlvs) = match header[13] & 0xf0 {
+        V2_INET if body.len() >= 12 => {
+            let ip = |at: usize| Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]);
+            let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+            let source = SocketAddr::new(ip(0).into(), port(8));
+            let destination = SocketAddr::new(ip(4).into(), port(10));
+            (Some(source), Some(destination), &body[12..])
+        }
+        V2_INET6 if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[at..at + 16]);
+                Ipv6Addr::from(octets)
+            };
+            let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+            let source = SocketAddr::new(ip(0).into(), port(32));
+            let destination = SocketAddr::new(ip(16).into(), port(34));
+            (Some(source), Some(destination), &body[36..])
+        }
+        V2_INET | V2_INET6 => bail!("PROXY protocol v2 addresses are truncated"),
+        // unspecified and unix families keep the peer of the connection
+        _ => return Ok(ProxyHeader::default()),
+    };
+    Ok(ProxyHeader {
+        source,
+        destination,
+        tlvs: parse_tlvs(tlvs)?,
+    })
+}
+
+fn parse_tlvs(mut buf: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, GError> {
+    let mut tlvs = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 3 {
+            bail!("PROXY protocol v2 TLV is truncated");
+        }
+        let len = 3 + u16::from_be_bytes([buf[1], buf[2]]) as usize;
+        if buf.len() < len {
+            bail!("PROXY protocol v2 TLV is truncated");
+        }
+        tlvs.push((buf[0], buf[3..len].to_vec()));
+        buf = &buf[len..];
+    }
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// v2 header of `command` and `family` with `body`
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(V2_VERSION | command);
+        buf.push(family);
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    /// body of a TCP over IPv4 header from 192.0.2.1:56324 to 192.0.2.2:443
+    fn inet_body() -> Vec<u8> {
+        let mut body = vec![192, 0, 2, 1, 192, 0, 2, 2];
+        body.extend_from_slice(&56324_u16.to_be_bytes());
+        body.extend_from_slice(&443_u16.to_be_bytes());
+        body
+    }
+
+    #[test]
+    fn parses_v1() {
+        let buf = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (header, len) = parse_proxy_header(buf).unwrap().unwrap();
+        assert_eq!(len, 42);
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("192.0.2.2:443".parse().unwrap()));
+
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (header, _) = parse_proxy_header(buf).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        for bad in [
+            &b"PROXY TCP4 192.0.2.1 192.0.2.2 056324 443\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(parse_proxy_header(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn waits_for_whole_header() {
+        let v1 = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        let v2 = v2(V2_PROXY, V2_INET | 0x01, &inet_body());
+        for header in [&v1[..], &v2] {
+            for len in 0..header.len() {
+                assert!(parse_proxy_header(&header[..len]).unwrap().is_none());
+            }
+            let (_, len) = parse_proxy_header(header).unwrap().unwrap();
+            assert_eq!(len, header.len());
+        }
+    }
+
+    #[test]
+    fn rejects_oversize_v1() {
+        let mut buf = b"PROXY UNKNOWN ".to_vec();
+        buf.resize(V1_MAX_LEN - 1, b'x');
+        assert!(parse_proxy_header(&buf).unwrap().is_none());
+        buf.push(b'x');
+        assert!(parse_proxy_header(&buf).is_err());
+        // the end of line is too late
+        buf.extend_from_slice(b"\r\n");
+        assert!(parse_proxy_header(&buf).is_err());
+    }
+
+    #[test]
+    fn keeps_peer_of_unknown() {
+        let (header, len) = parse_proxy_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!((header, len), (ProxyHeader::default(), 15));
+        let buf = b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n";
+        assert_eq!(
+            parse_proxy_header(buf).unwrap().unwrap().0,
+            ProxyHeader::default()
+        );
+
+        // AF_UNSPEC and unix families, their addresses and TLVs are skipped
+        let buf = v2(V2_PROXY, 0x00, &[]);
+        assert_eq!(
+            parse_proxy_header(&buf).unwrap().unwrap().0,
+            ProxyHeader::default()
+        );
+        let buf = v2(V2_PROXY, 0x31, &[0; 216]);
+        let (header, len) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!((header, len), (ProxyHeader::default(), 232));
+    }
+
+    #[test]
+    fn ignores_local_addresses() {
+        // a health check of the balancer, with addresses of its own
+        let buf = v2(V2_LOCAL, V2_INET | 0x01, &inet_body());
+        let (header, len) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!((header, len), (ProxyHeader::default(), buf.len()));
+
+        assert!(parse_proxy_header(&v2(0x02, V2_INET | 0x01, &inet_body())).is_err());
+        let mut v3 = v2(V2_PROXY, V2_INET | 0x01, &inet_body());
+        v3[12] = 0x31;
+        assert!(parse_proxy_header(&v3).is_err());
+    }
+
+    #[test]
+    fn parses_v2_with_tlvs() {
+        let mut body = inet_body();
+        body.extend_from_slice(&[PP2_TYPE_ALPN, 0, 2]);
+        body.extend_from_slice(b"h2");
+        body.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0, 11]);
+        body.extend_from_slice(b"example.com");
+        body.extend_from_slice(&[PP2_TYPE_SSL, 0, 0]);
+        let mut buf = v2(V2_PROXY, V2_INET | 0x01, &body);
+        buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+        let (header, len) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!(len, V2_HEADER_LEN + body.len());
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("192.0.2.2:443".parse().unwrap()));
+        assert_eq!(header.tlvs.len(), 3);
+        assert_eq!(header.tlv(PP2_TYPE_ALPN), Some(&b"h2"[..]));
+        assert_eq!(header.authority(), Some("example.com"));
+        assert_eq!(header.tlv(PP2_TYPE_SSL), Some(&[][..]));
+        assert_eq!(header.tlv(PP2_TYPE_UNIQUE_ID), None);
+
+        let mut body = vec![0; 36];
+        body[15] = 1;
+        body[33] = 80;
+        let (header, _) = parse_proxy_header(&v2(V2_PROXY, V2_INET6 | 0x01, &body))
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.source, Some("[::1]:80".parse().unwrap()));
+
+        // a TLV longer than the header, or addresses cut short
+        let mut body = inet_body();
+        body.extend_from_slice(&[PP2_TYPE_ALPN, 0, 9, b'h']);
+        assert!(parse_proxy_header(&v2(V2_PROXY, V2_INET | 0x01, &body)).is_err());
+        assert!(parse_proxy_header(&v2(V2_PROXY, V2_INET | 0x01, &[0; 8])).is_err());
+        assert!(parse_proxy_header(&v2(V2_PROXY, V2_INET6 | 0x01, &[0; 12])).is_err());
+    }
+}
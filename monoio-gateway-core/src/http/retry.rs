@@ -0,0 +1,270 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::bail;
+use http::{Method, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{balance::weighted::random, error::GError};
+
+/// Retries of a rule whose upstream request failed.
+///
+/// ```json
+/// {
+///   "retry_on": ["connect_error", "reset", "503"],
+///   "max_attempts": 3,
+///   "per_try_timeout": 2000,
+///   "budget": {"percent": 20, "min_retries": 3}
+/// }
+/// ```
+///
+/// Only requests without a body are retried, a streamed body can not be sent
+/// twice. Retries of a balanced upstream go to endpoints not tried yet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// attempts of a request, the first one included
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// milliseconds an attempt may wait for the response header
+    #[serde(default)]
+    pub per_try_timeout: Option<u64>,
+    /// milliseconds of the backoff before the first retry, doubled for each
+    /// retry up to `max_backoff` and jittered
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    /// methods retried, the idempotent ones if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub budget: RetryBudget,
+    /// requests and retries in flight, shared by all workers
+    #[serde(skip)]
+    state: Arc<BudgetState>,
+}
+
+/// Why an attempt failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// the endpoint could not be connected
+    ConnectError,
+    /// the connection failed before a response was received
+    Reset,
+    /// no response within `per_try_timeout`
+    Timeout,
+    #[serde(rename = "502")]
+    BadGateway,
+    #[serde(rename = "503")]
+    ServiceUnavailable,
+    #[serde(rename = "504")]
+    GatewayTimeout,
+}
+
+impl RetryOn {
+    /// condition of an upstream response status, `None` if it is not one
+    pub fn of_status(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::BAD_GATEWAY => Some(RetryOn::BadGateway),
+            StatusCode::SERVICE_UNAVAILABLE => Some(RetryOn::ServiceUnavailable),
+            StatusCode::GATEWAY_TIMEOUT => Some(RetryOn::GatewayTimeout),
+            _ => None,
+        }
+    }
+}
+
+/// Retries in flight are limited to `percent` of the requests in flight of
+/// the rule, but `min_retries` are always allowed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetryBudget {
+    #[serde(default = "default_budget_percent")]
+    pub percent: u32,
+    #[serde(default = "default_min_retries")]
+    pub min_retries: usize,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self {
+            percent: default_budget_percent(),
+            min_retries: default_min_retries(),
+        }
+    }
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectError, RetryOn::Reset, RetryOn::Timeout]
+}
+
+fn default_max_attempts() -> u32 {
+    2
+}
+
+fn default_backoff() -> u64 {
+    25
+}
+
+fn default_max_backoff() -> u64 {
+    250
+}
+
+fn default_budget_percent() -> u32 {
+    20
+}
+
+fn default_min_retries() -> usize {
+    3
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    requests: Arc<AtomicUsize>,
+    retries: Arc<AtomicUsize>,
+}
+
+/// A request or retry counted in flight until dropped.
+#[derive(Debug)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), GError> {
+        if self.max_attempts == 0 {
+            bail!("max_attempts must be positive");
+        }
+        if self.per_try_timeout == Some(0) {
+            bail!("per_try_timeout must be positive");
+        }
+        if self.backoff > self.max_backoff {
+            bail!("backoff is over max_backoff");
+        }
+        if self.budget.percent > 100 {
+            bail!("retry budget percent must not be over 100");
+        }
+        for method in self.methods.iter() {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                bail!("invalid retry method {}", method);
+            }
+        }
+        Ok(())
+    }
+
+    /// whether requests of `method` may be retried
+    pub fn retries_method(&self, method: &Method) -> bool {
+        if self.methods.is_empty() {
+            return method.is_idempotent();
+        }
+        self.methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    #[inline]
+    pub fn retries_on(&self, condition: RetryOn) -> bool {
+        self.retry_on.contains(&condition)
+    }
+
+    /// whether an upstream response of `status` is retried instead of forwarded
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        matches!(RetryOn::of_status(status), Some(condition) if self.retries_on(condition))
+    }
+
+    pub fn per_try_timeout(&self) -> Option<Duration> {
+        self.per_try_timeout.map(Duration::from_millis)
+    }
+
+    /// Jittered backoff before retry `retry`, counted from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+        Duration::from_millis(ceiling / 2 + random() % (ceiling / 2 + 1))
+    }
+
+    /// Count a request of the rule in flight until the guard drops.
+    pub fn track(&self) -> InFlight {
+        InFlight::new(&self.state.requests)
+    }
+
+    /// Take a retry out of the budget, `None` if it is used up.
+    pub fn acquire_retry(&self) -> Option<InFlight> {
+        let requests = self.state.requests.load(Ordering::Relaxed);
+        let allowed = (requests * self.budget.percent as usize / 100).max(self.budget.min_retries);
+        let retry = InFlight::new(&self.state.retries);
+        if self.state.retries.load(Ordering::Relaxed) > allowed {
+            return None;
+        }
+        Some(retry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> RetryPolicy {
+        let policy: RetryPolicy = serde_json::from_str(json).unwrap();
+        policy.validate().unwrap();
+        policy
+    }
+
+    #[test]
+    fn budget_caps_retries() {
+        let policy = policy(r#"{"budget": {"percent": 20, "min_retries": 0}}"#);
+        let requests: Vec<InFlight> = (0..100).map(|_| policy.track()).collect();
+        let mut retries: Vec<InFlight> = (0..30).filter_map(|_| policy.acquire_retry()).collect();
+        assert_eq!(retries.len(), 20);
+        assert!(policy.acquire_retry().is_none());
+
+        // a finished retry gives its share back
+        retries.pop();
+        assert!(policy.acquire_retry().is_some());
+        // fewer requests in flight allow fewer retries
+        drop(requests);
+        retries.clear();
+        assert!(policy.acquire_retry().is_none());
+    }
+
+    #[test]
+    fn min_retries_without_requests() {
+        let policy = policy("{}");
+        let retries: Vec<InFlight> = (0..5).filter_map(|_| policy.acquire_retry()).collect();
+        assert_eq!(retries.len(), 3);
+    }
+
+    #[test]
+    fn retries_idempotent_methods() {
+        let policy = policy("{}");
+        for method in [Method::GET, Method::HEAD, Method::PUT, Method::DELETE] {
+            assert!(policy.retries_method(&method), "{}", method);
+        }
+        for method in [Method::POST, Method::PATCH, Method::CONNECT] {
+            assert!(!policy.retries_method(&method), "{}", method);
+        }
+
+        let policy = self::policy(r#"{"methods": ["get", "POST"]}"#);
+        assert!(policy.retries_method(&Method::POST));
+        assert!(!policy.retries_method(&Method::PUT));
+    }
+}
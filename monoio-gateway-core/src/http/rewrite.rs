@@ -1,10 +1,69 @@
-use http::HeaderValue;
+use anyhow::bail;
+use http::{HeaderValue, Uri};
 use monoio_http::{
     common::{request::Request, response::Response},
     h1::payload::Payload,
 };
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::dns::http::Domain;
+use super::router::{prefix_match, PathCaptures};
+use crate::{dns::http::Domain, error::GError};
+
+/// Path rewrite of a router rule.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RewriteRule {
+    /// Replace the whole path, `$1` and `${name}` refer to the captures of
+    /// the matched rule. A `?query` part replaces the query string.
+    pub path: Option<String>,
+    /// Replace the path if it matches, like nginx `rewrite`.
+    #[serde(default)]
+    pub regex: Option<RegexRewrite>,
+    /// removed from the path if it starts with these whole segments
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+}
+
+impl RewriteRule {
+    /// Compile `regex`, must be called before rewriting with it.
+    pub fn compile(&mut self) -> Result<(), GError> {
+        if let Some(regex) = &mut self.regex {
+            if self.path.is_some() {
+                bail!("path and regex of a rewrite are exclusive");
+            }
+            regex.regex = Some(Regex::new(&regex.pattern)?);
+        }
+        Ok(())
+    }
+}
+
+/// `{"pattern": "^/users/(\\d+)$", "replacement": "/u/$1"}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegexRewrite {
+    pub pattern: String,
+    /// `$1` and `${name}` refer to the captures of `pattern`, a `?query`
+    /// part replaces the query string
+    pub replacement: String,
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+impl RegexRewrite {
+    pub fn new(pattern: impl Into<String>, replacement: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+            regex: None,
+        }
+    }
+
+    /// `replacement` expanded with the captures of `path`, if it matches
+    fn replace(&self, path: &str) -> Option<String> {
+        let regex = self.regex.as_ref()?;
+        let captures = regex.captures(path)?;
+        Some(PathCaptures::from_regex(regex, &captures).expand(&self.replacement))
+    }
+}
 
 pub struct Rewrite;
 
@@ -44,4 +103,199 @@ impl Rewrite {
             .headers_mut()
             .insert(http::header::HOST, new_header);
     }
+
+    /// Rewrite the path of a request matched by a router rule.
+    ///
+    /// `rewrite.path` replaces the whole path if set, a matching
+    /// `rewrite.regex` otherwise. Else the `base` path of `proxy_pass`, if
+    /// its url has one, replaces the matched prefix like nginx does:
+    /// `http://backend/` maps `/api/x` of `/api/` to `/x` while
+    /// `http://backend` keeps it. `strip_prefix` and `add_prefix` are
+    /// applied after that. The query string is kept unless a template has a
+    /// `?query` part.
+    pub fn rewrite_path(
+        request: &mut Request<Payload>,
+        base: Option<&str>,
+        rewrite: Option<&RewriteRule>,
+        captures: &PathCaptures,
+    ) {
+        let uri = request.uri();
+        let path = uri.path();
+        let mut query = uri.query().map(ToOwned::to_owned);
+        let replaced = rewrite.and_then(|rewrite| match (&rewrite.path, &rewrite.regex) {
+            (Some(template), _) => Some(captures.expand(template)),
+            (None, Some(regex)) => regex.replace(path),
+            (None, None) => None,
+        });
+        let mut new_path = match (replaced, base) {
+            (Some(expanded), _) => match expanded.split_once('?') {
+                Some((p, q)) => {
+                    query = (!q.is_empty()).then(|| q.to_owned());
+                    p.to_owned()
+                }
+                None => expanded,
+            },
+            (None, Some(base)) => match path.strip_prefix(captures.matched()) {
+                Some(rest) => join_path(base, rest),
+                None => path.to_owned(),
+            },
+            (None, None) => path.to_owned(),
+        };
+        if let Some(rewrite) = rewrite {
+            if let Some(prefix) = &rewrite.strip_prefix {
+                if prefix_match(prefix, &new_path) {
+                    new_path = join_path("/", &new_path[prefix.len()..]);
+                }
+            }
+            if let Some(prefix) = &rewrite.add_prefix {
+                new_path = join_path(prefix, &new_path);
+            }
+        }
+        if !new_path.starts_with('/') {
+            new_path.insert(0, '/');
+        }
+        if new_path == path && query.as_deref() == uri.query() {
+            return;
+        }
+        let path_and_query = match query {
+            Some(query) => format!("{}?{}", new_path, query),
+            None => new_path,
+        };
+        log::debug!("rewrite {} -> {}", uri, path_and_query);
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = match path_and_query.parse() {
+            Ok(path_and_query) => Some(path_and_query),
+            Err(err) => {
+                log::warn!("invalid rewritten path {}: {}", path_and_query, err);
+                return;
+            }
+        };
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *request.uri_mut() = uri;
+        }
+    }
+}
+
+/// join `base` and `rest` with exactly one `/` between them
+fn join_path(base: &str, rest: &str) -> String {
+    match (base.ends_with('/'), rest.starts_with('/')) {
+        (true, true) => format!("{}{}", base, &rest[1..]),
+        (false, false) if !rest.is_empty() => format!("{}/{}", base, rest),
+        _ => format!("{}{}", base, rest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(
+        uri: &str,
+        base: Option<&str>,
+        rewrite: Option<&RewriteRule>,
+        captures: &PathCaptures,
+    ) -> String {
+        let mut request = http::Request::builder()
+            .uri(uri)
+            .body(Payload::None)
+            .unwrap();
+        Rewrite::rewrite_path(&mut request, base, rewrite, captures);
+        request.uri().to_string()
+    }
+
+    fn compiled(mut rule: RewriteRule) -> RewriteRule {
+        rule.compile().unwrap();
+        rule
+    }
+
+    #[test]
+    fn base_path_replaces_matched_prefix() {
+        let api = PathCaptures::whole("/api/");
+        assert_eq!(
+            rewrite("/api/users?a=1", Some("/"), None, &api),
+            "/users?a=1"
+        );
+        assert_eq!(rewrite("/api/users", Some("/v2/"), None, &api), "/v2/users");
+        assert_eq!(rewrite("/api/users", None, None, &api), "/api/users");
+
+        let api = PathCaptures::whole("/api");
+        assert_eq!(rewrite("/api", Some("/"), None, &api), "/");
+        assert_eq!(rewrite("/api/users", Some("/v2"), None, &api), "/v2/users");
+    }
+
+    #[test]
+    fn path_template() {
+        let regex = Regex::new(r"^/users/(?P<id>\d+)$").unwrap();
+        let captures = PathCaptures::from_regex(&regex, &regex.captures("/users/42").unwrap());
+        let rule = RewriteRule {
+            path: Some("/u/${id}".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewrite("/users/42?a=1", Some("/"), Some(&rule), &captures),
+            "/u/42?a=1"
+        );
+        let rule = RewriteRule {
+            path: Some("/u?id=$1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewrite("/users/42?a=1", None, Some(&rule), &captures),
+            "/u?id=42"
+        );
+    }
+
+    #[test]
+    fn regex_rewrite() {
+        let rule = compiled(RewriteRule {
+            regex: Some(RegexRewrite::new(r"^/api/users/(\d+)$", "/u/$1")),
+            ..Default::default()
+        });
+        let api = PathCaptures::whole("/api/");
+        assert_eq!(
+            rewrite("/api/users/7?a=1", Some("/"), Some(&rule), &api),
+            "/u/7?a=1"
+        );
+        // not matching falls back to the base path
+        assert_eq!(
+            rewrite("/api/users", Some("/"), Some(&rule), &api),
+            "/users"
+        );
+
+        let mut rule = RewriteRule {
+            path: Some("/".to_owned()),
+            regex: Some(RegexRewrite::new("^/", "/")),
+            ..Default::default()
+        };
+        assert!(rule.compile().is_err());
+        let mut rule = RewriteRule {
+            regex: Some(RegexRewrite::new("(", "/")),
+            ..Default::default()
+        };
+        assert!(rule.compile().is_err());
+    }
+
+    #[test]
+    fn strip_prefix_is_segment_aware() {
+        let root = PathCaptures::whole("/");
+        let rule = RewriteRule {
+            strip_prefix: Some("/api".to_owned()),
+            add_prefix: Some("/v1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(rewrite("/api/users", None, Some(&rule), &root), "/v1/users");
+        assert_eq!(rewrite("/api", None, Some(&rule), &root), "/v1/");
+        assert_eq!(
+            rewrite("/apix/users", None, Some(&rule), &root),
+            "/v1/apix/users"
+        );
+    }
+
+    #[test]
+    fn join_paths() {
+        assert_eq!(join_path("/v2/", "/users"), "/v2/users");
+        assert_eq!(join_path("/v2", "users"), "/v2/users");
+        assert_eq!(join_path("/v2", ""), "/v2");
+        assert_eq!(join_path("/", ""), "/");
+    }
 }
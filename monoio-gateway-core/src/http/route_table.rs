@@ -0,0 +1,302 @@
+use std::mem;
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::router::{MatchType, RouterRule};
+
+/// Order in which rules of a server are tried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RulePrecedence {
+    /// exact rules, then the longest prefix, then regex rules in config
+    /// order, `preferred_prefix` rules are plain prefixes
+    #[default]
+    PrefixFirst,
+    /// like nginx `location` blocks: exact rules, then the longest preferred
+    /// prefix if no prefix is longer, then regex rules in config order, then
+    /// the longest prefix
+    Nginx,
+}
+
+/// Rules of one server compiled for lookup.
+///
+/// Exact and prefix rules are stored in a radix tree keyed by their path, so
+/// finding the candidates of a request takes one walk over the request path
+/// no matter how many rules the server has. Regex rules can not be indexed
+/// and are kept in config order.
+///
+/// The table stores indices into the rules it was built from, it has to be
+/// rebuilt when the rules change.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    root: Node,
+    regex: Vec<usize>,
+    precedence: RulePrecedence,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// edge label from the parent node, never empty except for the root
+    label: String,
+    children: Vec<Node>,
+    /// exact rules whose path ends at this node, in config order
+    exact: Vec<usize>,
+    /// prefix rules whose path ends at this node, in config order
+    prefix: Vec<usize>,
+    /// preferred prefix rules whose path ends at this node, in config order
+    preferred: Vec<usize>,
+}
+
+impl RouteTable {
+    pub fn new<A>(rules: &[RouterRule<A>], precedence: RulePrecedence) -> Self {
+        let mut table = Self {
+            precedence,
+            ..Default::default()
+        };
+        for (index, rule) in rules.iter().enumerate() {
+            match rule.match_type {
+                MatchType::Exact => table.root.insert(&rule.path).exact.push(index),
+                MatchType::Prefix => table.root.insert(&rule.path).prefix.push(index),
+                MatchType::PreferredPrefix => table.root.insert(&rule.path).preferred.push(index),
+                MatchType::Regex => table.regex.push(index),
+            }
+        }
+        table
+    }
+
+    /// Indices of the rules that may serve `path`, by precedence.
+    ///
+    /// Exact rules come first. By default the prefix rules follow from the
+    /// longest one, then regex rules in config order. With
+    /// [`RulePrecedence::Nginx`] preferred prefix rules longer than any
+    /// matching prefix rule follow, then regex rules and last the other
+    /// prefix rules. Exact and prefix rules yielded already match `path`,
+    /// regex rules still have to be matched by the caller.
+    pub fn candidates(&self, path: &str) -> impl Iterator<Item = usize> + '_ {
+        let mut node = &self.root;
+        let mut rest = path;
+        // nodes of prefix rules on the walk, shortest first
+        let mut prefixes = vec![];
+        let mut exact: &[usize] = &[];
+        loop {
+            let boundary = rest.is_empty() || rest.starts_with('/') || node.label.ends_with('/');
+            if boundary && !(node.prefix.is_empty() && node.preferred.is_empty()) {
+                prefixes.push(node);
+            }
+            if rest.is_empty() {
+                exact = &node.exact;
+                break;
+            }
+            match node
+                .children
+                .iter()
+                .find(|child| rest.starts_with(&child.label))
+            {
+                Some(child) => {
+                    rest = &rest[child.label.len()..];
+                    node = child;
+                }
+                None => break,
+            }
+        }
+        // prefix rules tried before and after the regex rules
+        let mut before = vec![];
+        let mut after = vec![];
+        for node in prefixes.into_iter().rev() {
+            match self.precedence {
+                RulePrecedence::PrefixFirst => {
+                    before.extend_from_slice(&node.preferred);
+                    before.extend_from_slice(&node.prefix);
+                }
+                RulePrecedence::Nginx => {
+                    if after.is_empty() {
+                        before.extend_from_slice(&node.preferred);
+                    } else {
+                        after.extend_from_slice(&node.preferred);
+                    }
+                    after.extend_from_slice(&node.prefix);
+                }
+            }
+        }
+        exact
+            .iter()
+            .copied()
+            .chain(before)
+            .chain(self.regex.iter().copied())
+            .chain(after)
+    }
+}
+
+impl Node {
+    /// node of `key` below this node, created if missing
+    fn insert(&mut self, key: &str) -> &mut Node {
+        if key.is_empty() {
+            return self;
+        }
+        let position = self
+            .children
+            .iter()
+            .position(|child| common_prefix(&child.label, key) > 0);
+        let index = match position {
+            Some(index) => index,
+            None => {
+                self.children.push(Node {
+                    label: key.to_owned(),
+                    ..Default::default()
+                });
+                return self.children.last_mut().unwrap();
+            }
+        };
+        let child = &mut self.children[index];
+        let common = common_prefix(&child.label, key);
+        if common < child.label.len() {
+            // split the edge, the child keeps the common part of the label
+            let split = Node {
+                label: child.label.split_off(common),
+                children: mem::take(&mut child.children),
+                exact: mem::take(&mut child.exact),
+                prefix: mem::take(&mut child.prefix),
+                preferred: mem::take(&mut child.preferred),
+            };
+            child.children.push(split);
+        }
+        child.insert(&key[common..])
+    }
+}
+
+/// length in bytes of the common prefix of `a` and `b`, on a char boundary
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map(|((index, _), _)| index)
+        .unwrap_or_else(|| a.len().min(b.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(rules: &[(&str, MatchType)]) -> (Vec<RouterRule<()>>, RouteTable) {
+        build_with(rules, RulePrecedence::PrefixFirst)
+    }
+
+    fn build_with(
+        rules: &[(&str, MatchType)],
+        precedence: RulePrecedence,
+    ) -> (Vec<RouterRule<()>>, RouteTable) {
+        let rules: Vec<_> = rules
+            .iter()
+            .map(|(path, match_type)| {
+                RouterRule::new(*path, ())
+                    .with_match_type(*match_type)
+                    .unwrap()
+            })
+            .collect();
+        let table = RouteTable::new(&rules, precedence);
+        (rules, table)
+    }
+
+    /// first rule serving `path`, like the router picks it
+    fn first(rules: &[RouterRule<()>], table: &RouteTable, path: &str) -> Option<usize> {
+        table
+            .candidates(path)
+            .find(|index| rules[*index].match_path(path).is_some())
+    }
+
+    #[test]
+    fn precedence() {
+        let (rules, table) = build(&[
+            ("/", MatchType::Prefix),
+            (r"\.php$", MatchType::Regex),
+            ("/static/", MatchType::PreferredPrefix),
+            ("/index.php", MatchType::Exact),
+            ("/static/app/", MatchType::Prefix),
+            (r"^/v\d+/", MatchType::Regex),
+        ]);
+        assert_eq!(first(&rules, &table, "/index.php"), Some(3));
+        assert_eq!(first(&rules, &table, "/static/x.php"), Some(2));
+        assert_eq!(first(&rules, &table, "/static/app/x.php"), Some(4));
+        // any prefix wins over regex rules, `/` matches every path
+        assert_eq!(first(&rules, &table, "/x.php"), Some(0));
+        assert_eq!(first(&rules, &table, "/v1/x"), Some(0));
+        assert_eq!(
+            table.candidates("/static/app/x").collect::<Vec<_>>(),
+            vec![4, 2, 0, 1, 5]
+        );
+
+        // regex rules apply where no prefix matches
+        let (rules, table) = build(&[("/api", MatchType::Prefix), (r"^/v\d+/", MatchType::Regex)]);
+        assert_eq!(first(&rules, &table, "/api/v1/"), Some(0));
+        assert_eq!(first(&rules, &table, "/v1/x"), Some(1));
+    }
+
+    #[test]
+    fn nginx_precedence() {
+        let (rules, table) = build_with(
+            &[
+                ("/", MatchType::Prefix),
+                (r"\.php$", MatchType::Regex),
+                ("/static/", MatchType::PreferredPrefix),
+                ("/index.php", MatchType::Exact),
+                ("/static/app/", MatchType::Prefix),
+            ],
+            RulePrecedence::Nginx,
+        );
+        assert_eq!(first(&rules, &table, "/index.php"), Some(3));
+        assert_eq!(first(&rules, &table, "/static/x.php"), Some(2));
+        // a longer prefix rule makes the regex rules apply again
+        assert_eq!(first(&rules, &table, "/static/app/x.php"), Some(1));
+        assert_eq!(first(&rules, &table, "/static/app/x.js"), Some(4));
+        assert_eq!(first(&rules, &table, "/x.php"), Some(1));
+        assert_eq!(first(&rules, &table, "/x.js"), Some(0));
+        assert_eq!(
+            table.candidates("/static/app/x").collect::<Vec<_>>(),
+            vec![1, 4, 2, 0]
+        );
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let (rules, table) = build(&[
+            ("/api", MatchType::Prefix),
+            ("/api/v1/users", MatchType::Prefix),
+            ("/api/v1", MatchType::Prefix),
+            ("/apiary", MatchType::Prefix),
+        ]);
+        assert_eq!(first(&rules, &table, "/api/v1/users/1"), Some(1));
+        assert_eq!(first(&rules, &table, "/api/v1/user"), Some(2));
+        assert_eq!(first(&rules, &table, "/api/v2"), Some(0));
+        assert_eq!(first(&rules, &table, "/apiary/x"), Some(3));
+        assert_eq!(first(&rules, &table, "/apia"), None);
+        assert_eq!(
+            table.candidates("/api/v1/users").collect::<Vec<_>>(),
+            vec![1, 2, 0]
+        );
+    }
+
+    #[test]
+    fn regexes_in_config_order() {
+        let (rules, table) = build(&[
+            (r"^/users/\d+$", MatchType::Regex),
+            (r"^/users/", MatchType::Regex),
+            (r"^/users/1", MatchType::Regex),
+        ]);
+        assert_eq!(first(&rules, &table, "/users/1"), Some(0));
+        assert_eq!(first(&rules, &table, "/users/1x"), Some(1));
+        assert_eq!(table.candidates("/").collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let (rules, table) = build(&[("/docs/", MatchType::Prefix), ("/docs", MatchType::Exact)]);
+        assert_eq!(first(&rules, &table, "/docs"), Some(1));
+        assert_eq!(first(&rules, &table, "/docs/"), Some(0));
+        assert_eq!(first(&rules, &table, "/docs/a"), Some(0));
+        assert_eq!(first(&rules, &table, "/docsx"), None);
+
+        let (rules, table) = build(&[("/docs", MatchType::Prefix)]);
+        assert_eq!(first(&rules, &table, "/docs/"), Some(0));
+        assert_eq!(first(&rules, &table, "/docsx"), None);
+    }
+}
@@ -1,26 +1,88 @@
-use std::{collections::HashMap, path::Path};
+use std::{borrow::Cow, collections::HashMap, net::IpAddr, path::Path, sync::Arc};
 
 use anyhow::bail;
+use http::{HeaderValue, Request, StatusCode};
 use log::info;
 use monoio_http::ParamRef;
+use regex::Regex;
 use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{dns::Resolvable, error::GError, Builder, MAX_CONFIG_SIZE_LIMIT};
+use super::{
+    action::RuleAction,
+    admin::AdminConfig,
+    error_page::{validate_error_pages, ErrorPage, ErrorPages},
+    http2::Http2Settings,
+    keepalive::UpstreamKeepalive,
+    predicate::RoutePredicate,
+    retry::RetryPolicy,
+    route_table::{RouteTable, RulePrecedence},
+    ssl::{prepare_upstream_tls, UpstreamTls},
+    template::render,
+    timeout::{RouteTimeouts, Timeouts},
+    version::UpstreamProtocol,
+    RewriteRule,
+};
+use crate::{
+    balance::{
+        balance::{Endpoint, Upstream, UpstreamConfig, UpstreamGroup},
+        weighted::TrafficSplit,
+    },
+    dns::Resolvable,
+    error::GError,
+    Builder, CONFIG_READ_CHUNK,
+};
 
 type RouterMap<A> = HashMap<u16, Vec<RouterConfig<A>>>;
+type UpstreamMap<A> = HashMap<String, Arc<UpstreamGroup<A>>>;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RoutersConfig<A> {
     pub configs: Vec<RouterConfig<A>>,
+    /// load balanced groups rules refer to by name
+    #[serde(default = "Vec::new")]
+    pub upstreams: Vec<UpstreamConfig<A>>,
+    /// error pages of all servers, those of a server take precedence
+    #[serde(default)]
+    pub error_pages: ErrorPages,
+    /// endpoint changing split weights at runtime
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RouterConfig<A> {
     pub server_name: String,
+    /// aliases of `server_name`, wildcard and regex names are allowed in both
+    #[serde(default)]
+    pub server_names: Vec<String>,
     pub listen_port: Vec<u16>,
+    /// ports this server is the fallback of, for hosts matching no server
+    #[serde(default)]
+    pub default_server: Vec<u16>,
+    /// ports whose connections start with a PROXY protocol header of a load
+    /// balancer in front
+    #[serde(default)]
+    pub proxy_protocol: Vec<u16>,
     pub rules: Vec<RouterRule<A>>,
     pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub http2: Http2Settings,
+    /// bodies of the replies of the gateway itself by status code
+    #[serde(default)]
+    pub error_pages: ErrorPages,
+    /// order in which rules of different match types are tried
+    #[serde(default)]
+    pub rule_precedence: RulePrecedence,
+    /// `rules` compiled by [`RouterConfig::compile`]
+    #[serde(skip)]
+    pub table: Option<Arc<RouteTable>>,
+    /// plain http requests are redirected to https, resolved by
+    /// [`RouterConfig::compile`] before `listen_port` is split per port
+    #[serde(skip)]
+    pub redirect_https: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -28,44 +90,600 @@ pub struct TlsConfig {
     pub mail: String,
     pub chain: Option<String>,
     pub private_key: Option<String>,
+    /// reply `421 Misdirected Request` if the Host is served by another server than the SNI
+    #[serde(default)]
+    pub strict_sni: bool,
+    /// redirect plain http requests with `308` if the server listens on 443 too
+    #[serde(default = "default_force_https")]
+    pub force_https: bool,
+    /// `Strict-Transport-Security` of https responses
+    #[serde(default)]
+    pub hsts: Option<HstsConfig>,
+}
+
+fn default_force_https() -> bool {
+    true
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            mail: Default::default(),
+            chain: None,
+            private_key: None,
+            strict_sni: false,
+            force_https: default_force_https(),
+            hsts: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HstsConfig {
+    /// seconds, one year by default
+    #[serde(default = "default_hsts_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+fn default_hsts_max_age() -> u64 {
+    31536000
+}
+
+impl HstsConfig {
+    pub fn header_value(&self) -> HeaderValue {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        // only ascii is formatted
+        HeaderValue::from_str(&value).unwrap()
+    }
 }
 
 impl<A> RouterConfig<A> {
+    pub fn new(server_name: impl Into<String>, listen_port: Vec<u16>) -> Self {
+        Self {
+            server_name: server_name.into(),
+            server_names: vec![],
+            listen_port,
+            default_server: vec![],
+            proxy_protocol: vec![],
+            rules: vec![],
+            tls: None,
+            timeouts: Timeouts::default(),
+            http2: Http2Settings::default(),
+            error_pages: ErrorPages::new(),
+            rule_precedence: RulePrecedence::default(),
+            table: None,
+            redirect_https: false,
+        }
+    }
+
     pub fn get_rules(&self) -> &Vec<RouterRule<A>> {
         &self.rules
     }
+
+    /// `server_name` followed by its aliases
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.server_name).chain(self.server_names.iter())
+    }
+
+    /// Bind rules with `upstream` to the group of that name.
+    pub fn bind_upstreams(&mut self, upstreams: &UpstreamMap<A>) -> Result<(), GError> {
+        for rule in self.rules.iter_mut() {
+            if let Some(name) = &rule.upstream {
+                match upstreams.get(name) {
+                    Some(group) => rule.group = Some(group.clone()),
+                    None => bail!(
+                        "unknown upstream {} in rule {} of {}",
+                        name,
+                        rule.path,
+                        self.server_name
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the pages of `error_pages` this server has none of a status for.
+    pub fn inherit_error_pages(&mut self, error_pages: &ErrorPages) {
+        for (status, page) in error_pages.iter() {
+            self.error_pages
+                .entry(*status)
+                .or_insert_with(|| page.clone());
+        }
+    }
+
+    #[inline]
+    pub fn error_page(&self, status: StatusCode) -> Option<&ErrorPage> {
+        self.error_pages.get(&status.as_u16())
+    }
+
+    /// Compile regex rules and the route table, must be called again after
+    /// `rules` are changed.
+    pub fn compile(&mut self) -> Result<(), GError> {
+        for rule in self.rules.iter_mut() {
+            if let Err(err) = rule.compile() {
+                bail!(
+                    "invalid rule {} of {}: {}",
+                    rule.path,
+                    self.server_name,
+                    err
+                );
+            }
+        }
+        if let Err(err) = self.http2.validate() {
+            bail!("invalid http2 of {}: {}", self.server_name, err);
+        }
+        if let Err(err) = validate_error_pages(&self.error_pages) {
+            bail!("invalid error_pages of {}: {}", self.server_name, err);
+        }
+        self.table = Some(Arc::new(RouteTable::new(&self.rules, self.rule_precedence)));
+        self.redirect_https = match &self.tls {
+            Some(tls) => tls.force_https && self.listen_port.contains(&443),
+            None => false,
+        };
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_compiled(&self) -> bool {
+        self.table.is_some()
+    }
+
+    /// Find the rule serving `req`, nothing matches before [`RouterConfig::compile`].
+    ///
+    /// Rules are tried in the order of [`RouteTable::candidates`]: by default
+    /// exact rules first, then the longest matching prefix, then regex rules
+    /// in config order. Rules whose `match` predicate rejects the request are
+    /// skipped, so rules sharing a path are tried in config order.
+    pub fn match_rule<B>(&self, req: &Request<B>) -> Option<(&RouterRule<A>, PathCaptures)> {
+        let path = req.uri().path();
+        self.table
+            .as_ref()?
+            .candidates(path)
+            .filter_map(|index| {
+                let rule = self.rules.get(index)?;
+                let captures = match rule.match_type {
+                    MatchType::Regex => rule.match_path(path)?,
+                    // matched by the table already
+                    _ => PathCaptures::whole(&rule.path),
+                };
+                Some((rule, captures))
+            })
+            .find(|(rule, _)| rule.match_request(req))
+    }
+}
+
+/// How [`RouterRule::path`] is compared with the request path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchType {
+    /// `location = /path`
+    Exact,
+    /// `location /path`, matched on whole path segments
+    #[default]
+    Prefix,
+    /// `location ^~ /path`, with [`RulePrecedence::Nginx`] a prefix skipping
+    /// regex rules when it is the longest one matching, a plain prefix
+    /// otherwise
+    PreferredPrefix,
+    /// `location ~ regex`
+    Regex,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RouterRule<A> {
     pub path: String,
-    pub proxy_pass: A,
-    // TODO
+    #[serde(default)]
+    pub match_type: MatchType,
+    /// extra conditions on method, headers, query and cookies
+    #[serde(default, rename = "match")]
+    pub predicate: Option<RoutePredicate>,
+    /// endpoint of the rule, may be omitted if `split`, `upstream` or `action` is set
+    pub proxy_pass: Option<A>,
+    /// weighted endpoints, used instead of `proxy_pass`
+    pub split: Option<TrafficSplit<A>>,
+    /// name of a load balanced group of [`RoutersConfig::upstreams`]
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// reply by the gateway instead of proxying
+    #[serde(default)]
+    pub action: Option<RuleAction>,
+    /// path and query rewrite before proxying
+    #[serde(default)]
+    pub rewrite: Option<RewriteRule>,
+    /// retries of failed upstream requests
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// upstream and request body timeouts overriding those of the server
+    #[serde(default)]
+    pub timeouts: Option<RouteTimeouts>,
+    /// tls of https endpoints of `proxy_pass` and `split`, an `upstream`
+    /// group has its own
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTls>,
+    /// protocol of the endpoints of `proxy_pass` and `split`, an `upstream`
+    /// group has its own
+    #[serde(default)]
+    pub upstream_protocol: Option<UpstreamProtocol>,
+    /// connection reuse of the endpoints of `proxy_pass` and `split`, an
+    /// `upstream` group has its own
+    #[serde(default)]
+    pub upstream_keepalive: Option<UpstreamKeepalive>,
+    /// compiled `path` for [`MatchType::Regex`]
+    #[serde(skip)]
+    regex: Option<Regex>,
+    /// group of `upstream`, bound by [`RouterConfig::bind_upstreams`]
+    #[serde(skip, default = "Option::default")]
+    group: Option<Arc<UpstreamGroup<A>>>,
 }
 
 impl<A> RouterRule<A> {
+    /// A prefix rule, the default of config files.
+    pub fn new(path: impl Into<String>, proxy_pass: A) -> Self {
+        Self {
+            path: path.into(),
+            match_type: MatchType::Prefix,
+            predicate: None,
+            proxy_pass: Some(proxy_pass),
+            split: None,
+            upstream: None,
+            action: None,
+            rewrite: None,
+            retry: None,
+            timeouts: None,
+            upstream_tls: None,
+            upstream_protocol: None,
+            upstream_keepalive: None,
+            regex: None,
+            group: None,
+        }
+    }
+
+    pub fn with_match_type(mut self, match_type: MatchType) -> Result<Self, GError> {
+        self.match_type = match_type;
+        self.compile()?;
+        Ok(self)
+    }
+
+    pub fn with_predicate(mut self, predicate: RoutePredicate) -> Self {
+        self.predicate = Some(predicate);
+        self
+    }
+
+    /// Reply with `action` instead of proxying.
+    pub fn with_action(mut self, action: RuleAction) -> Self {
+        self.proxy_pass = None;
+        self.action = Some(action);
+        self
+    }
+
+    /// Proxy to weighted endpoints instead of `proxy_pass`.
+    pub fn with_split(mut self, split: TrafficSplit<A>) -> Self {
+        self.proxy_pass = None;
+        self.split = Some(split);
+        self
+    }
+
+    /// Proxy to a load balanced group instead of `proxy_pass`.
+    pub fn with_upstream(mut self, group: Arc<UpstreamGroup<A>>) -> Self {
+        self.proxy_pass = None;
+        self.upstream = Some(group.name().to_owned());
+        self.group = Some(group);
+        self
+    }
+
+    pub fn with_rewrite(mut self, rewrite: RewriteRule) -> Self {
+        self.rewrite = Some(rewrite);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn with_upstream_tls(mut self, tls: UpstreamTls) -> Self {
+        self.upstream_tls = Some(tls);
+        self
+    }
+
+    pub fn with_upstream_protocol(mut self, protocol: UpstreamProtocol) -> Self {
+        self.upstream_protocol = Some(protocol);
+        self
+    }
+
+    pub fn with_upstream_keepalive(mut self, keepalive: UpstreamKeepalive) -> Self {
+        self.upstream_keepalive = Some(keepalive);
+        self
+    }
+
     pub fn get_path(&self) -> &String {
         &self.path
     }
 
-    pub fn get_proxy_pass(&self) -> &A {
-        &self.proxy_pass
+    pub fn get_proxy_pass(&self) -> Option<&A> {
+        self.proxy_pass.as_ref()
+    }
+
+    pub fn get_upstream(&self) -> Option<&Arc<UpstreamGroup<A>>> {
+        self.group.as_ref()
+    }
+
+    /// Tls settings of the endpoints of the rule, those of its group if bound.
+    pub fn get_upstream_tls(&self) -> Option<&UpstreamTls> {
+        match &self.group {
+            Some(group) => group.tls(),
+            None => self.upstream_tls.as_ref(),
+        }
+    }
+
+    /// Protocol of the endpoints of the rule, that of its group if bound.
+    pub fn get_upstream_protocol(&self) -> UpstreamProtocol {
+        match &self.group {
+            Some(group) => group.protocol(),
+            None => self.upstream_protocol.unwrap_or_default(),
+        }
+    }
+
+    /// Connection reuse of the endpoints of the rule, that of its group if bound.
+    pub fn get_upstream_keepalive(&self) -> UpstreamKeepalive {
+        match &self.group {
+            Some(group) => group.keepalive(),
+            None => self.upstream_keepalive.unwrap_or_default(),
+        }
+    }
+
+    /// Endpoint of a request, picked from `upstream` or `split` if set.
+    pub fn select_upstream<B>(
+        &self,
+        req: &Request<B>,
+        client_ip: IpAddr,
+    ) -> Option<Upstream<'_, A>> {
+        if let Some(group) = &self.group {
+            return group.select(req, client_ip).map(Upstream::Balanced);
+        }
+        match &self.split {
+            Some(split) => split.select(req, client_ip),
+            None => self.proxy_pass.as_ref(),
+        }
+        .map(Upstream::Fixed)
+    }
+
+    /// Endpoint of a retry of a request, balanced upstreams avoid the
+    /// endpoints `tried` while others are available.
+    pub fn select_retry<B>(
+        &self,
+        req: &Request<B>,
+        client_ip: IpAddr,
+        tried: &[Arc<Endpoint<A>>],
+    ) -> Option<Upstream<'_, A>> {
+        match &self.group {
+            Some(group) => group
+                .select_retry(req, client_ip, tried)
+                .map(Upstream::Balanced),
+            None => self.select_upstream(req, client_ip),
+        }
+    }
+
+    /// compile regex of this rule, must be called before matching regex rules.
+    pub fn compile(&mut self) -> Result<(), GError> {
+        match &self.action {
+            Some(action) => action.validate()?,
+            None if self.proxy_pass.is_none()
+                && self.split.is_none()
+                && self.upstream.is_none() =>
+            {
+                bail!("one of proxy_pass, split, upstream and action is required")
+            }
+            None => {}
+        }
+        if let (Some(name), None) = (&self.upstream, &self.group) {
+            bail!("upstream {} is not bound", name);
+        }
+        if let Some(split) = &mut self.split {
+            split.compile()?;
+        }
+        if let Some(retry) = &self.retry {
+            retry.validate()?;
+        }
+        if let Some(rewrite) = &mut self.rewrite {
+            rewrite.compile()?;
+        }
+        if let (Some(_), Some(upstream)) = (&self.upstream_tls, &self.upstream) {
+            bail!(
+                "rule {} proxies to upstream {}, set tls on the group",
+                self.path,
+                upstream
+            );
+        }
+        if let (Some(_), Some(upstream)) = (&self.upstream_protocol, &self.upstream) {
+            bail!(
+                "rule {} proxies to upstream {}, set protocol on the group",
+                self.path,
+                upstream
+            );
+        }
+        if let (Some(_), Some(upstream)) = (&self.upstream_keepalive, &self.upstream) {
+            bail!(
+                "rule {} proxies to upstream {}, set keepalive on the group",
+                self.path,
+                upstream
+            );
+        }
+        if self.upstream.is_none() {
+            prepare_upstream_tls(self.upstream_tls.as_ref(), self.get_upstream_protocol())?;
+        }
+        self.regex = match self.match_type {
+            MatchType::Regex => Some(Regex::new(&self.path)?),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    #[inline]
+    pub fn match_request<B>(&self, req: &Request<B>) -> bool {
+        match &self.predicate {
+            Some(predicate) => predicate.matches(req),
+            None => true,
+        }
+    }
+
+    pub fn match_path(&self, path: &str) -> Option<PathCaptures> {
+        match self.match_type {
+            MatchType::Exact => (path == self.path).then(|| PathCaptures::whole(path)),
+            MatchType::Prefix | MatchType::PreferredPrefix => {
+                prefix_match(&self.path, path).then(|| PathCaptures::whole(&self.path))
+            }
+            MatchType::Regex => {
+                let regex = self.regex.as_ref()?;
+                let captures = regex.captures(path)?;
+                Some(PathCaptures::from_regex(regex, &captures))
+            }
+        }
+    }
+}
+
+/// segment aware prefix match, `/api` matches `/api` and `/api/v1` but not `/apiary`
+#[inline]
+pub(crate) fn prefix_match(prefix: &str, path: &str) -> bool {
+    if !path.starts_with(prefix) {
+        return false;
+    }
+    path.len() == prefix.len() || prefix.ends_with('/') || path.as_bytes()[prefix.len()] == b'/'
+}
+
+/// Captures of a matched rule, group 0 is the matched part of the request path.
+///
+/// Stored into request extensions by the router so later stages (rewrite,
+/// redirect) can refer to them as `$1` or `${name}`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathCaptures {
+    groups: Vec<Option<String>>,
+    named: Vec<(String, String)>,
+}
+
+impl PathCaptures {
+    pub(crate) fn whole(matched: &str) -> Self {
+        Self {
+            groups: vec![Some(matched.to_owned())],
+            named: vec![],
+        }
+    }
+
+    pub(crate) fn from_regex(regex: &Regex, captures: &regex::Captures) -> Self {
+        let groups = captures
+            .iter()
+            .map(|group| group.map(|m| m.as_str().to_owned()))
+            .collect();
+        let named = regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| {
+                captures
+                    .name(name)
+                    .map(|m| (name.to_owned(), m.as_str().to_owned()))
+            })
+            .collect();
+        Self { groups, named }
+    }
+
+    /// the matched part of the request path
+    pub fn matched(&self) -> &str {
+        self.get(0).unwrap_or("")
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.groups.get(index).and_then(|group| group.as_deref())
+    }
+
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.named
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// capture of a template variable, `1` or `name`
+    pub fn lookup(&self, var: &str) -> Option<&str> {
+        match var.parse::<usize>() {
+            Ok(index) => self.get(index),
+            Err(_) => self.name(var),
+        }
+    }
+
+    /// Expand `$1`, `${1}` and `${name}` in `template` with the captures.
+    pub fn expand(&self, template: &str) -> String {
+        render(template, |var| self.lookup(var).map(Cow::Borrowed))
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
     }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Router<A> {
     map: RouterMap<A>,
+    /// groups shared by all ports and workers
+    #[serde(skip, default = "HashMap::new")]
+    upstreams: UpstreamMap<A>,
+    #[serde(skip)]
+    admin: Option<AdminConfig>,
+}
+
+impl<A> Router<A> {
+    pub fn upstream(&self, name: &str) -> Option<&Arc<UpstreamGroup<A>>> {
+        self.upstreams.get(name)
+    }
+
+    pub fn upstreams(&self) -> &HashMap<String, Arc<UpstreamGroup<A>>> {
+        &self.upstreams
+    }
+
+    #[inline]
+    pub fn admin(&self) -> Option<&AdminConfig> {
+        self.admin.as_ref()
+    }
 }
 
 impl<A> Builder<RoutersConfig<A>> for Router<A>
 where
     A: Resolvable,
 {
-    fn build_with_config(config: RoutersConfig<A>) -> Self {
+    fn build_with_config(config: RoutersConfig<A>) -> Result<Self, GError> {
         let mut rule_map = RouterMap::new();
-        for conf in config.configs {
+        let mut upstreams = UpstreamMap::new();
+        if let Some(admin) = &config.admin {
+            admin.validate()?;
+        }
+        for upstream in config.upstreams {
+            info!("building upstream {}", upstream.name);
+            let name = upstream.name.clone();
+            if upstreams.contains_key(&name) {
+                bail!("upstream {} is declared twice", name);
+            }
+            let group = UpstreamGroup::new(upstream)?;
+            upstreams.insert(name, Arc::new(group));
+        }
+        for mut conf in config.configs {
             info!("building {}", conf.server_name);
+            conf.inherit_error_pages(&config.error_pages);
+            conf.bind_upstreams(&upstreams)?;
+            conf.compile()?;
             for listen_port in conf.listen_port.iter() {
                 if !rule_map.contains_key(listen_port) {
                     rule_map.insert(*listen_port, vec![]);
@@ -77,7 +695,11 @@ where
                     .and_modify(|conf_vec| conf_vec.push(cloned));
             }
         }
-        Self { map: rule_map }
+        Ok(Self {
+            map: rule_map,
+            upstreams,
+            admin: config.admin,
+        })
     }
 }
 
@@ -94,12 +716,17 @@ where
     pub async fn read_from_file(path: impl AsRef<Path>) -> Result<RoutersConfig<A>, GError> {
         match monoio::fs::File::open(path).await {
             Ok(f) => {
-                let buf = vec![0; MAX_CONFIG_SIZE_LIMIT];
-                let (sz, buf) = f.read_at(buf, 0).await;
-                let len = sz?;
-                info!("read {} bytes from config", len);
-                let raw = &buf[..len];
-                let router_config = serde_json::from_slice::<RoutersConfig<A>>(raw)?;
+                let mut raw = Vec::new();
+                loop {
+                    let buf = Vec::with_capacity(CONFIG_READ_CHUNK);
+                    let (sz, buf) = f.read_at(buf, raw.len() as u64).await;
+                    if sz? == 0 {
+                        break;
+                    }
+                    raw.extend_from_slice(&buf);
+                }
+                info!("read {} bytes from config", raw.len());
+                let router_config = serde_json::from_slice::<RoutersConfig<A>>(&raw)?;
                 info!("gateway count: {}", router_config.configs.len());
                 Ok(router_config)
             }
@@ -107,3 +734,72 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::http::Domain;
+
+    fn build(json: &str) -> Result<Router<Domain>, GError> {
+        Router::build_with_config(serde_json::from_str::<RoutersConfig<Domain>>(json).unwrap())
+    }
+
+    #[test]
+    fn build_with_config_reports_invalid_config() {
+        let server = r#"{"server_name": "a", "listen_port": [80], "rules": [
+            {"path": "/", "upstream": "backend"}
+        ]}"#;
+        let upstream = r#"{"name": "backend", "endpoints": [
+            {"proxy_pass": {"uri": "http://127.0.0.1:8000"}}
+        ]}"#;
+        let router = build(&format!(
+            r#"{{"configs": [{}], "upstreams": [{}]}}"#,
+            server, upstream
+        ))
+        .unwrap();
+        assert!(router.upstream("backend").is_some());
+        assert_eq!(router.param_ref()[&80].len(), 1);
+
+        let err = build(&format!(
+            r#"{{"configs": [{}], "upstreams": [{}, {}]}}"#,
+            server, upstream, upstream
+        ));
+        assert!(err.is_err());
+        let err = build(&format!(r#"{{"configs": [{}]}}"#, server));
+        assert!(err.err().unwrap().to_string().contains("unknown upstream"));
+        let err = build(
+            r#"{"configs": [{"server_name": "a", "listen_port": [80], "rules": [
+                {"path": "(", "match_type": "regex", "proxy_pass": {"uri": "http://127.0.0.1"}}
+            ]}]}"#,
+        );
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn rules_of_a_path_by_method() {
+        let router = build(
+            r#"{"configs": [{"server_name": "a", "listen_port": [80], "rules": [
+                {"path": "/upload", "match": {"methods": ["POST"]},
+                 "proxy_pass": {"uri": "http://uploads:8000"}},
+                {"path": "/upload", "match": {"methods": ["GET", "HEAD"]},
+                 "proxy_pass": {"uri": "http://files:8000"}}
+            ]}]}"#,
+        )
+        .unwrap();
+        let server = &router.param_ref()[&80][0];
+        let host = |method: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/upload")
+                .body(())
+                .unwrap();
+            server
+                .match_rule(&req)
+                .map(|(rule, _)| rule.get_proxy_pass().unwrap().host().to_string())
+        };
+        assert_eq!(host("POST").as_deref(), Some("uploads"));
+        assert_eq!(host("GET").as_deref(), Some("files"));
+        assert_eq!(host("HEAD").as_deref(), Some("files"));
+        assert_eq!(host("DELETE"), None);
+    }
+}
@@ -1,10 +1,16 @@
-use std::{fmt::Debug, fs::File, io::BufReader, path::Path};
+use std::{fmt::Debug, fs::File, io::BufReader, path::Path, sync::Arc, time::SystemTime};
 
 use anyhow::bail;
 use monoio_rustls::TlsConnector;
-use rustls::server::ResolvesServerCert;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::ResolvesServerCert,
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use serde_derive::{Deserialize, Serialize};
 
-use crate::{error::GError, CERTIFICATE_MAP, DEFAULT_SSL_CLIENT_CONFIG};
+use super::version::UpstreamProtocol;
+use crate::{error::GError, CERTIFICATE_MAP, DEFAULT_SSL_CLIENT_CONFIG, UPSTREAM_TLS_CONFIGS};
 
 #[derive(Default)]
 pub struct CertificateResolver;
@@ -27,7 +33,11 @@ impl ResolvesServerCert for CertificateResolver {
         match client_hello.server_name() {
             Some(server_name) => {
                 let map = CERTIFICATE_MAP.read().unwrap();
-                let item = map.get(server_name);
+                let item = map.get(server_name).or_else(|| {
+                    // fallback to the certificate of `*.parent.domain`
+                    let (_, parent) = server_name.split_once('.')?;
+                    map.get(&format!("*.{}", parent))
+                });
                 match item {
                     Some(item) => Some(item.to_owned()),
                     None => None,
@@ -97,3 +107,217 @@ where
 pub fn get_default_tls_connector() -> TlsConnector {
     TlsConnector::from(DEFAULT_SSL_CLIENT_CONFIG.clone())
 }
+
+/// Settings of an https endpoint spoken to with `protocol`, `None` for the
+/// default ones.
+///
+/// The ALPN protocols of `protocol` are offered unless `tls` sets its own.
+pub fn upstream_tls(tls: Option<&UpstreamTls>, protocol: UpstreamProtocol) -> Option<UpstreamTls> {
+    let alpn = protocol.alpn();
+    match tls {
+        Some(tls) if !tls.alpn.is_empty() || alpn.is_empty() => Some(tls.clone()),
+        None if alpn.is_empty() => None,
+        tls => Some(UpstreamTls {
+            alpn: alpn.iter().map(|protocol| protocol.to_string()).collect(),
+            ..tls.cloned().unwrap_or_default()
+        }),
+    }
+}
+
+/// Validate `tls` and build the client configs of endpoints spoken to with
+/// `protocol`, so that requests find them built and certificate files are
+/// read once at startup.
+pub fn prepare_upstream_tls(
+    tls: Option<&UpstreamTls>,
+    protocol: UpstreamProtocol,
+) -> Result<(), GError> {
+    if let Some(tls) = tls {
+        tls.validate()?;
+    }
+    // the variant offering the ALPN protocols of `protocol`
+    if let Some(tls) = upstream_tls(tls, protocol) {
+        tls.client_config()?;
+    }
+    Ok(())
+}
+
+/// Connector of `tls`, the default one trusting webpki roots if `None`.
+pub fn get_tls_connector(tls: Option<&UpstreamTls>) -> Result<TlsConnector, GError> {
+    match tls {
+        Some(tls) => Ok(TlsConnector::from(tls.client_config()?)),
+        None => Ok(get_default_tls_connector()),
+    }
+}
+
+/// TLS settings of connections to an upstream.
+///
+/// ```json
+/// {
+///   "ca": "/etc/gateway/internal-ca.pem",
+///   "client_cert": "/etc/gateway/client.pem",
+///   "client_key": "/etc/gateway/client.key",
+///   "server_name": "api.internal",
+///   "alpn": ["http/1.1"]
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UpstreamTls {
+    /// pem bundle of the trusted CAs, webpki roots if unset
+    #[serde(default)]
+    pub ca: Option<String>,
+    /// pem certificate chain sent to upstreams asking for client auth
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// `pkcs8` key of `client_cert`
+    #[serde(default)]
+    pub client_key: Option<String>,
+    /// name sent as SNI and verified, the endpoint host if unset
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// protocols offered by ALPN, none if empty
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// accept any upstream certificate, for development only
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+impl UpstreamTls {
+    pub fn validate(&self) -> Result<(), GError> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            bail!("client_cert and client_key must be set together");
+        }
+        if let Some(server_name) = &self.server_name {
+            if ServerName::try_from(server_name.as_str()).is_err() {
+                bail!("invalid upstream server_name {}", server_name);
+            }
+        }
+        if self.insecure {
+            log::warn!(
+                "upstream certificates are not verified, do not use insecure tls in production"
+            );
+        }
+        // certificate files are read once here
+        self.client_config()?;
+        Ok(())
+    }
+
+    /// Name sent to and verified of an upstream reached at `host`.
+    pub fn server_name(&self, host: &str) -> Result<ServerName, GError> {
+        let name = self.server_name.as_deref().unwrap_or(host);
+        Ok(ServerName::try_from(name)?)
+    }
+
+    /// Client config of these settings, built once and shared by all workers.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, GError> {
+        if let Some(config) = UPSTREAM_TLS_CONFIGS.read().unwrap().get(self) {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(self.build_client_config()?);
+        UPSTREAM_TLS_CONFIGS
+            .write()
+            .unwrap()
+            .insert(self.clone(), config.clone());
+        Ok(config)
+    }
+
+    fn build_client_config(&self) -> Result<ClientConfig, GError> {
+        let mut root_store = RootCertStore::empty();
+        match &self.ca {
+            Some(ca) => {
+                for cert in read_pem_chain_file(ca)? {
+                    root_store.add(&Certificate(cert))?;
+                }
+                if root_store.is_empty() {
+                    bail!("no certificate found in {}", ca);
+                }
+            }
+            None => root_store.add_server_trust_anchors(
+                webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }),
+            ),
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store);
+        let mut config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let chain = read_pem_chain_file(cert)?
+                    .into_iter()
+                    .map(Certificate)
+                    .collect();
+                let key = PrivateKey(read_private_key_file(key)?);
+                builder.with_single_cert(chain, key)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        if self.insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+        config.alpn_protocols = self
+            .alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        Ok(config)
+    }
+}
+
+/// Verifier of [`UpstreamTls::insecure`] accepting any certificate.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_alpn_configs_ahead() {
+        let tls = UpstreamTls {
+            server_name: Some("api.internal".to_string()),
+            ..Default::default()
+        };
+        prepare_upstream_tls(Some(&tls), UpstreamProtocol::Auto).unwrap();
+        let alpn = upstream_tls(Some(&tls), UpstreamProtocol::Auto).unwrap();
+        {
+            let configs = UPSTREAM_TLS_CONFIGS.read().unwrap();
+            assert!(configs[&tls].alpn_protocols.is_empty());
+            assert_eq!(
+                configs[&alpn].alpn_protocols,
+                vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+            );
+        }
+
+        // endpoints without tls settings of their own
+        prepare_upstream_tls(None, UpstreamProtocol::Http2).unwrap();
+        let h2 = upstream_tls(None, UpstreamProtocol::Http2).unwrap();
+        assert!(UPSTREAM_TLS_CONFIGS.read().unwrap().contains_key(&h2));
+        assert_eq!(upstream_tls(None, UpstreamProtocol::Http1), None);
+
+        let missing = UpstreamTls {
+            ca: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(prepare_upstream_tls(Some(&missing), UpstreamProtocol::Auto).is_err());
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    fs::Metadata,
+    io,
+    mem::ManuallyDrop,
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use bytes::Bytes;
+use http::{
+    header::{
+        ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, TRANSFER_ENCODING,
+    },
+    HeaderValue, Method, Request, StatusCode,
+};
+use monoio::fs::File;
+use monoio_http::{
+    common::response::Response,
+    h1::payload::{stream_payload_pair, FixedPayload, Payload, StreamPayloadSender},
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    error::GError,
+    transfer::{generate_redirect, generate_response},
+    util::percent::percent_decode,
+};
+
+/// most bytes of a file read at once, larger files are sent chunked
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serve files under `root`, like nginx `root` and `index`.
+///
+/// The request path is appended to `root`. Files are opened with io_uring
+/// and read a chunk at a time while the response is written.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StaticFiles {
+    pub root: String,
+    /// files tried in order for a directory
+    #[serde(default = "default_index")]
+    pub index: Vec<String>,
+}
+
+fn default_index() -> Vec<String> {
+    vec!["index.html".to_owned()]
+}
+
+/// size and validators of a file
+struct FileMeta {
+    len: u64,
+    etag: HeaderValue,
+    last_modified: HeaderValue,
+    modified: SystemTime,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            index: default_index(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), GError> {
+        if self.root.is_empty() {
+            bail!("root of static files is empty");
+        }
+        Ok(())
+    }
+
+    /// Path of the file of a request path, `None` if it would escape `root`.
+    ///
+    /// The path is percent decoded, `.` and empty segments are ignored and
+    /// `..` segments are rejected.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let decoded = String::from_utf8(percent_decode(path, false).into_owned()).ok()?;
+        let mut resolved = PathBuf::from(&self.root);
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                segment if segment.contains('\0') => return None,
+                segment => resolved.push(segment),
+            }
+        }
+        Some(resolved)
+    }
+
+    /// Reply `req` with a file, only `GET` and `HEAD` are allowed.
+    ///
+    /// Supports `If-None-Match`, `If-Modified-Since` and a single `Range`
+    /// with `If-Range`. A file larger than a chunk is sent chunked, its
+    /// [`FileBody`] has to be fed while the response is written.
+    pub async fn serve<B>(&self, req: &Request<B>) -> (Response, Option<FileBody>) {
+        let (path, file, metadata) = match self.find_file(req).await {
+            Ok(found) => found,
+            Err(resp) => return (resp, None),
+        };
+        let meta = FileMeta::new(&metadata);
+        if meta.not_modified(req) {
+            let mut resp = generate_response(StatusCode::NOT_MODIFIED);
+            meta.insert_headers(&mut resp);
+            return (resp, None);
+        }
+        let range = match meta.range(req) {
+            Ok(range) => range,
+            Err(()) => {
+                let mut resp = generate_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                let content_range = format!("bytes */{}", meta.len);
+                resp.headers_mut().insert(
+                    CONTENT_RANGE,
+                    HeaderValue::from_str(&content_range).unwrap(),
+                );
+                return (resp, None);
+            }
+        };
+        let (status, start, len) = match range {
+            Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+            None => (StatusCode::OK, 0, meta.len),
+        };
+        let mut resp = generate_response(status);
+        let mut body = None;
+        if req.method() == Method::GET && len > 0 {
+            if len <= READ_CHUNK_SIZE as u64 {
+                match read_chunk(&file, start, len as usize).await {
+                    Ok(data) => *resp.body_mut() = Payload::Fixed(FixedPayload::new(data)),
+                    Err(err) => return (error_response(&err), None),
+                }
+            } else {
+                let (payload, sender) = stream_payload_pair();
+                *resp.body_mut() = Payload::Stream(payload);
+                body = Some(FileBody {
+                    file,
+                    pos: start,
+                    remaining: len,
+                    sender,
+                });
+            }
+        }
+        meta.insert_headers(&mut resp);
+        let headers = resp.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(&path)));
+        if body.is_some() {
+            headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        } else {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {}-{}/{}", start, start + len - 1, meta.len);
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+        }
+        (resp, body)
+    }
+
+    /// The open file of `req` and its path, or the reply if there is none.
+    async fn find_file<B>(&self, req: &Request<B>) -> Result<(PathBuf, File, Metadata), Response> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut resp = generate_response(StatusCode::METHOD_NOT_ALLOWED);
+            resp.headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Err(resp);
+        }
+        let uri_path = req.uri().path();
+        let path = match self.resolve(uri_path) {
+            Some(path) => path,
+            None => return Err(generate_response(StatusCode::BAD_REQUEST)),
+        };
+        let (file, metadata) = open_file(&path).await.map_err(|err| error_response(&err))?;
+        if metadata.is_dir() {
+            if !uri_path.ends_with('/') {
+                let location = match req.uri().query() {
+                    Some(query) => format!("{}/?{}", uri_path, query),
+                    None => format!("{}/", uri_path),
+                };
+                return Err(generate_redirect(StatusCode::MOVED_PERMANENTLY, &location));
+            }
+            for index in self.index.iter() {
+                let path = path.join(index);
+                if let Ok((file, metadata)) = open_file(&path).await {
+                    if metadata.is_file() {
+                        return Ok((path, file, metadata));
+                    }
+                }
+            }
+            return Err(generate_response(StatusCode::FORBIDDEN));
+        }
+        if !metadata.is_file() {
+            return Err(generate_response(StatusCode::NOT_FOUND));
+        }
+        Ok((path, file, metadata))
+    }
+}
+
+/// Body of a file sent chunked.
+pub struct FileBody {
+    file: File,
+    pos: u64,
+    remaining: u64,
+    sender: StreamPayloadSender,
+}
+
+impl FileBody {
+    /// Feed the file to the payload of its response, a chunk at a time.
+    pub async fn feed(mut self) {
+        while self.remaining > 0 {
+            let len = self.remaining.min(READ_CHUNK_SIZE as u64) as usize;
+            match read_chunk(&self.file, self.pos, len).await {
+                Ok(data) => self.sender.feed_data(Some(data)),
+                Err(err) => {
+                    log::warn!("static file error: {}", err);
+                    self.sender.feed_error(err.into());
+                    return;
+                }
+            }
+            self.pos += len as u64;
+            self.remaining -= len as u64;
+        }
+        self.sender.feed_data(None);
+    }
+}
+
+impl FileMeta {
+    fn new(metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}\"", secs, metadata.len());
+        Self {
+            len: metadata.len(),
+            etag: HeaderValue::from_str(&etag).unwrap(),
+            last_modified: HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
+            modified,
+        }
+    }
+
+    fn insert_headers(&self, resp: &mut Response) {
+        let headers = resp.headers_mut();
+        headers.insert(ETAG, self.etag.clone());
+        headers.insert(LAST_MODIFIED, self.last_modified.clone());
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+
+    /// `If-None-Match` takes precedence over `If-Modified-Since`
+    fn not_modified<B>(&self, req: &Request<B>) -> bool {
+        if let Some(if_none_match) = req.headers().get(IF_NONE_MATCH) {
+            let if_none_match = if_none_match.to_str().unwrap_or("");
+            return if_none_match.split(',').map(str::trim).any(|tag| {
+                tag == "*" || tag.trim_start_matches("W/").as_bytes() == self.etag.as_bytes()
+            });
+        }
+        match header_date(req, IF_MODIFIED_SINCE) {
+            Some(since) => truncate_secs(self.modified) <= since,
+            None => false,
+        }
+    }
+
+    /// Requested range `(first, last)` of a `GET`, `None` for the whole file
+    /// and `Err` if the range can not be satisfied.
+    fn range<B>(&self, req: &Request<B>) -> Result<Option<(u64, u64)>, ()> {
+        if req.method() != Method::GET {
+            return Ok(None);
+        }
+        let range = match req.headers().get(RANGE).and_then(|r| r.to_str().ok()) {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+        if let Some(if_range) = req.headers().get(IF_RANGE) {
+            let fresh = if_range.as_bytes() == self.etag.as_bytes()
+                || header_date(req, IF_RANGE) == Some(truncate_secs(self.modified));
+            if !fresh {
+                return Ok(None);
+            }
+        }
+        parse_range(range, self.len)
+    }
+}
+
+/// Parse a single `bytes=` range of a file of `len` bytes.
+///
+/// Malformed headers and multiple ranges are ignored, the whole file is
+/// served for them.
+fn parse_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // bytes=-500, the last 500 bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        // bytes=500-
+        (Ok(first), Err(_)) if last.is_empty() => {
+            if first >= len {
+                return Err(());
+            }
+            (first, len - 1)
+        }
+        (Ok(first), Ok(last)) if first <= last => {
+            if first >= len {
+                return Err(());
+            }
+            (first, last.min(len - 1))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(range))
+}
+
+/// Open `path` with io_uring and take its metadata from the open file.
+async fn open_file(path: &Path) -> io::Result<(File, Metadata)> {
+    let file = File::open(path).await?;
+    // fstat of an open file does not wait for the disk like a stat of its
+    // path may, the file stays owned by `file`
+    let std_file = ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(file.as_raw_fd()) });
+    let metadata = std_file.metadata()?;
+    Ok((file, metadata))
+}
+
+/// `len` bytes of `file` from `pos`, at most one chunk
+async fn read_chunk(file: &File, pos: u64, len: usize) -> io::Result<Bytes> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let buf = vec![0_u8; len - data.len()];
+        let (n, mut read) = file.read_at(buf, pos + data.len() as u64).await;
+        let n = n?;
+        if n == 0 {
+            // truncated while reading
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        read.truncate(n);
+        data.append(&mut read);
+    }
+    Ok(Bytes::from(data))
+}
+
+fn error_response(err: &io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::NotFound => generate_response(StatusCode::NOT_FOUND),
+        io::ErrorKind::PermissionDenied => generate_response(StatusCode::FORBIDDEN),
+        _ => {
+            log::warn!("static file error: {}", err);
+            generate_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn header_date<B>(req: &Request<B>, name: http::header::HeaderName) -> Option<SystemTime> {
+    let value = req.headers().get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+/// http dates have a precision of seconds
+fn truncate_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => UNIX_EPOCH,
+    }
+}
+
+/// `Content-Type` by file extension
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), Ok(Some((0, 4))));
+        assert_eq!(parse_range("bytes=5-", 10), Ok(Some((5, 9))));
+        assert_eq!(parse_range("bytes=5-100", 10), Ok(Some((5, 9))));
+        // suffix ranges
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Ok(Some((0, 9))));
+        // unsatisfiable, replied with 416
+        assert_eq!(parse_range("bytes=10-", 10), Err(()));
+        assert_eq!(parse_range("bytes=10-20", 10), Err(()));
+        assert_eq!(parse_range("bytes=-0", 10), Err(()));
+        assert_eq!(parse_range("bytes=-5", 0), Err(()));
+        // multiple ranges and malformed headers serve the whole file
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(parse_range("bytes=4-1", 10), Ok(None));
+        assert_eq!(parse_range("bytes=x-1", 10), Ok(None));
+        assert_eq!(parse_range("items=0-1", 10), Ok(None));
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let files = StaticFiles::new("/srv/www");
+        assert_eq!(
+            files.resolve("/a/./b%20c/"),
+            Some(PathBuf::from("/srv/www/a/b c"))
+        );
+        assert_eq!(files.resolve("/../etc/passwd"), None);
+        assert_eq!(files.resolve("/a/../../etc/passwd"), None);
+        assert_eq!(files.resolve("/%2e%2e/etc/passwd"), None);
+        assert_eq!(files.resolve("/a/%2E%2E%2F..%2Fx"), None);
+        assert_eq!(files.resolve("/a%00b"), None);
+        assert_eq!(files.resolve("/%ff"), None);
+    }
+}
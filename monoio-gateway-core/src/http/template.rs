@@ -0,0 +1,80 @@
+use std::borrow::Cow;
+
+/// Render `$name` and `${name}` variables of `template` with `lookup`.
+///
+/// A name is made of ascii alphanumerics and `_`, `$$` is a literal `$`.
+/// Unknown variables are rendered as empty strings.
+pub fn render<'a, F>(template: &str, lookup: F) -> String
+where
+    F: Fn(&str) -> Option<Cow<'a, str>>,
+{
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(tail) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = tail;
+            continue;
+        }
+        let (name, tail) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.split_once('}') {
+                Some(split) => split,
+                None => {
+                    // unclosed brace, keep as is
+                    out.push('$');
+                    continue;
+                }
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            out.push('$');
+        } else if let Some(value) = lookup(name) {
+            out.push_str(&value);
+        }
+        rest = tail;
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(name: &str) -> Option<Cow<'static, str>> {
+        match name {
+            "host" => Some(Cow::Borrowed("example.com")),
+            "1" => Some(Cow::Borrowed("42")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn renders_variables() {
+        assert_eq!(
+            render("https://$host/u/$1", vars),
+            "https://example.com/u/42"
+        );
+        assert_eq!(render("${host}x", vars), "example.comx");
+        assert_eq!(render("$hostx", vars), "");
+        assert_eq!(render("/$missing/", vars), "//");
+        assert_eq!(render("no variables", vars), "no variables");
+    }
+
+    #[test]
+    fn keeps_literal_dollars() {
+        assert_eq!(render("$$host", vars), "$host");
+        assert_eq!(render("cost $", vars), "cost $");
+        assert_eq!(render("$/", vars), "$/");
+        assert_eq!(render("${}", vars), "$");
+        assert_eq!(render("${host", vars), "${host");
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Timeouts of a server in milliseconds, `0` disables one.
+///
+/// `client_header`, `keepalive` and `tls_handshake` run before the server of
+/// a request is known, so a listener takes them from its default server, or
+/// its first server without one.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// reading the first request of a connection, and the head of later ones
+    /// from their first byte, replied `408`
+    #[serde(default = "default_client_timeout")]
+    pub client_header: u64,
+    /// reading a request body, replied `408`
+    #[serde(default = "default_client_timeout")]
+    pub client_body: u64,
+    /// waiting for the next request of a kept alive connection to start
+    #[serde(default = "default_keepalive")]
+    pub keepalive: u64,
+    #[serde(default = "default_tls_handshake")]
+    pub tls_handshake: u64,
+    /// connecting an endpoint, replied `504`
+    #[serde(default = "default_upstream_timeout")]
+    pub upstream_connect: u64,
+    /// waiting for the response head once the request is sent, replied `504`
+    #[serde(default = "default_upstream_timeout")]
+    pub upstream_header: u64,
+    /// pooled upstream connections unused for longer are closed
+    #[serde(default = "default_upstream_timeout")]
+    pub upstream_idle: u64,
+}
+
+/// Timeouts of a rule overriding those of its server.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct RouteTimeouts {
+    #[serde(default)]
+    pub client_body: Option<u64>,
+    #[serde(default)]
+    pub upstream_connect: Option<u64>,
+    #[serde(default)]
+    pub upstream_header: Option<u64>,
+    #[serde(default)]
+    pub upstream_idle: Option<u64>,
+}
+
+fn default_client_timeout() -> u64 {
+    60000
+}
+
+fn default_keepalive() -> u64 {
+    75000
+}
+
+fn default_tls_handshake() -> u64 {
+    10000
+}
+
+fn default_upstream_timeout() -> u64 {
+    60000
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            client_header: default_client_timeout(),
+            client_body: default_client_timeout(),
+            keepalive: default_keepalive(),
+            tls_handshake: default_tls_handshake(),
+            upstream_connect: default_upstream_timeout(),
+            upstream_header: default_upstream_timeout(),
+            upstream_idle: default_upstream_timeout(),
+        }
+    }
+}
+
+impl Timeouts {
+    /// timeouts of a rule of this server
+    pub fn with_route(mut self, route: Option<&RouteTimeouts>) -> Self {
+        if let Some(route) = route {
+            self.client_body = route.client_body.unwrap_or(self.client_body);
+            self.upstream_connect = route.upstream_connect.unwrap_or(self.upstream_connect);
+            self.upstream_header = route.upstream_header.unwrap_or(self.upstream_header);
+            self.upstream_idle = route.upstream_idle.unwrap_or(self.upstream_idle);
+        }
+        self
+    }
+}
+
+/// `None` for a disabled timeout of `millis`
+#[inline]
+pub fn duration(millis: u64) -> Option<Duration> {
+    (millis > 0).then_some(Duration::from_millis(millis))
+}
@@ -1,3 +1,5 @@
+use serde_derive::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub enum Version {
     HTTP11,
@@ -9,3 +11,26 @@ pub enum Type {
     HTTP,
     HTTPS,
 }
+
+/// Protocol spoken to the endpoints of an upstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// h2 if an https endpoint selects it by ALPN, HTTP/1.1 otherwise
+    Auto,
+    /// h2 by ALPN on https, prior knowledge h2c on http endpoints
+    Http2,
+}
+
+impl UpstreamProtocol {
+    /// ALPN protocols offered to https endpoints, none for HTTP/1.1 only
+    pub fn alpn(&self) -> &'static [&'static str] {
+        match self {
+            UpstreamProtocol::Http1 => &[],
+            UpstreamProtocol::Auto => &["h2", "http/1.1"],
+            UpstreamProtocol::Http2 => &["h2"],
+        }
+    }
+}
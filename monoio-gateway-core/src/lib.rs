@@ -24,9 +24,14 @@ use figlet_rs::FIGfont;
 use lazy_static::lazy_static;
 use rustls::{OwnedTrustAnchor, RootCertStore};
 
-use crate::{dns::http::Domain, http::ssl::CertificateResolver};
+use crate::{
+    balance::{health::HealthMap, weighted::SplitWeights},
+    dns::http::Domain,
+    error::GError,
+    http::ssl::{CertificateResolver, UpstreamTls},
+};
 
-pub const MAX_CONFIG_SIZE_LIMIT: usize = 8072;
+pub const CONFIG_READ_CHUNK: usize = 64 * 1024;
 pub const MAX_IOURING_ENTRIES: u32 = 32768;
 pub const ACME_URI_PREFIX: &str = "/.well-known";
 
@@ -60,10 +65,16 @@ lazy_static! {
     /// Note:
     /// A thread-shared map <Server Domain, Proxy Pass Domain>
     pub static ref DISCOVERED: Arc<RwLock<HashMap<Domain, Domain>>> = Arc::new(RwLock::new(HashMap::new()));
+    /// Runtime weights of named traffic splits, shared by all workers
+    pub static ref SPLIT_WEIGHTS: Arc<RwLock<HashMap<String, Arc<SplitWeights>>>> = Arc::new(RwLock::new(HashMap::new()));
+    /// Health of upstream endpoints by group name, shared by all workers
+    pub static ref UPSTREAM_HEALTH: Arc<RwLock<HashMap<String, HealthMap>>> = Arc::new(RwLock::new(HashMap::new()));
+    /// Client configs of upstream tls settings, one per distinct settings
+    pub static ref UPSTREAM_TLS_CONFIGS: Arc<RwLock<HashMap<UpstreamTls, Arc<rustls::ClientConfig>>>> = Arc::new(RwLock::new(HashMap::new()));
 }
 
-pub trait Builder<Config> {
-    fn build_with_config(config: Config) -> Self;
+pub trait Builder<Config>: Sized {
+    fn build_with_config(config: Config) -> Result<Self, GError>;
 }
 
 pub fn print_logo() {
@@ -1,4 +1,4 @@
-use std::{fmt::Display, future::Future, iter::Enumerate};
+use std::{fmt::Display, future::Future};
 
 use crate::util::{identity::Identity, stack::Stack};
 
@@ -23,17 +23,6 @@ pub trait Layer<S> {
     fn layer(&self, service: S) -> Self::Service;
 }
 
-#[allow(dead_code)]
-pub struct SvcList<S>
-where
-    S: IntoIterator,
-{
-    inner: Enumerate<S::IntoIter>,
-}
-
-#[allow(dead_code)]
-type ListSvcList<S> = SvcList<Vec<S>>;
-
 pub struct ServiceBuilder<L> {
     layer: L,
 }
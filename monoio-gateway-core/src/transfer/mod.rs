@@ -1,6 +1,8 @@
-use std::{cell::UnsafeCell, rc::Rc};
-
-use http::{header::HOST, StatusCode};
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_LENGTH, HOST, LOCATION},
+    HeaderValue, StatusCode,
+};
 use monoio::{
     io::{
         sink::{Sink, SinkExt},
@@ -13,7 +15,7 @@ use monoio_http::{
     common::{request::Request, response::Response, IntoParts},
     h1::{
         codec::decoder::{DecodeError, FillPayload},
-        payload::Payload,
+        payload::{FixedPayload, Payload},
     },
 };
 
@@ -140,45 +142,36 @@ where
     Ok(())
 }
 
-pub async fn copy_response_lock<Read, Write>(
-    local: Rc<UnsafeCell<Read>>,
-    remote: Rc<UnsafeCell<Write>>,
-    domain: Domain,
-) -> Result<(), std::io::Error>
-where
-    Read: Stream<Item = Result<Response<Payload>, DecodeError>> + FillPayload,
-    Write: Sink<Response<Payload>>,
-{
-    let local = unsafe { &mut *local.get() };
-    let remote = unsafe { &mut *remote.get() };
-    loop {
-        match local.next().await {
-            Some(Ok(response)) => {
-                let mut response: Response = response;
-                Rewrite::rewrite_response(&mut response, &domain);
-                log::info!(
-                    "response code: {},{:?}",
-                    response.status(),
-                    response.headers(),
-                );
-                let _ = monoio::join!(local.fill_payload(), remote.send_and_flush(response));
-            }
-            Some(Err(decode_error)) => {
-                log::warn!("DecodeError: {}", decode_error);
-                break;
-            }
-            None => {
-                log::info!("backward reached EOF, bye");
-                break;
-            }
-        }
-    }
-    let _ = remote.close().await;
-    Ok(())
-}
-
 pub fn generate_response(status_code: StatusCode) -> Response {
     let mut resp = Response::builder();
     resp = resp.status(status_code);
     resp.body(Payload::None).unwrap()
 }
+
+/// response of `status_code` with `body`, an empty body is sent as `Content-Length: 0`
+pub fn generate_body_response(status_code: StatusCode, body: Bytes) -> Response {
+    let mut resp = generate_response(status_code);
+    if body.is_empty() {
+        resp.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+    } else {
+        resp.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        *resp.body_mut() = Payload::Fixed(FixedPayload::new(body));
+    }
+    resp
+}
+
+/// empty response redirecting to `location`
+pub fn generate_redirect(status_code: StatusCode, location: &str) -> Response {
+    let location = match HeaderValue::from_str(location) {
+        Ok(location) => location,
+        Err(_) => {
+            log::warn!("invalid redirect location: {}", location);
+            return generate_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let mut resp = generate_body_response(status_code, Bytes::new());
+    resp.headers_mut().insert(LOCATION, location);
+    resp
+}
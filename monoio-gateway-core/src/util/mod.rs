@@ -1,2 +1,4 @@
 pub mod identity;
+pub mod pattern;
+pub mod percent;
 pub mod stack;
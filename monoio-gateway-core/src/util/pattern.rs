@@ -0,0 +1,47 @@
+use std::{fmt::Debug, ops::Deref};
+
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A regex compiled when config is loaded, (de)serialized as its source string.
+#[derive(Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(re: &str) -> Result<Self, regex::Error> {
+        Regex::new(re).map(Self)
+    }
+}
+
+impl Deref for Pattern {
+    type Target = Regex;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0.as_str())
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let re = String::deserialize(deserializer)?;
+        Pattern::new(&re).map_err(D::Error::custom)
+    }
+}
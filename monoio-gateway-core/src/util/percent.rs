@@ -0,0 +1,44 @@
+use std::borrow::Cow;
+
+/// Decode `%XX` escapes of `s`, and `+` as a space if `plus_as_space`.
+///
+/// Malformed escapes are kept as they are. The result may not be utf-8.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Cow<'_, [u8]> {
+    if !s.contains('%') && !(plus_as_space && s.contains('+')) {
+        return Cow::Borrowed(s.as_bytes());
+    }
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match bytes[i] {
+            b'+' if plus_as_space => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push((hi * 16 + lo) as u8);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(&*percent_decode("/a%20b/%2e%2E", false), b"/a b/..");
+        assert_eq!(&*percent_decode("a+b%2B", false), b"a+b+");
+        assert_eq!(&*percent_decode("a+b%2B", true), b"a b+");
+        assert_eq!(&*percent_decode("%zz%4", false), b"%zz%4");
+        assert_eq!(&*percent_decode("%ff", false), [0xff]);
+        assert!(matches!(percent_decode("/plain", true), Cow::Borrowed(_)));
+    }
+}
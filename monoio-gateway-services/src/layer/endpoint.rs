@@ -1,43 +1,150 @@
-use std::{cell::UnsafeCell, future::Future, rc::Rc};
+use std::{
+    collections::hash_map::DefaultHasher,
+    future::Future,
+    hash::{Hash, Hasher},
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
 
 use anyhow::bail;
 use log::info;
 use monoio::{
     io::{AsyncWriteRent, OwnedReadHalf, OwnedWriteHalf, Splitable},
-    net::TcpStream,
+    net::{TcpStream, UnixStream},
 };
 use monoio_gateway_core::{
     dns::{http::Domain, Resolvable},
     error::GError,
-    http::ssl::get_default_tls_connector,
+    http::{
+        ssl::{get_tls_connector, upstream_tls, UpstreamTls},
+        version::UpstreamProtocol,
+    },
     service::Service,
 };
 use monoio_http::h1::codec::{decoder::ResponseDecoder, encoder::GenericEncoder};
 
 use rustls::ServerName;
 
+use super::h2::H2Connection;
+
 pub struct EndpointRequestParams<EndPoint> {
     pub(crate) endpoint: EndPoint,
+    pub(crate) tls: Option<UpstreamTls>,
+    pub(crate) protocol: UpstreamProtocol,
 }
 
 impl<Endpoint> EndpointRequestParams<Endpoint> {
     pub fn new(endpoint: Endpoint) -> Self {
-        Self { endpoint }
+        Self {
+            endpoint,
+            tls: None,
+            protocol: UpstreamProtocol::Http1,
+        }
+    }
+
+    /// tls settings of an https endpoint, the default ones if `None`
+    pub fn with_tls(mut self, tls: Option<UpstreamTls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: UpstreamProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+}
+
+impl EndpointRequestParams<Domain> {
+    /// Connections of the same key are interchangeable.
+    pub fn pool_key(&self) -> String {
+        let mut key = match self.endpoint.unix_path() {
+            Some(socket) => format!("unix:{}", socket.display()),
+            None => format!("{}:{}", self.endpoint.host(), self.endpoint.port()),
+        };
+        if self.protocol != UpstreamProtocol::Http1 {
+            key.push_str(&format!("/{:?}", self.protocol));
+        }
+        if let Some(tls) = &self.tls {
+            let mut hasher = DefaultHasher::new();
+            tls.hash(&mut hasher);
+            key.push_str(&format!("#{:x}", hasher.finish()));
+        }
+        key
     }
 }
 
 #[derive(Default, Clone)]
 pub struct ConnectEndpoint;
 
+/// An HTTP/1 connection to an endpoint, the response of a request is read
+/// before the next request is sent.
+pub struct Http1Connection<R, W> {
+    pub(crate) decoder: ResponseDecoder<R>,
+    pub(crate) sender: GenericEncoder<W>,
+    /// socket of the connection, checked before it is reused
+    socket: Option<RawFd>,
+}
+
+impl<R, W> Http1Connection<R, W> {
+    pub fn new(read: R, write: W) -> Self {
+        Self {
+            decoder: ResponseDecoder::new(read),
+            sender: GenericEncoder::new(write),
+            socket: None,
+        }
+    }
+
+    /// `socket` is the one `read` and `write` are halves of
+    pub fn with_socket(mut self, socket: RawFd) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Whether an idle connection can send a request. It can not once the
+    /// endpoint closed it or sent bytes no request asked for.
+    pub fn is_alive(&self) -> bool {
+        let socket = match self.socket {
+            Some(socket) => socket,
+            None => return true,
+        };
+        let mut byte = 0_u8;
+        // nothing to read on an open idle connection
+        let read = unsafe {
+            libc::recv(
+                socket,
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock
+    }
+}
+
 pub enum ClientConnectionType<I, O: AsyncWriteRent> {
-    Http(
-        Rc<UnsafeCell<ResponseDecoder<OwnedReadHalf<I>>>>,
-        Rc<UnsafeCell<GenericEncoder<OwnedWriteHalf<O>>>>,
-    ),
+    Http(Http1Connection<OwnedReadHalf<I>, OwnedWriteHalf<O>>),
     Tls(
-        Rc<UnsafeCell<ResponseDecoder<monoio_rustls::ClientTlsStreamReadHalf<I>>>>,
-        Rc<UnsafeCell<GenericEncoder<monoio_rustls::ClientTlsStreamWriteHalf<O>>>>,
+        Http1Connection<
+            monoio_rustls::ClientTlsStreamReadHalf<I>,
+            monoio_rustls::ClientTlsStreamWriteHalf<O>,
+        >,
     ),
+    /// an endpoint listening on a unix socket
+    Unix(Http1Connection<OwnedReadHalf<UnixStream>, OwnedWriteHalf<UnixStream>>),
+    /// requests of any number of clients multiplexed on one connection
+    H2(H2Connection),
+}
+
+impl<I, O: AsyncWriteRent> ClientConnectionType<I, O> {
+    /// whether the connection can send a request, see [`Http1Connection::is_alive`]
+    pub fn is_alive(&self) -> bool {
+        match self {
+            ClientConnectionType::Http(conn) => conn.is_alive(),
+            ClientConnectionType::Tls(conn) => conn.is_alive(),
+            ClientConnectionType::Unix(conn) => conn.is_alive(),
+            ClientConnectionType::H2(h2) => !h2.is_closed(),
+        }
+    }
 }
 
 impl Service<EndpointRequestParams<Domain>> for ConnectEndpoint {
@@ -52,32 +159,67 @@ impl Service<EndpointRequestParams<Domain>> for ConnectEndpoint {
     fn call(&mut self, req: EndpointRequestParams<Domain>) -> Self::Future<'_> {
         async move {
             info!("trying to connect to endpoint");
+            if let Some(socket) = req.endpoint.unix_path() {
+                let stream = match UnixStream::connect(socket).await {
+                    Ok(stream) => stream,
+                    Err(err) => bail!("error connect endpoint: {}", err),
+                };
+                if req.protocol == UpstreamProtocol::Http2 {
+                    // prior knowledge h2c
+                    let conn = H2Connection::handshake(stream, &req.endpoint).await?;
+                    return Ok(Some(ClientConnectionType::H2(conn)));
+                }
+                let socket = stream.as_raw_fd();
+                let (r, w) = stream.into_split();
+                let conn = Http1Connection::new(r, w).with_socket(socket);
+                return Ok(Some(ClientConnectionType::Unix(conn)));
+            }
             let resolved = req.endpoint.resolve().await?;
             match resolved {
                 Some(addr) => {
                     info!("resolved addr: {}", addr);
-                    match TcpStream::connect(addr).await {
+                    match addr.connect().await {
                         Ok(stream) => match req.endpoint.version() {
+                            monoio_gateway_core::http::version::Type::HTTP
+                                if req.protocol == UpstreamProtocol::Http2 =>
+                            {
+                                // prior knowledge h2c
+                                let conn = H2Connection::handshake(stream, &req.endpoint).await?;
+                                return Ok(Some(ClientConnectionType::H2(conn)));
+                            }
                             monoio_gateway_core::http::version::Type::HTTP => {
                                 // no need to handshake
+                                let socket = stream.as_raw_fd();
                                 let (r, w) = stream.into_split();
-                                return Ok(Some(ClientConnectionType::Http(
-                                    Rc::new(UnsafeCell::new(ResponseDecoder::new(r))),
-                                    Rc::new(UnsafeCell::new(GenericEncoder::new(w))),
-                                )));
+                                let conn = Http1Connection::new(r, w).with_socket(socket);
+                                return Ok(Some(ClientConnectionType::Http(conn)));
                             }
                             monoio_gateway_core::http::version::Type::HTTPS => {
                                 info!("establishing https connection to endpoint");
-                                let tls_connector = get_default_tls_connector();
-                                let server_name =
-                                    ServerName::try_from(req.endpoint.host().as_ref())?;
+                                let socket = stream.as_raw_fd();
+                                let tls = upstream_tls(req.tls.as_ref(), req.protocol);
+                                let tls_connector = get_tls_connector(tls.as_ref())?;
+                                let server_name = match &tls {
+                                    Some(tls) => tls.server_name(req.endpoint.host())?,
+                                    None => ServerName::try_from(req.endpoint.host().as_ref())?,
+                                };
                                 match tls_connector.connect(server_name, stream).await {
                                     Ok(endpoint_stream) => {
+                                        let alpn = endpoint_stream.get_ref().1.alpn_protocol();
+                                        if alpn == Some(b"h2") {
+                                            let conn = H2Connection::handshake(
+                                                endpoint_stream,
+                                                &req.endpoint,
+                                            )
+                                            .await?;
+                                            return Ok(Some(ClientConnectionType::H2(conn)));
+                                        }
+                                        if req.protocol == UpstreamProtocol::Http2 {
+                                            bail!("{} did not select h2 by alpn", req.endpoint);
+                                        }
                                         let (r, w) = endpoint_stream.split();
-                                        return Ok(Some(ClientConnectionType::Tls(
-                                            Rc::new(UnsafeCell::new(ResponseDecoder::new(r))),
-                                            Rc::new(UnsafeCell::new(GenericEncoder::new(w))),
-                                        )));
+                                        let conn = Http1Connection::new(r, w).with_socket(socket);
+                                        return Ok(Some(ClientConnectionType::Tls(conn)));
                                     }
                                     Err(tls_error) => bail!("{}", tls_error),
                                 }
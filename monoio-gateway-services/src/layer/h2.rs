@@ -0,0 +1,522 @@
+//! HTTP/2 of clients and upstreams, bridged to the HTTP/1 types the router
+//! works with.
+
+use std::{
+    cell::Cell,
+    future::{poll_fn, Future},
+    io::{self, Cursor},
+    rc::Rc,
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use h2::{
+    client::{ResponseFuture, SendRequest},
+    server::{Connection, SendResponse},
+    Reason, RecvStream, SendStream,
+};
+use http::{
+    header::{CONNECTION, CONTENT_LENGTH, COOKIE, HOST, TE, TRANSFER_ENCODING},
+    HeaderMap, HeaderName, HeaderValue, StatusCode, Uri, Version,
+};
+use log::debug;
+use monoio::io::{stream::Stream, AsyncReadRent, AsyncWriteRent, PrefixedReadIo};
+use monoio_compat::StreamWrapper;
+use monoio_gateway_core::{
+    dns::http::Domain,
+    error::GError,
+    http::{http2::Http2Settings, version::Type},
+};
+use monoio_http::{
+    common::{request::Request, response::Response},
+    h1::payload::{stream_payload_pair, Payload, StreamPayloadSender},
+};
+
+use super::router::{RequestBody, ResponseSink};
+
+/// connection preface of an h2 client
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// flow control window of a stream, the default 64k throttles bulk transfers
+pub const STREAM_WINDOW: u32 = 1 << 20;
+/// flow control window of a connection, shared by its streams
+pub const CONNECTION_WINDOW: u32 = 4 << 20;
+
+/// headers of a single HTTP/1 connection, never sent on h2
+const CONNECTION_HEADERS: [&str; 5] = [
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "http2-settings",
+];
+
+/// An h2 connection to an endpoint, shared by the requests of all clients of
+/// a worker. Each request is a stream of its own.
+#[derive(Clone)]
+pub struct H2Connection {
+    sender: SendRequest<Bytes>,
+    /// set once the connection task is done
+    closed: Rc<Cell<bool>>,
+}
+
+impl H2Connection {
+    /// Handshake h2 over `io` and drive the connection in the background.
+    pub async fn handshake<T>(io: T, endpoint: &Domain) -> Result<Self, GError>
+    where
+        T: AsyncReadRent + AsyncWriteRent + Unpin + 'static,
+    {
+        let (sender, connection) = h2::client::Builder::new()
+            .initial_window_size(STREAM_WINDOW)
+            .initial_connection_window_size(CONNECTION_WINDOW)
+            .handshake::<_, Bytes>(StreamWrapper::new(io))
+            .await?;
+        let closed = Rc::new(Cell::new(false));
+        let endpoint = endpoint.to_string();
+        let done = closed.clone();
+        monoio::spawn(async move {
+            match connection.await {
+                Ok(()) => debug!("h2 connection to {} closed", endpoint),
+                Err(err) => debug!("h2 connection to {} failed: {}", endpoint, err),
+            }
+            done.set(true);
+        });
+        Ok(Self { sender, closed })
+    }
+
+    /// whether no more streams can be opened on the connection
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+
+    /// Open a stream for `request`, waiting while the endpoint allows no more
+    /// concurrent streams.
+    ///
+    /// The body of `request` is returned with the stream to send it on,
+    /// unless it has none.
+    pub async fn send(
+        &self,
+        request: Request<Payload>,
+        proxy_pass: &Domain,
+    ) -> Result<(ResponseFuture, Option<(Payload, SendStream<Bytes>)>), h2::Error> {
+        let mut sender = self.sender.clone().ready().await?;
+        let (request, body) = h2_request(request, proxy_pass);
+        let end_of_stream = matches!(body, Payload::None);
+        let (response, stream) = sender.send_request(request, end_of_stream)?;
+        Ok((response, (!end_of_stream).then_some((body, stream))))
+    }
+}
+
+/// The h2 request of `request` to `proxy_pass`, and its body.
+fn h2_request(request: Request<Payload>, proxy_pass: &Domain) -> (http::Request<()>, Payload) {
+    let (mut parts, body) = request.into_parts();
+    let scheme = match proxy_pass.version() {
+        Type::HTTP => "http",
+        Type::HTTPS => "https",
+    };
+    // the authority of the rewritten Host header
+    let authority = parts
+        .headers
+        .remove(HOST)
+        .and_then(|host| host.to_str().ok().map(str::to_owned))
+        .or_else(|| {
+            proxy_pass
+                .authority()
+                .map(|authority| authority.to_string())
+        });
+    let mut uri = Uri::builder().scheme(scheme);
+    if let Some(authority) = authority {
+        uri = uri.authority(authority);
+    }
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    if let Ok(h2_uri) = uri.path_and_query(path).build() {
+        parts.uri = h2_uri;
+    }
+    parts.version = Version::HTTP_2;
+    strip_connection_headers(&mut parts.headers);
+    (http::Request::from_parts(parts, ()), body)
+}
+
+/// Remove the hop by hop headers of an HTTP/1 message forwarded on h2.
+pub fn strip_connection_headers(headers: &mut HeaderMap) {
+    // headers named by Connection are hop by hop too
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    headers.remove(CONNECTION);
+    for name in CONNECTION_HEADERS {
+        headers.remove(name);
+    }
+    if matches!(headers.get(TE), Some(te) if te != "trailers") {
+        headers.remove(TE);
+    }
+}
+
+/// Send `body` on `stream` as flow control allows.
+pub async fn send_body(body: Payload, mut stream: SendStream<Bytes>) -> Result<(), GError> {
+    match body {
+        Payload::None => stream.send_data(Bytes::new(), true)?,
+        Payload::Fixed(payload) => send_data(&mut stream, payload.get().await?, true).await?,
+        Payload::Stream(mut payload) => {
+            while let Some(data) = payload.next().await {
+                send_data(&mut stream, data?, false).await?;
+            }
+            stream.send_data(Bytes::new(), true)?;
+        }
+    }
+    Ok(())
+}
+
+async fn send_data(
+    stream: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end_of_stream: bool,
+) -> Result<(), h2::Error> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            // the peer reset the stream
+            None => return Err(Reason::CANCEL.into()),
+        };
+        let chunk = data.split_to(capacity.min(data.len()));
+        stream.send_data(chunk, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
+
+/// The HTTP/1.1 response of an h2 response, and the task feeding its body.
+///
+/// A body not known to be empty is sent chunked.
+pub fn h1_response(
+    response: http::Response<RecvStream>,
+) -> (Response<Payload>, Option<impl Future<Output = ()>>) {
+    let (mut parts, body) = response.into_parts();
+    parts.version = Version::HTTP_11;
+    if body.is_end_stream() {
+        if !parts.headers.contains_key(CONTENT_LENGTH)
+            && parts.status != StatusCode::NO_CONTENT
+            && parts.status != StatusCode::NOT_MODIFIED
+        {
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+        }
+        return (Response::from_parts(parts, Payload::None), None);
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    let (payload, sender) = stream_payload_pair();
+    (
+        Response::from_parts(parts, Payload::Stream(payload)),
+        Some(recv_body(body, sender)),
+    )
+}
+
+/// Feed the data of `body` to `sender` until the stream ends.
+pub async fn recv_body(mut body: RecvStream, mut sender: StreamPayloadSender) {
+    while let Some(data) = body.data().await {
+        match data {
+            Ok(data) => {
+                let _ = body.flow_control().release_capacity(data.len());
+                sender.feed_data(Some(data));
+            }
+            Err(err) => {
+                debug!("h2 body failed: {}", err);
+                sender.feed_error(io::Error::new(io::ErrorKind::ConnectionAborted, err).into());
+                return;
+            }
+        }
+    }
+    sender.feed_data(None);
+}
+
+/// Read the connection preface of a prior knowledge h2c client if `stream`
+/// starts with it.
+///
+/// What was read is replayed by the returned stream, HTTP/1 requests are
+/// told apart by their first bytes.
+pub async fn read_preface<S>(
+    mut stream: S,
+) -> Result<(bool, PrefixedReadIo<S, Cursor<Vec<u8>>>), GError>
+where
+    S: AsyncReadRent,
+{
+    let mut read = Vec::with_capacity(PREFACE.len());
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        let (res, buf) = stream
+            .read(Vec::with_capacity(PREFACE.len() - read.len()))
+            .await;
+        if res? == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf);
+    }
+    let h2c = read == PREFACE;
+    Ok((h2c, PrefixedReadIo::new(stream, Cursor::new(read))))
+}
+
+/// Handshake h2 with a client whose preface `io` starts with.
+pub async fn server_handshake<T>(
+    io: T,
+    settings: &Http2Settings,
+) -> Result<Connection<StreamWrapper<T>, Bytes>, GError>
+where
+    T: AsyncReadRent + AsyncWriteRent + Unpin + 'static,
+{
+    let conn = h2::server::Builder::new()
+        .max_concurrent_streams(settings.max_concurrent_streams)
+        .initial_window_size(settings.initial_stream_window)
+        .initial_connection_window_size(settings.initial_connection_window)
+        .max_frame_size(settings.max_frame_size)
+        .max_header_list_size(settings.max_header_list_size)
+        .handshake(StreamWrapper::new(io))
+        .await?;
+    Ok(conn)
+}
+
+/// The HTTP/1.1 request of an h2 request stream, and its body.
+///
+/// The authority becomes the Host header, a body not known to be empty is
+/// sent chunked.
+pub fn h1_request(request: http::Request<RecvStream>) -> (Request<Payload>, H2RequestBody) {
+    let (mut parts, body) = request.into_parts();
+    parts.version = Version::HTTP_11;
+    if let Some(host) = parts
+        .uri
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    {
+        parts.headers.insert(HOST, host);
+    }
+    if let Some(path) = parts.uri.path_and_query().cloned() {
+        parts.uri = Uri::from(path);
+    }
+    // h2 may split cookies into several fields, HTTP/1.1 allows one
+    if parts.headers.get_all(COOKIE).iter().count() > 1 {
+        let cookies: Vec<&[u8]> = parts
+            .headers
+            .get_all(COOKIE)
+            .iter()
+            .map(|cookie| cookie.as_bytes())
+            .collect();
+        if let Ok(cookie) = HeaderValue::from_bytes(&cookies.join(&b"; "[..])) {
+            parts.headers.insert(COOKIE, cookie);
+        }
+    }
+    if body.is_end_stream() {
+        return (
+            Request::from_parts(parts, Payload::None),
+            H2RequestBody(None),
+        );
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    let (payload, sender) = stream_payload_pair();
+    (
+        Request::from_parts(parts, Payload::Stream(payload)),
+        H2RequestBody(Some((body, sender))),
+    )
+}
+
+/// Body of an h2 request, fed to its payload while the request is forwarded.
+pub struct H2RequestBody(Option<(RecvStream, StreamPayloadSender)>);
+
+impl RequestBody for H2RequestBody {
+    type Future<'a> = impl Future<Output = ()> + 'a
+    where
+        Self: 'a;
+
+    fn fill(&mut self) -> Self::Future<'_> {
+        async move {
+            if let Some((body, sender)) = self.0.take() {
+                recv_body(body, sender).await;
+            }
+        }
+    }
+}
+
+/// Writes the response of an h2 request stream.
+pub struct H2Responder(Option<SendResponse<Bytes>>);
+
+impl H2Responder {
+    pub fn new(respond: SendResponse<Bytes>) -> Self {
+        Self(Some(respond))
+    }
+}
+
+impl ResponseSink for H2Responder {
+    type Future<'a> = impl Future<Output = Result<(), GError>> + 'a
+    where
+        Self: 'a;
+
+    fn send_response(&mut self, response: Response<Payload>) -> Self::Future<'_> {
+        async move {
+            let mut respond = self
+                .0
+                .take()
+                .ok_or_else(|| anyhow!("response of the stream is sent already"))?;
+            let (mut parts, body) = response.into_parts();
+            parts.version = Version::HTTP_2;
+            strip_connection_headers(&mut parts.headers);
+            let end_of_stream = matches!(body, Payload::None);
+            let stream =
+                respond.send_response(http::Response::from_parts(parts, ()), end_of_stream)?;
+            if !end_of_stream {
+                send_body(body, stream).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use monoio::{
+        io::AsyncWriteRentExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn request(uri: &str) -> Request<Payload> {
+        let mut req = Request::new(Payload::None);
+        *req.uri_mut() = uri.parse().unwrap();
+        req
+    }
+
+    #[test]
+    fn h2_request_of_h1() {
+        let proxy_pass = Domain::new("https", "backend:8443", "/");
+        let mut req = request("/v1/users?page=2");
+        let headers = req.headers_mut();
+        headers.insert(HOST, HeaderValue::from_static("api.example.com"));
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, x-hop"));
+        headers.insert("x-hop", HeaderValue::from_static("1"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert(TE, HeaderValue::from_static("gzip"));
+        headers.insert("x-end", HeaderValue::from_static("1"));
+        let (h2, body) = h2_request(req, &proxy_pass);
+        assert!(matches!(body, Payload::None));
+        assert_eq!(h2.version(), Version::HTTP_2);
+        assert_eq!(
+            h2.uri().to_string(),
+            "https://api.example.com/v1/users?page=2"
+        );
+        let names: Vec<&str> = h2.headers().keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, vec!["x-end"]);
+
+        // without a Host header the authority is that of the endpoint
+        let proxy_pass = Domain::new("http", "backend:8000", "/");
+        let mut req = request("/");
+        req.headers_mut()
+            .insert(TE, HeaderValue::from_static("trailers"));
+        let (h2, _) = h2_request(req, &proxy_pass);
+        assert_eq!(h2.uri().to_string(), "http://backend:8000/");
+        assert_eq!(h2.headers()[TE], "trailers");
+    }
+
+    /// a connected client stream and the server side of it
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = monoio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    /// send `data` and close the client stream
+    async fn send(client: &mut TcpStream, data: &[u8]) {
+        client.write_all(data.to_vec()).await.0.unwrap();
+        client.shutdown().await.unwrap();
+    }
+
+    async fn read_to_end<S: AsyncReadRent>(stream: &mut S) -> Vec<u8> {
+        let mut read = vec![];
+        loop {
+            let (res, buf) = stream.read(Vec::with_capacity(1024)).await;
+            if res.unwrap() == 0 {
+                return read;
+            }
+            read.extend_from_slice(&buf);
+        }
+    }
+
+    #[monoio::test]
+    async fn tells_h2c_from_http1() {
+        let (mut client, server) = pair().await;
+        send(&mut client, &[PREFACE, b"\x00\x00\x00"].concat()).await;
+        let (h2c, mut io) = read_preface(server).await.unwrap();
+        assert!(h2c);
+        // the preface is replayed, what follows is not read ahead
+        let read = read_to_end(&mut io).await;
+        assert_eq!(&read[..PREFACE.len()], PREFACE);
+        assert_eq!(&read[PREFACE.len()..], b"\x00\x00\x00");
+
+        let (mut client, server) = pair().await;
+        send(&mut client, b"PRIVATE / HTTP/1.1\r\n\r\n").await;
+        let (h2c, mut io) = read_preface(server).await.unwrap();
+        assert!(!h2c);
+        assert_eq!(read_to_end(&mut io).await, b"PRIVATE / HTTP/1.1\r\n\r\n");
+
+        // a client closing within the preface
+        let (mut client, server) = pair().await;
+        send(&mut client, &PREFACE[..8]).await;
+        let (h2c, mut io) = read_preface(server).await.unwrap();
+        assert!(!h2c);
+        assert_eq!(read_to_end(&mut io).await, &PREFACE[..8]);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn h2_connection_streams() {
+        let (client, server) = pair().await;
+        monoio::spawn(async move {
+            let mut conn = server_handshake(server, &Http2Settings::default())
+                .await
+                .unwrap();
+            let (request, mut respond) = conn.accept().await.unwrap().unwrap();
+            let response = http::Response::builder()
+                .header("x-path", request.uri().path())
+                .header("x-authority", request.uri().authority().unwrap().as_str())
+                .body(())
+                .unwrap();
+            let mut stream = respond.send_response(response, false).unwrap();
+            stream.send_data(Bytes::from_static(b"pong"), true).unwrap();
+            // no more streams, the client sees the connection close
+            conn.graceful_shutdown();
+            let _ = poll_fn(|cx| conn.poll_closed(cx)).await;
+        });
+
+        let endpoint = Domain::new("http", "backend:8000", "/");
+        let h2 = H2Connection::handshake(client, &endpoint).await.unwrap();
+        assert!(!h2.is_closed());
+        let (response, body) = h2.send(request("/ping"), &endpoint).await.unwrap();
+        assert!(body.is_none());
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-path"], "/ping");
+        assert_eq!(response.headers()["x-authority"], "backend:8000");
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "pong");
+        assert!(body.data().await.is_none());
+
+        for _ in 0..100 {
+            if h2.is_closed() {
+                break;
+            }
+            monoio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(h2.is_closed());
+    }
+}
@@ -5,7 +5,10 @@ pub mod delay;
 pub mod detect;
 pub mod discover;
 pub mod endpoint;
+pub mod h2;
 pub mod listen;
+pub mod pool;
+pub mod proxy_protocol;
 pub mod router;
 pub mod timeout;
 pub mod tls;
@@ -0,0 +1,358 @@
+//! Upstream connections kept alive by a worker for all of its clients.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ops::Deref,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use monoio::{io::AsyncWriteRent, net::TcpStream};
+use monoio_gateway_core::{balance::outlier::ConnectionSlot, http::keepalive::UpstreamKeepalive};
+
+use super::endpoint::ClientConnectionType;
+
+/// idle connections of all endpoints are checked this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SharedTcpConnectPool<I, O> = Rc<RefCell<ConnectPool<I, O>>>;
+
+thread_local! {
+    static WORKER_POOL: SharedTcpConnectPool<TcpStream, TcpStream> = Default::default();
+}
+
+/// The upstream connection pool of the current worker, shared by the
+/// clients of all of its listeners.
+pub fn worker_pool() -> SharedTcpConnectPool<TcpStream, TcpStream> {
+    WORKER_POOL.with(|pool| pool.clone())
+}
+
+/// Close the expired connections of `pool` every [`SWEEP_INTERVAL`], to be
+/// spawned once by each worker.
+pub async fn sweep_idle<I, O: AsyncWriteRent>(pool: SharedTcpConnectPool<I, O>) {
+    loop {
+        monoio::time::sleep(SWEEP_INTERVAL).await;
+        pool.borrow_mut().sweep(Instant::now());
+    }
+}
+
+/// An upstream connection, holding a connection slot of its upstream group.
+pub struct PooledConnection<I, O: AsyncWriteRent> {
+    pub(crate) conn: ClientConnectionType<I, O>,
+    _slot: Option<ConnectionSlot>,
+    /// requests sent on the connection
+    requests: Cell<u64>,
+    last_used: Cell<Instant>,
+    /// `upstream_idle` of the last request
+    idle_timeout: Cell<Option<Duration>>,
+}
+
+impl<I, O: AsyncWriteRent> PooledConnection<I, O> {
+    pub fn new(conn: ClientConnectionType<I, O>, slot: Option<ConnectionSlot>) -> Self {
+        Self {
+            conn,
+            _slot: slot,
+            requests: Cell::new(0),
+            last_used: Cell::new(Instant::now()),
+            idle_timeout: Cell::new(None),
+        }
+    }
+
+    /// whether requests are multiplexed on the connection, h2 ones are
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        matches!(self.conn, ClientConnectionType::H2(_))
+    }
+
+    /// whether an h2 connection can open no more streams
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        matches!(&self.conn, ClientConnectionType::H2(h2) if h2.is_closed())
+    }
+
+    /// Count a request sent on the connection, which may idle for
+    /// `idle_timeout` once it is done.
+    pub fn start_request(&self, idle_timeout: Option<Duration>) {
+        self.requests.set(self.requests.get() + 1);
+        self.idle_timeout.set(idle_timeout);
+    }
+
+    #[inline]
+    pub fn requests(&self) -> u64 {
+        self.requests.get()
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        let idle = now.saturating_duration_since(self.last_used.get());
+        self.is_closed() || matches!(self.idle_timeout.get(), Some(timeout) if idle >= timeout)
+    }
+}
+
+/// A connection checked out of the pool by a request.
+pub enum Checkout<I, O: AsyncWriteRent> {
+    /// an HTTP/1 connection, owned by the request until it is released
+    Owned(PooledConnection<I, O>),
+    /// an h2 connection, left in the pool for the requests to come
+    Shared(Rc<PooledConnection<I, O>>),
+}
+
+impl<I, O: AsyncWriteRent> Deref for Checkout<I, O> {
+    type Target = PooledConnection<I, O>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Checkout::Owned(conn) => conn,
+            Checkout::Shared(conn) => conn,
+        }
+    }
+}
+
+/// Connections of a worker by [`EndpointRequestParams::pool_key`].
+///
+/// An HTTP/1 connection serves one request at a time, it is owned by the
+/// request from [`ConnectPool::acquire`] until [`ConnectPool::release`]. An
+/// h2 connection stays in the pool and serves any number of requests at once.
+///
+/// [`EndpointRequestParams::pool_key`]: super::endpoint::EndpointRequestParams::pool_key
+pub struct ConnectPool<I, O: AsyncWriteRent> {
+    /// idle HTTP/1 connections, the most recently used last
+    idle: HashMap<String, Vec<PooledConnection<I, O>>>,
+    shared: HashMap<String, Rc<PooledConnection<I, O>>>,
+}
+
+impl<I, O: AsyncWriteRent> Default for ConnectPool<I, O> {
+    fn default() -> Self {
+        Self {
+            idle: HashMap::new(),
+            shared: HashMap::new(),
+        }
+    }
+}
+
+impl<I, O: AsyncWriteRent> ConnectPool<I, O> {
+    /// A connection of `key` to send a request on, expired ones and those
+    /// closed by the endpoint are closed.
+    pub fn acquire(&mut self, key: &str) -> Option<Checkout<I, O>> {
+        let now = Instant::now();
+        match self.shared.get(key) {
+            Some(conn) if !evictable(conn, now) => return Some(Checkout::Shared(conn.clone())),
+            Some(_) => {
+                self.shared.remove(key);
+            }
+            None => {}
+        }
+        let idle = self.idle.get_mut(key)?;
+        while let Some(conn) = idle.pop() {
+            if !conn.expired(now) && conn.conn.is_alive() {
+                return Some(Checkout::Owned(conn));
+            }
+        }
+        None
+    }
+
+    /// Check out a new connection of `key`, an h2 one is shared with the
+    /// requests to come.
+    pub fn checkout(&mut self, key: &str, conn: PooledConnection<I, O>) -> Checkout<I, O> {
+        if !conn.is_shared() {
+            return Checkout::Owned(conn);
+        }
+        let conn = Rc::new(conn);
+        self.shared.insert(key.to_string(), conn.clone());
+        Checkout::Shared(conn)
+    }
+
+    /// Return a connection done with its request, to be reused if
+    /// `reusable` and `keepalive` allows.
+    ///
+    /// Connections not kept are closed once their last user drops them.
+    pub fn release(
+        &mut self,
+        key: &str,
+        conn: Checkout<I, O>,
+        keepalive: &UpstreamKeepalive,
+        reusable: bool,
+    ) {
+        conn.last_used.set(Instant::now());
+        let reusable = reusable && !conn.is_closed() && keepalive.reusable(conn.requests());
+        let conn = match conn {
+            Checkout::Owned(conn) => conn,
+            Checkout::Shared(conn) => {
+                if !reusable
+                    && matches!(self.shared.get(key), Some(shared) if Rc::ptr_eq(shared, &conn))
+                {
+                    self.shared.remove(key);
+                }
+                return;
+            }
+        };
+        if !reusable || keepalive.max_idle == 0 {
+            return;
+        }
+        let idle = self.idle.entry(key.to_string()).or_default();
+        if idle.len() >= keepalive.max_idle {
+            // the longest idle one makes room
+            idle.remove(0);
+        }
+        idle.push(conn);
+    }
+
+    /// Close the expired connections of all endpoints.
+    fn sweep(&mut self, now: Instant) {
+        self.idle.retain(|_, idle| {
+            idle.retain(|conn| !conn.expired(now));
+            !idle.is_empty()
+        });
+        self.shared.retain(|_, conn| !evictable(conn, now));
+    }
+}
+
+/// an h2 connection is not closed for idling while requests use it
+#[inline]
+fn evictable<I, O: AsyncWriteRent>(conn: &Rc<PooledConnection<I, O>>, now: Instant) -> bool {
+    conn.is_closed() || (Rc::strong_count(conn) == 1 && conn.expired(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::prelude::AsRawFd;
+
+    use http::{header::CONNECTION, HeaderMap, HeaderValue, Version};
+    use monoio::{
+        io::Splitable,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::layer::{endpoint::Http1Connection, router::closes_connection};
+
+    const KEY: &str = "10.0.0.1:80";
+
+    /// a pooled connection and the endpoint side of it
+    async fn connection() -> (PooledConnection<TcpStream, TcpStream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stream, accepted) = monoio::join!(TcpStream::connect(addr), listener.accept());
+        let stream = stream.unwrap();
+        let socket = stream.as_raw_fd();
+        let (r, w) = stream.into_split();
+        let conn = Http1Connection::new(r, w).with_socket(socket);
+        let conn = PooledConnection::new(ClientConnectionType::Http(conn), None);
+        (conn, accepted.unwrap().0)
+    }
+
+    fn idle(pool: &ConnectPool<TcpStream, TcpStream>) -> usize {
+        pool.idle.get(KEY).map_or(0, Vec::len)
+    }
+
+    #[monoio::test]
+    async fn keeps_max_idle() {
+        let keepalive = UpstreamKeepalive {
+            max_idle: 2,
+            ..Default::default()
+        };
+        let mut pool = ConnectPool::default();
+        let mut peers = vec![];
+        for requests in 1..=3 {
+            let (conn, peer) = connection().await;
+            for _ in 0..requests {
+                conn.start_request(None);
+            }
+            pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+            peers.push(peer);
+        }
+        assert_eq!(idle(&pool), 2);
+        // the most recently used first, the longest idle one was closed
+        assert_eq!(pool.acquire(KEY).unwrap().requests(), 3);
+        assert_eq!(pool.acquire(KEY).unwrap().requests(), 2);
+        assert!(pool.acquire(KEY).is_none());
+
+        let keepalive = UpstreamKeepalive {
+            max_idle: 0,
+            ..Default::default()
+        };
+        let (conn, _peer) = connection().await;
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+        assert_eq!(idle(&pool), 0);
+    }
+
+    #[monoio::test]
+    async fn closes_after_max_requests() {
+        let keepalive = UpstreamKeepalive {
+            max_requests: 2,
+            ..Default::default()
+        };
+        let mut pool = ConnectPool::default();
+        let (conn, _peer) = connection().await;
+        conn.start_request(None);
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+        let conn = pool.acquire(KEY).unwrap();
+        conn.start_request(None);
+        pool.release(KEY, conn, &keepalive, true);
+        assert_eq!(idle(&pool), 0);
+    }
+
+    #[monoio::test]
+    async fn idle_connections_expire() {
+        let keepalive = UpstreamKeepalive::default();
+        let mut pool = ConnectPool::default();
+        let (conn, _peer) = connection().await;
+        conn.start_request(Some(Duration::from_secs(5)));
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+        let (conn, _other) = connection().await;
+        conn.start_request(None);
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+
+        pool.sweep(Instant::now());
+        assert_eq!(idle(&pool), 2);
+        // without `upstream_idle` a connection idles until it is closed
+        pool.sweep(Instant::now() + Duration::from_secs(5));
+        assert_eq!(idle(&pool), 1);
+        assert_eq!(pool.idle[KEY][0].idle_timeout.get(), None);
+
+        let (conn, _peer) = connection().await;
+        conn.start_request(Some(Duration::ZERO));
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+        let conn = pool.acquire(KEY).unwrap();
+        assert_eq!(conn.idle_timeout.get(), None);
+        assert!(pool.acquire(KEY).is_none());
+    }
+
+    #[monoio::test]
+    async fn skips_closed_connections() {
+        let keepalive = UpstreamKeepalive::default();
+        let mut pool = ConnectPool::default();
+        let (conn, mut peer) = connection().await;
+        conn.start_request(None);
+        assert!(conn.conn.is_alive());
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, true);
+        peer.shutdown().await.unwrap();
+        assert!(pool.acquire(KEY).is_none());
+    }
+
+    #[monoio::test]
+    async fn upstream_close_is_not_pooled() {
+        let keepalive = UpstreamKeepalive::default();
+        let mut pool = ConnectPool::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, close"));
+        let (conn, _peer) = connection().await;
+        conn.start_request(None);
+        let reusable = !closes_connection(&headers, Version::HTTP_11);
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, reusable);
+        assert_eq!(idle(&pool), 0);
+
+        // HTTP/1.0 closes unless asked to keep alive
+        let (conn, _peer) = connection().await;
+        conn.start_request(None);
+        let reusable = !closes_connection(&HeaderMap::new(), Version::HTTP_10);
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, reusable);
+        assert_eq!(idle(&pool), 0);
+
+        let (conn, _peer) = connection().await;
+        conn.start_request(None);
+        let reusable = !closes_connection(&HeaderMap::new(), Version::HTTP_11);
+        pool.release(KEY, Checkout::Owned(conn), &keepalive, reusable);
+        assert_eq!(idle(&pool), 1);
+    }
+}
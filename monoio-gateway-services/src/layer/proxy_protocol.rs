@@ -0,0 +1,97 @@
+use std::{future::Future, io::Cursor, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use log::debug;
+use monoio::io::{AsyncReadRent, PrefixedReadIo};
+use monoio_gateway_core::{
+    error::GError,
+    http::proxy_protocol::{parse_proxy_header, ProxyHeader},
+    service::Service,
+};
+
+use super::accept::Accept;
+
+/// bytes read at once while looking for the end of a header
+const READ_SIZE: usize = 256;
+
+/// Client stream with the bytes read after the PROXY protocol header replayed
+pub type ProxiedIo<S> = PrefixedReadIo<S, Cursor<Vec<u8>>>;
+/// Accepted connection with the client address of its PROXY protocol header
+/// in place of the peer, if the port reads one.
+pub type ProxiedAccept<S> = (ProxiedIo<S>, SocketAddr, Option<ProxyHeader>);
+
+/// Reads the PROXY protocol header of connections of ports behind a load
+/// balancer, other connections are passed through.
+#[derive(Clone, Default)]
+pub struct ProxyProtocolService {
+    enabled: bool,
+    timeout: Option<Duration>,
+}
+
+impl ProxyProtocolService {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            timeout: None,
+        }
+    }
+
+    /// Close connections without a whole header within `timeout`.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl<S> Service<Accept<S>> for ProxyProtocolService
+where
+    S: AsyncReadRent,
+{
+    type Response = ProxiedAccept<S>;
+
+    type Error = GError;
+
+    type Future<'cx> = impl Future<Output = Result<Self::Response, Self::Error>> + 'cx
+    where
+        Self: 'cx;
+
+    fn call(&mut self, accept: Accept<S>) -> Self::Future<'_> {
+        async move {
+            let (stream, peer) = accept;
+            if !self.enabled {
+                return Ok((PrefixedReadIo::new(stream, Cursor::new(vec![])), peer, None));
+            }
+            let read = read_proxy_header(stream);
+            let (stream, header) = match self.timeout {
+                Some(timeout) => match monoio::time::timeout(timeout, read).await {
+                    Ok(res) => res,
+                    Err(_) => bail!("no PROXY protocol header from {}", peer),
+                },
+                None => read.await,
+            }
+            .map_err(|err| anyhow!("{} from {}", err, peer))?;
+            let client = header.source.unwrap_or(peer);
+            debug!("{} proxied {} to {:?}", peer, client, header.destination);
+            Ok((stream, client, Some(header)))
+        }
+    }
+}
+
+/// Read the PROXY protocol header at the start of `stream`.
+async fn read_proxy_header<S>(mut stream: S) -> Result<(ProxiedIo<S>, ProxyHeader), GError>
+where
+    S: AsyncReadRent,
+{
+    let mut buf = vec![];
+    loop {
+        let (res, chunk) = stream.read(Vec::with_capacity(READ_SIZE)).await;
+        if res? == 0 {
+            bail!("connection closed before its PROXY protocol header");
+        }
+        buf.extend_from_slice(&chunk);
+        if let Some((header, len)) = parse_proxy_header(&buf)? {
+            buf.drain(..len);
+            return Ok((PrefixedReadIo::new(stream, Cursor::new(buf)), header));
+        }
+    }
+}
@@ -1,36 +1,56 @@
 use std::{
-    borrow::Borrow, cell::UnsafeCell, collections::HashMap, future::Future, path::Path, rc::Rc,
+    cell::Cell,
+    future::{poll_fn, Future},
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+    time::Instant,
 };
 
-use async_channel::Receiver;
-use bytes::Bytes;
-use http::{response::Builder, StatusCode};
+use anyhow::{anyhow, bail};
+use http::{
+    header::{CONNECTION, FORWARDED, HOST, STRICT_TRANSPORT_SECURITY},
+    HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Version,
+};
 use log::{debug, info};
 use monoio::{
+    buf::{IoBufMut, IoVecBufMut},
     io::{
         sink::{Sink, SinkExt},
         stream::Stream,
-        AsyncReadRent, AsyncWriteRent, Split, Splitable,
+        AsyncReadRent, AsyncWriteRent, PrefixedReadIo, Split, Splitable,
     },
     net::TcpStream,
+    BufResult,
 };
 use monoio_gateway_core::{
     acme::Acmed,
+    balance::balance::Upstream,
     dns::{http::Domain, Resolvable},
     error::GError,
     http::{
-        router::{RouterConfig, RouterRule},
+        action::{RequestVars, RuleAction},
+        error_page::{error_response, request_id, ErrorVars},
+        host::{normalize_host, strip_port, VirtualHosts},
+        proxy_protocol::ProxyHeader,
+        retry::{RetryOn, RetryPolicy},
+        router::{PathCaptures, RouterConfig, RouterRule},
+        static_files::{FileBody, StaticFiles},
+        timeout::{duration, Timeouts},
         Rewrite,
     },
     service::Service,
-    transfer::{copy_response_lock, generate_response},
+    transfer::{generate_redirect, generate_response},
     ACME_URI_PREFIX,
 };
 use monoio_http::{
     common::{request::Request, response::Response},
     h1::{
-        codec::{decoder::RequestDecoder, encoder::GenericEncoder},
-        payload::{FixedPayload, Payload},
+        codec::{
+            decoder::{DecodeError, FillPayload, RequestDecoder, ResponseDecoder},
+            encoder::GenericEncoder,
+        },
+        payload::Payload,
     },
 };
 
@@ -38,17 +58,21 @@ use crate::layer::endpoint::ConnectEndpoint;
 
 use super::{
     accept::Accept,
-    endpoint::{ClientConnectionType, EndpointRequestParams},
+    endpoint::{ClientConnectionType, EndpointRequestParams, Http1Connection},
+    h2::{
+        h1_request, h1_response, read_preface, send_body, server_handshake, H2Connection,
+        H2Responder,
+    },
+    pool::{Checkout, PooledConnection, SharedTcpConnectPool},
     tls::TlsAccept,
 };
 
-pub type SharedTcpConnectPool<I, O> =
-    Rc<UnsafeCell<HashMap<String, Rc<ClientConnectionType<I, O>>>>>;
-
 pub struct RouterService<A, I, O: AsyncWriteRent> {
-    routes: Rc<HashMap<String, RouterConfig<A>>>,
+    routes: Rc<VirtualHosts<A>>,
 
     connect_pool: SharedTcpConnectPool<I, O>,
+    /// PROXY protocol header of the connection to serve
+    proxy_header: Option<ProxyHeader>,
 }
 
 impl<A, I, O> Clone for RouterService<A, I, O>
@@ -59,6 +83,130 @@ where
         Self {
             routes: self.routes.clone(),
             connect_pool: self.connect_pool.clone(),
+            proxy_header: self.proxy_header.clone(),
+        }
+    }
+}
+
+/// Client side facts of a connection, shared by all of its requests.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    /// the client, the source of the PROXY protocol header if there is one
+    pub peer: SocketAddr,
+    pub tls: bool,
+    /// server name the client sent in the TLS handshake
+    pub sni: Option<String>,
+    /// PROXY protocol header sent by a load balancer in front
+    pub proxy: Option<ProxyHeader>,
+}
+
+impl ClientInfo {
+    #[inline]
+    pub fn default_port(&self) -> u16 {
+        if self.tls {
+            443
+        } else {
+            80
+        }
+    }
+
+    #[inline]
+    pub fn scheme(&self) -> &'static str {
+        if self.tls {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// Where the responses of a client connection are written.
+pub trait ResponseSink {
+    type Future<'a>: Future<Output = Result<(), GError>>
+    where
+        Self: 'a;
+
+    fn send_response(&mut self, response: Response<Payload>) -> Self::Future<'_>;
+}
+
+impl<O> ResponseSink for GenericEncoder<O>
+where
+    GenericEncoder<O>: Sink<Response<Payload>>,
+{
+    type Future<'a> = impl Future<Output = Result<(), GError>> + 'a
+    where
+        Self: 'a;
+
+    fn send_response(&mut self, response: Response<Payload>) -> Self::Future<'_> {
+        async move {
+            self.send_and_flush(response)
+                .await
+                .map_err(|_| anyhow!("failed to send response"))
+        }
+    }
+}
+
+/// Fills the streamed body of the request being served.
+pub trait RequestBody {
+    type Future<'a>: Future<Output = ()>
+    where
+        Self: 'a;
+
+    fn fill(&mut self) -> Self::Future<'_>;
+}
+
+impl<R: AsyncReadRent> RequestBody for RequestDecoder<R> {
+    type Future<'a> = impl Future<Output = ()> + 'a
+    where
+        Self: 'a;
+
+    fn fill(&mut self) -> Self::Future<'_> {
+        async move {
+            let _ = self.fill_payload().await;
+        }
+    }
+}
+
+/// Read half of a client connection noting when the bytes of the next
+/// request start to arrive, until then a kept alive connection is idle.
+struct ClientRead<R> {
+    inner: R,
+    /// when the first bytes were read since it was last reset
+    started: Rc<Cell<Option<Instant>>>,
+}
+
+impl<R> ClientRead<R> {
+    fn note(&self, res: &std::io::Result<usize>) {
+        if matches!(res, Ok(read) if *read > 0) && self.started.get().is_none() {
+            self.started.set(Some(Instant::now()));
+        }
+    }
+}
+
+impl<R: AsyncReadRent> AsyncReadRent for ClientRead<R> {
+    type ReadFuture<'a, T> = impl Future<Output = BufResult<usize, T>> + 'a
+    where
+        T: 'a,
+        Self: 'a;
+
+    type ReadvFuture<'a, T> = impl Future<Output = BufResult<usize, T>> + 'a
+    where
+        T: 'a,
+        Self: 'a;
+
+    fn read<T: IoBufMut>(&mut self, buf: T) -> Self::ReadFuture<'_, T> {
+        async move {
+            let (res, buf) = self.inner.read(buf).await;
+            self.note(&res);
+            (res, buf)
+        }
+    }
+
+    fn readv<T: IoVecBufMut>(&mut self, buf: T) -> Self::ReadvFuture<'_, T> {
+        async move {
+            let (res, buf) = self.inner.readv(buf).await;
+            self.note(&res);
+            (res, buf)
         }
     }
 }
@@ -66,7 +214,7 @@ where
 /// Direct use router before Accept
 impl<S> Service<Accept<S>> for RouterService<Domain, TcpStream, TcpStream>
 where
-    S: Split + AsyncReadRent + AsyncWriteRent + 'static,
+    S: Split + AsyncReadRent + AsyncWriteRent + Unpin + 'static,
 {
     type Response = ();
 
@@ -79,100 +227,40 @@ where
     fn call(&mut self, local_stream: Accept<S>) -> Self::Future<'_> {
         async move {
             let (stream, socketaddr) = local_stream;
-            let (local_read, local_write) = stream.into_split();
-            let mut local_decoder = RequestDecoder::new(local_read);
-            let local_encoder = Rc::new(UnsafeCell::new(GenericEncoder::new(local_write)));
-            let (tx, rx) = async_channel::bounded(1);
-            loop {
-                let connect_pool = self.connect_pool.clone();
-                match local_decoder.next().await {
-                    Some(Ok(req)) => {
-                        let req: Request<Payload> = req;
-                        let host = get_host(&req);
-                        match host {
-                            Some(host) => {
-                                let domain = Domain::with_uri(host.parse()?);
-                                let target = self.match_target(&host.to_owned());
-                                match target {
-                                    Some(target) => {
-                                        let m = longest_match(req.uri().path(), target.get_rules());
-                                        if let Some(rule) = m {
-                                            // parsed rule for this request and spawn task to handle endpoint connection
-                                            let proxy_pass = rule.get_proxy_pass().to_owned();
-                                            handle_endpoint_connection(
-                                                connect_pool,
-                                                &proxy_pass,
-                                                local_encoder.clone(),
-                                                req,
-                                                rx.clone(),
-                                            )
-                                            .await;
-                                            continue;
-                                        } else {
-                                            // no match router rule, is acme?
-                                            if let Ok(handled) = self
-                                                .handle_acme_verification(
-                                                    req,
-                                                    target,
-                                                    local_encoder.clone(),
-                                                )
-                                                .await
-                                            {
-                                                // no, is not acme, not find handler
-                                                if handled {
-                                                    continue;
-                                                }
-                                            }
-                                            debug!("no matching router rule, {}", domain);
-                                            let local_encoder =
-                                                unsafe { &mut *local_encoder.get() };
-                                            let _ = local_encoder.send_and_flush(
-                                                generate_response(StatusCode::NOT_FOUND),
-                                            );
-                                        }
-                                    }
-                                    None => {
-                                        debug!("no matching endpoint, ignoring {}", domain);
-                                        let local_encoder = unsafe { &mut *local_encoder.get() };
-                                        let _ = local_encoder.send_and_flush(generate_response(
-                                            StatusCode::NOT_FOUND,
-                                        ));
-                                    }
-                                }
-                            }
-                            None => {
-                                debug!("request has no host, uri: {}", req.uri());
-                                let local_encoder = unsafe { &mut *local_encoder.get() };
-                                let _ = local_encoder
-                                    .send_and_flush(generate_response(StatusCode::FORBIDDEN));
-                            }
-                        };
-                    }
-                    Some(Err(err)) => {
-                        // TODO: fallback to tcp
-                        log::warn!("{}", err);
-                        break;
-                    }
-                    None => {
-                        info!("http client {} closed", socketaddr);
-                        break;
-                    }
+            let client = ClientInfo {
+                peer: socketaddr,
+                tls: false,
+                sni: None,
+                proxy: self.proxy_header.take(),
+            };
+            let (h2c, stream) = if self.routes.http2().enabled {
+                let preface = read_preface(stream);
+                match duration(self.routes.timeouts().client_header) {
+                    Some(wait) => match monoio::time::timeout(wait, preface).await {
+                        Ok(read) => read?,
+                        Err(_) => {
+                            info!("no request from {} in {}ms", client.peer, wait.as_millis());
+                            return Ok(());
+                        }
+                    },
+                    None => preface.await?,
                 }
+            } else {
+                (false, PrefixedReadIo::new(stream, Cursor::new(vec![])))
+            };
+            if h2c {
+                return self.serve_h2(stream, client).await;
             }
-            // notify disconnect from endpoints
-            rx.close();
-            let _ = tx.send(()).await;
-            Ok(())
+            let (local_read, local_write) = stream.into_split();
+            self.serve(local_read, local_write, client).await
         }
     }
 }
 
 /// Direct use router before Accept
-///
-/// TODO: less copy code
 impl<S> Service<TlsAccept<S>> for RouterService<Domain, TcpStream, TcpStream>
 where
-    S: Split + AsyncReadRent + AsyncWriteRent + 'static,
+    S: Split + AsyncReadRent + AsyncWriteRent + Unpin + 'static,
 {
     type Response = ();
 
@@ -184,93 +272,291 @@ where
 
     fn call(&mut self, local_stream: TlsAccept<S>) -> Self::Future<'_> {
         async move {
-            let (stream, socketaddr, _) = local_stream;
+            let (stream, socketaddr) = local_stream;
+            let client = ClientInfo {
+                peer: socketaddr,
+                tls: true,
+                sni: stream.get_ref().1.sni_hostname().map(str::to_owned),
+                proxy: self.proxy_header.take(),
+            };
+            if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                return self.serve_h2(stream, client).await;
+            }
             let (local_read, local_write) = stream.split();
-            let mut local_decoder = RequestDecoder::new(local_read);
-            let local_encoder = Rc::new(UnsafeCell::new(GenericEncoder::new(local_write)));
-            // exit notifier
-            let (tx, rx) = async_channel::bounded(1);
-            loop {
-                let connect_pool = self.connect_pool.clone();
-                match local_decoder.next().await {
-                    Some(Ok(req)) => {
-                        let req: Request<Payload> = req;
-                        let host = get_host(&req);
-                        match host {
-                            Some(host) => {
-                                let domain = Domain::with_uri(host.parse()?);
-                                let target = self.match_target(&host.to_owned());
-                                match target {
-                                    Some(target) => {
-                                        let m = longest_match(req.uri().path(), target.get_rules());
-                                        if let Some(rule) = m {
-                                            // parsed rule for this request and spawn task to handle endpoint connection
-                                            let proxy_pass = rule.get_proxy_pass().to_owned();
-                                            handle_endpoint_connection(
-                                                connect_pool,
-                                                &proxy_pass,
-                                                local_encoder.clone(),
-                                                req,
-                                                rx.clone(),
-                                            )
-                                            .await;
-                                            continue;
-                                        } else {
-                                            // no match router rule, is acme?
-                                            if let Ok(handled) = self
-                                                .handle_acme_verification(
-                                                    req,
-                                                    target,
-                                                    local_encoder.clone(),
-                                                )
-                                                .await
-                                            {
-                                                // no, is not acme, not find handler
-                                                if handled {
-                                                    continue;
-                                                }
-                                            }
-                                            debug!("no matching router rule, {}", domain);
-                                            let local_encoder =
-                                                unsafe { &mut *local_encoder.get() };
-                                            let _ = local_encoder.send_and_flush(
-                                                generate_response(StatusCode::NOT_FOUND),
-                                            );
-                                        }
-                                    }
-                                    None => {
-                                        debug!("no matching endpoint, ignoring {}", domain);
-                                        let local_encoder = unsafe { &mut *local_encoder.get() };
-                                        let _ = local_encoder.send_and_flush(generate_response(
-                                            StatusCode::NOT_FOUND,
-                                        ));
-                                    }
-                                }
-                            }
-                            None => {
-                                debug!("request has no host, uri: {}", req.uri());
-                                let local_encoder = unsafe { &mut *local_encoder.get() };
-                                let _ = local_encoder
-                                    .send_and_flush(generate_response(StatusCode::FORBIDDEN));
+            self.serve(local_read, local_write, client).await
+        }
+    }
+}
+
+impl RouterService<Domain, TcpStream, TcpStream> {
+    /// serve http requests of a client connection until it closes
+    async fn serve<R, W>(
+        &mut self,
+        local_read: R,
+        local_write: W,
+        client: ClientInfo,
+    ) -> Result<(), GError>
+    where
+        R: AsyncReadRent,
+        W: AsyncWriteRent + 'static,
+        GenericEncoder<W>: Sink<Response<Payload>>,
+    {
+        let started = Rc::new(Cell::new(None));
+        let local_read = ClientRead {
+            inner: local_read,
+            started: started.clone(),
+        };
+        let mut local_decoder = RequestDecoder::new(local_read);
+        let mut local_encoder = GenericEncoder::new(local_write);
+        let timeouts = self.routes.timeouts();
+        let mut first = true;
+        loop {
+            let idle = Instant::now();
+            started.set(None);
+            let next = {
+                let next = local_decoder.next();
+                let mut next = std::pin::pin!(next);
+                loop {
+                    // the first request must arrive in time, later ones may
+                    // idle until they start and then have as long for the head
+                    let until = match (first, started.get()) {
+                        (true, _) => deadline(idle, timeouts.client_header),
+                        (false, None) => deadline(idle, timeouts.keepalive),
+                        (false, Some(at)) => deadline(at, timeouts.client_header),
+                    };
+                    let wait = match until {
+                        Some(until) if until <= Instant::now() => break None,
+                        Some(until) => until.saturating_duration_since(Instant::now()),
+                        // look again whether a request started meanwhile
+                        None if !first && started.get().is_none() => {
+                            match duration(timeouts.client_header) {
+                                Some(wait) => wait,
+                                None => break Some(next.as_mut().await),
                             }
-                        };
+                        }
+                        None => break Some(next.as_mut().await),
+                    };
+                    if let Ok(next) = monoio::time::timeout(wait, next.as_mut()).await {
+                        break Some(next);
                     }
-                    Some(Err(err)) => {
-                        log::warn!("{}", err);
+                }
+            };
+            let next = match next {
+                Some(next) => next,
+                None if first || started.get().is_some() => {
+                    info!("no whole request head from {} in time", client.peer);
+                    let _ = local_encoder
+                        .send_and_flush(closing_response(StatusCode::REQUEST_TIMEOUT))
+                        .await;
+                    break;
+                }
+                None => {
+                    info!("keep-alive of {} timed out", client.peer);
+                    break;
+                }
+            };
+            first = false;
+            match next {
+                Some(Ok(req)) => {
+                    let req: Request<Payload> = req;
+                    let close = self
+                        .handle(req, &client, &mut local_decoder, &mut local_encoder)
+                        .await;
+                    // the next request starts after the body of this one
+                    if close || !skip_body(&mut local_decoder, timeouts.client_body).await {
                         break;
                     }
-                    None => {
-                        info!("https client {} closed", socketaddr);
+                }
+                Some(Err(err)) => {
+                    // TODO: fallback to tcp
+                    log::warn!("{}", err);
+                    break;
+                }
+                None => {
+                    info!("http client {} closed", client.peer);
+                    break;
+                }
+            }
+        }
+        log::info!("bye {}! Now we remove router", client.peer);
+        Ok(())
+    }
+
+    /// serve the request streams of an h2 client connection until it closes
+    async fn serve_h2<S>(&mut self, io: S, client: ClientInfo) -> Result<(), GError>
+    where
+        S: AsyncReadRent + AsyncWriteRent + Unpin + 'static,
+    {
+        let timeouts = self.routes.timeouts();
+        let handshake = server_handshake(io, &self.routes.http2());
+        let mut conn = match duration(timeouts.client_header) {
+            Some(wait) => match monoio::time::timeout(wait, handshake).await {
+                Ok(conn) => conn?,
+                Err(_) => bail!(
+                    "no h2 handshake from {} in {}ms",
+                    client.peer,
+                    wait.as_millis()
+                ),
+            },
+            None => handshake.await?,
+        };
+        info!("h2 client {} connected", client.peer);
+        // streams being served, the connection idles without any
+        let open = Rc::new(Cell::new(0_usize));
+        loop {
+            let next = match duration(timeouts.keepalive) {
+                Some(wait) => match monoio::time::timeout(wait, conn.accept()).await {
+                    Ok(next) => next,
+                    Err(_) if open.get() > 0 => continue,
+                    Err(_) => {
+                        info!("keep-alive of {} timed out", client.peer);
+                        conn.graceful_shutdown();
+                        let _ = poll_fn(|cx| conn.poll_closed(cx)).await;
                         break;
                     }
+                },
+                None => conn.accept().await,
+            };
+            match next {
+                Some(Ok((request, respond))) => {
+                    let router = self.clone();
+                    let client = client.clone();
+                    let open = open.clone();
+                    open.set(open.get() + 1);
+                    monoio::spawn(async move {
+                        let (req, mut body) = h1_request(request);
+                        let mut responder = H2Responder::new(respond);
+                        router.handle(req, &client, &mut body, &mut responder).await;
+                        open.set(open.get() - 1);
+                    });
+                }
+                Some(Err(err)) => {
+                    log::warn!("h2 connection of {} failed: {}", client.peer, err);
+                    break;
+                }
+                None => {
+                    info!("h2 client {} closed", client.peer);
+                    break;
                 }
             }
-            log::info!("bye {}! Now we remove router", socketaddr);
-            // notify disconnect from endpoints
-            rx.close();
-            let _ = tx.send(()).await;
-            Ok(())
         }
+        log::info!("bye {}! Now we remove router", client.peer);
+        Ok(())
+    }
+
+    /// Serve `req` of `client` and write its response to `encoder`.
+    ///
+    /// Returns whether the connection is to be closed.
+    async fn handle<B, E>(
+        &self,
+        req: Request<Payload>,
+        client: &ClientInfo,
+        body: &mut B,
+        encoder: &mut E,
+    ) -> bool
+    where
+        B: RequestBody,
+        E: ResponseSink,
+    {
+        let mut req = req;
+        let client_close = closes_connection(req.headers(), req.version());
+        let request_id = request_id(&mut req);
+        let host = get_host(&req)
+            .and_then(|host| normalize_host(host, client.default_port()))
+            .unwrap_or_default();
+        let path = req.uri().path().to_string();
+        let head = req.method() == Method::HEAD;
+        let reply_error = |server: Option<&RouterConfig<Domain>>, status| {
+            let vars = ErrorVars {
+                status,
+                request_id: &request_id,
+                host: strip_port(&host),
+                path: &path,
+            };
+            error_response(server.and_then(|s| s.error_page(status)), &vars, head)
+        };
+        let target = match self.route_host(&req, client) {
+            Ok(target) => target,
+            Err(status) => {
+                debug!("reply {} to {}, uri: {}", status, client.peer, req.uri());
+                let resp = reply_error(self.routes.fallback(), status);
+                let _ = encoder.send_response(resp).await;
+                return client_close;
+            }
+        };
+        let target = match target {
+            Some(target) => target,
+            None => {
+                debug!("no matching endpoint, ignoring {:?}", get_host(&req));
+                let resp = reply_error(self.routes.fallback(), StatusCode::NOT_FOUND);
+                let _ = encoder.send_response(resp).await;
+                return client_close;
+            }
+        };
+        if target.redirect_https && !client.tls && !req.uri().path().starts_with(ACME_URI_PREFIX) {
+            let _ = encoder.send_response(https_redirect(&req)).await;
+            return client_close;
+        }
+        let mut extra_headers = response_headers(target, client);
+        if client_close {
+            extra_headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+        let (rule, captures) = match match_rule(&req, target) {
+            Some(m) => m,
+            None => {
+                // no match router rule, is acme?
+                let uri = req.uri().to_owned();
+                if let Ok(handled) = self.handle_acme_verification(req, target, encoder).await {
+                    // no, is not acme, not find handler
+                    if handled {
+                        return client_close;
+                    }
+                }
+                debug!("no matching router rule, {}", uri);
+                let resp = reply_error(Some(target), StatusCode::NOT_FOUND);
+                let _ = encoder.send_response(resp).await;
+                return client_close;
+            }
+        };
+        let (mut resp, file_body) = match &rule.action {
+            Some(action) => {
+                Rewrite::rewrite_path(&mut req, None, rule.rewrite.as_ref(), &captures);
+                action_response(&req, action, &captures, client).await
+            }
+            None => {
+                let mut forward = Forward {
+                    connect_pool: self.connect_pool.clone(),
+                    rule,
+                    client,
+                    body,
+                    encoder: &mut *encoder,
+                    extra_headers: &extra_headers,
+                    timeouts: target.timeouts.with_route(rule.timeouts.as_ref()),
+                    cut_short: false,
+                };
+                let status = match forward.proxy(req, captures).await {
+                    Ok(()) => return client_close || forward.cut_short,
+                    Err(status) => status,
+                };
+                let mut resp = reply_error(Some(target), status);
+                if status == StatusCode::REQUEST_TIMEOUT {
+                    // the rest of the body is not read
+                    resp.headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                }
+                (resp, None)
+            }
+        };
+        resp.headers_mut().extend(extra_headers);
+        let close = matches!(
+            resp.headers().get(CONNECTION),
+            Some(value) if value == "close"
+        );
+        let sent = match file_body {
+            Some(file_body) => monoio::join!(file_body.feed(), encoder.send_response(resp)).1,
+            None => encoder.send_response(resp).await,
+        };
+        close || sent.is_err()
     }
 }
 
@@ -279,208 +565,872 @@ where
     A: Resolvable,
     O: AsyncWriteRent,
 {
-    pub fn new(routes: Rc<HashMap<String, RouterConfig<A>>>) -> Self {
+    pub fn new(routes: Rc<VirtualHosts<A>>) -> Self {
         Self {
             routes,
             connect_pool: Default::default(),
+            proxy_header: None,
         }
     }
 
+    /// Share upstream connections with other routers, those of a worker by
+    /// [`worker_pool`](super::pool::worker_pool).
+    pub fn with_connect_pool(mut self, connect_pool: SharedTcpConnectPool<I, O>) -> Self {
+        self.connect_pool = connect_pool;
+        self
+    }
+
+    /// Serve a connection whose peer was taken from `header`.
+    pub fn with_proxy_header(mut self, header: Option<ProxyHeader>) -> Self {
+        self.proxy_header = header;
+        self
+    }
+
     #[inline]
-    fn match_target(&self, host: &String) -> Option<&RouterConfig<A>> {
+    fn match_target(&self, host: &str) -> Option<&RouterConfig<A>> {
         self.routes.get(host)
     }
 
+    /// Select the server of `req` by its host, or the status to reply with.
+    fn route_host(
+        &self,
+        req: &Request<Payload>,
+        client: &ClientInfo,
+    ) -> Result<Option<&RouterConfig<A>>, StatusCode> {
+        let host = get_host(req)
+            .and_then(|host| normalize_host(host, client.default_port()))
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let target = self.match_target(&host);
+        if let (Some(target), Some(sni)) = (target, &client.sni) {
+            let strict_sni = target.tls.as_ref().map(|tls| tls.strict_sni);
+            if strict_sni.unwrap_or(false) {
+                let by_sni = normalize_host(sni, client.default_port())
+                    .and_then(|sni| self.match_target(&sni));
+                if !by_sni.map(|s| std::ptr::eq(s, target)).unwrap_or(false) {
+                    return Err(StatusCode::MISDIRECTED_REQUEST);
+                }
+            }
+        }
+        Ok(target)
+    }
+
     /// if not handled, return false to continue handler
-    async fn handle_acme_verification<IO: Sink<Response>>(
+    async fn handle_acme_verification<E: ResponseSink>(
         &self,
         req: Request<Payload>,
         conf: &RouterConfig<A>,
-        encoder: Rc<UnsafeCell<IO>>,
+        encoder: &mut E,
     ) -> Result<bool, GError> {
-        let encoder = unsafe { &mut *encoder.get() };
+        if conf.tls.is_none() || !req.uri().path().starts_with(ACME_URI_PREFIX) {
+            return Ok(false);
+        }
         let name = conf.server_name.get_acme_path()?;
-        let p = Path::new(&name);
-        match &conf.tls {
-            Some(_) => {
-                let req_path = req.uri().path().to_string();
-                log::info!("acme: request path: {}", req_path);
-                if req_path.starts_with(ACME_URI_PREFIX) {
-                    // read files
-                    let abs_path = p.join(&req_path[1..]);
-                    log::info!("acme: read path: {:?}", abs_path);
-                    let mut file_bytes = vec![];
-                    match monoio::fs::File::open(Path::new(&abs_path)).await {
-                        Ok(challenge_file) => {
-                            let mut pos = 0;
-                            loop {
-                                let buf = vec![0 as u8; 1024];
-                                let (n, mut read) = challenge_file.read_at(buf, pos).await;
-                                let n = n? as u64;
-                                if n == 0 {
-                                    // EOF, let's send our challenge now.
-                                    break;
-                                }
-                                pos += n;
-                                unsafe { read.set_len(n as usize) };
-                                file_bytes.append(&mut read);
-                            }
-                            let bytes = Bytes::from(file_bytes);
-                            let response = Builder::new()
-                                .body(Payload::Fixed(FixedPayload::new(bytes)))
-                                .unwrap();
-                            let _ = encoder.send_and_flush(response).await;
-                            info!("acme challenge replied");
-                            return Ok(true);
-                        }
-                        Err(e) => {
-                            log::warn!("find acme file error: {}", e);
-                            let data = Bytes::from_static(b"404 not found --- Monoio Gateway.");
-                            let response = Builder::new()
-                                .body(Payload::Fixed(FixedPayload::new(data)))
-                                .unwrap();
-                            let _ = encoder.send_and_flush(response).await;
-                        }
-                    }
-                }
-            }
-            _ => {}
+        log::info!("acme: request path: {}", req.uri().path());
+        let challenge = StaticFiles::new(name.to_string_lossy());
+        // challenge tokens are small, they are never sent chunked
+        let (response, _) = challenge.serve(&req).await;
+        if response.status() == StatusCode::OK {
+            info!("acme challenge replied");
+        } else {
+            log::warn!("acme challenge {} replied {}", req.uri(), response.status());
         }
-        Ok(false)
+        let _ = encoder.send_response(response).await;
+        Ok(true)
     }
 }
 
 #[inline]
-fn longest_match<'cx>(
-    req_path: &'cx str,
-    routes: &'cx Vec<RouterRule<Domain>>,
-) -> Option<&'cx RouterRule<Domain>> {
-    log::info!("request path: {}", req_path);
-    // TODO: opt progress
+fn match_rule<'cx>(
+    req: &Request<Payload>,
+    target: &'cx RouterConfig<Domain>,
+) -> Option<(&'cx RouterRule<Domain>, PathCaptures)> {
+    let req_path = req.uri().path();
+    log::debug!("request path: {}", req_path);
     if req_path.starts_with(ACME_URI_PREFIX) {
         return None;
     }
-    let mut target_route = None;
-    let mut route_len = 0;
-    for route in routes.iter() {
-        let route_path = route.get_path();
-        let route_path_len = route_path.len();
-        if req_path.starts_with(route_path) && route_path_len > route_len {
-            target_route = Some(route);
-            route_len = route_path_len;
+    target.match_rule(req)
+}
+
+/// reply of a rule with an action instead of `proxy_pass`, and the body
+/// of a static file to feed while it is sent
+async fn action_response(
+    req: &Request<Payload>,
+    action: &RuleAction,
+    captures: &PathCaptures,
+    client: &ClientInfo,
+) -> (Response<Payload>, Option<FileBody>) {
+    match action {
+        RuleAction::Redirect(redirect) => {
+            let host = get_host(req)
+                .and_then(|host| normalize_host(host, client.default_port()))
+                .unwrap_or_default();
+            let vars = RequestVars {
+                scheme: client.scheme(),
+                host: &host,
+                path: req.uri().path(),
+                query: req.uri().query(),
+                captures,
+            };
+            let location = redirect.location(&vars);
+            debug!("redirect {} to {}", req.uri(), location);
+            (generate_redirect(redirect.status(), &location), None)
         }
+        RuleAction::Static(files) => files.serve(req).await,
+        RuleAction::Return(response) => (response.response(req), None),
     }
-    target_route
 }
 
+/// `308` to the https url of a plain http request, the host is already checked by `route_host`
+fn https_redirect(req: &Request<Payload>) -> Response<Payload> {
+    let host = get_host(req)
+        .and_then(|host| normalize_host(host, 80))
+        .unwrap_or_default();
+    let request_uri = req
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    let location = format!("https://{}{}", strip_port(&host), request_uri);
+    debug!("redirect {} to {}", req.uri(), location);
+    generate_redirect(StatusCode::PERMANENT_REDIRECT, &location)
+}
+
+/// headers added to every response of `target` for `client`
+fn response_headers(target: &RouterConfig<Domain>, client: &ClientInfo) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let hsts = target.tls.as_ref().and_then(|tls| tls.hsts.as_ref());
+    if let (true, Some(hsts)) = (client.tls, hsts) {
+        headers.insert(STRICT_TRANSPORT_SECURITY, hsts.header_value());
+    }
+    headers
+}
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// Tell upstreams who `client` is, the source of its PROXY protocol header
+/// if there is one.
+///
+/// The client address is appended to `X-Forwarded-For` and `Forwarded`, with
+/// the scheme and host it asked for, and replaces `X-Real-IP`.
+fn add_forwarded_headers(req: &mut Request<Payload>, client: &ClientInfo) {
+    let ip = client.peer.ip();
+    let mut forwarded = match ip {
+        IpAddr::V4(ip) => format!("for={};proto={}", ip, client.scheme()),
+        IpAddr::V6(ip) => format!("for=\"[{}]\";proto={}", ip, client.scheme()),
+    };
+    if let Some(host) = get_host(req) {
+        forwarded.push_str(";host=");
+        forwarded.push_str(&forwarded_value(host));
+    }
+    let headers = req.headers_mut();
+    append_header(headers, X_FORWARDED_FOR, &ip.to_string());
+    append_header(headers, FORWARDED, &forwarded);
+    if let Ok(ip) = HeaderValue::from_str(&ip.to_string()) {
+        headers.insert(X_REAL_IP, ip);
+    }
+}
+
+/// Add `element` to the list of header `name`, its lines joined in one.
+fn append_header(headers: &mut HeaderMap, name: HeaderName, element: &str) {
+    let mut list: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    list.push(element);
+    if let Ok(value) = HeaderValue::from_str(&list.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+/// `value` as a token of a `Forwarded` element, quoted unless it is one
+/// already (RFC 7239)
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        return value.to_owned();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The authority of an absolute-form request uri, or the `Host` header.
+///
+/// Like RFC 7230 section 5.4, the request uri wins over a `Host` header
+/// naming another host.
 #[inline]
 fn get_host(req: &Request<Payload>) -> Option<&str> {
-    match req.headers().get("host") {
-        Some(host) => Some(host.to_str().unwrap_or("")),
-        None => None,
+    req.uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| {
+            req.headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .filter(|host| !host.is_empty())
+        })
+}
+
+/// Why a request could not be proxied, nothing was sent to the client yet.
+enum ForwardError {
+    /// the circuit breaker of the upstream group has no connection left
+    CircuitOpen,
+    Connect(GError),
+    ConnectTimeout,
+    /// the connection failed before a response was received
+    Upstream(GError),
+    /// no response head within the header or per try timeout
+    Timeout,
+    /// the client did not send the request body in time
+    ClientTimeout,
+    /// a response of this status was dropped to retry the request
+    Status(StatusCode),
+}
+
+impl ForwardError {
+    /// status replied to the client
+    fn status(&self) -> StatusCode {
+        match self {
+            ForwardError::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            ForwardError::Connect(_) | ForwardError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ForwardError::ConnectTimeout | ForwardError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ForwardError::ClientTimeout => StatusCode::REQUEST_TIMEOUT,
+            ForwardError::Status(status) => *status,
+        }
+    }
+
+    /// retry condition of the error, an open circuit or a slow client is
+    /// never retried
+    fn retry_on(&self) -> Option<RetryOn> {
+        match self {
+            ForwardError::CircuitOpen | ForwardError::ClientTimeout => None,
+            ForwardError::Connect(_) | ForwardError::ConnectTimeout => Some(RetryOn::ConnectError),
+            ForwardError::Upstream(_) => Some(RetryOn::Reset),
+            ForwardError::Timeout => Some(RetryOn::Timeout),
+            ForwardError::Status(status) => RetryOn::of_status(*status),
+        }
+    }
+}
+
+impl std::fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardError::CircuitOpen => write!(f, "circuit breaker open"),
+            ForwardError::Connect(err) => write!(f, "connect failed: {}", err),
+            ForwardError::ConnectTimeout => write!(f, "connect timeout"),
+            ForwardError::Upstream(err) => write!(f, "{}", err),
+            ForwardError::Timeout => write!(f, "response timeout"),
+            ForwardError::ClientTimeout => write!(f, "request body timeout"),
+            ForwardError::Status(status) => write!(f, "upstream replied {}", status),
+        }
     }
 }
 
-/// handle backward connections and send request to endpoint.
-/// This function use spawn feature of monoio and will not block caller.
-async fn handle_endpoint_connection<O>(
+/// Proxying of a request of a client connection to the upstream of a rule.
+struct Forward<'a, B, E> {
     connect_pool: SharedTcpConnectPool<TcpStream, TcpStream>,
-    proxy_pass: &Domain,
-    encoder: Rc<UnsafeCell<GenericEncoder<O>>>,
-    mut request: Request<Payload>,
-    rx: Receiver<()>,
-) where
-    O: AsyncWriteRent + 'static,
-    GenericEncoder<O>: monoio::io::sink::Sink<Response<Payload>>,
+    rule: &'a RouterRule<Domain>,
+    client: &'a ClientInfo,
+    /// filling the streamed body of the request
+    body: &'a mut B,
+    encoder: &'a mut E,
+    extra_headers: &'a HeaderMap,
+    /// timeouts of the server with those of the rule
+    timeouts: Timeouts,
+    /// whether a forwarded response was cut short, the client can not tell
+    /// where the next one would start
+    cut_short: bool,
+}
+
+impl<'a, B, E> Forward<'a, B, E>
+where
+    B: RequestBody,
+    E: ResponseSink,
 {
-    // we add a write lock to prevent multiple context execute into block below.
-    {
-        let connect_pool = unsafe { &mut *connect_pool.get() };
-        // critical code start
-        if !connect_pool.contains_key(proxy_pass.host()) {
-            // hold endpoint request, prevent
-            log::info!(
-                "{} endpoint connections not exists, try connect now. [{:?}]",
-                proxy_pass.host(),
-                connect_pool.keys()
-            );
-            // open channel
-            let proxy_pass_domain = proxy_pass.clone();
-            let local_encoder_clone = encoder.clone();
-            // no connections
-            let mut connect_svc = ConnectEndpoint::default();
-            if let Ok(Some(conn)) = connect_svc
-                .call(EndpointRequestParams {
-                    endpoint: proxy_pass.clone(),
-                })
+    /// Proxy `req`, retried by the retry policy of the rule.
+    ///
+    /// Returns the status to reply with if no upstream response was forwarded.
+    async fn proxy(
+        &mut self,
+        mut req: Request<Payload>,
+        captures: PathCaptures,
+    ) -> Result<(), StatusCode> {
+        let (rule, client_ip) = (self.rule, self.client.peer.ip());
+        add_forwarded_headers(&mut req, self.client);
+        let retry = rule.retry.as_ref().filter(|retry| {
+            retry.retries_method(req.method()) && matches!(req.body(), Payload::None)
+        });
+        let retry = match retry {
+            Some(retry) => retry,
+            None => {
+                let proxy_pass = rule
+                    .select_upstream(&req, client_ip)
+                    .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+                return self
+                    .attempt(&proxy_pass, req, &captures, None, true)
+                    .await
+                    .map_err(|err| err.status());
+            }
+        };
+        let _request = retry.track();
+        let mut tried = vec![];
+        let mut _retry = None;
+        let mut attempt = 1;
+        loop {
+            let proxy_pass = match attempt {
+                1 => rule.select_upstream(&req, client_ip),
+                _ => rule.select_retry(&req, client_ip, &tried),
+            }
+            .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+            let last = attempt >= retry.max_attempts;
+            let err = match self
+                .attempt(
+                    &proxy_pass,
+                    copy_request(&req),
+                    &captures,
+                    Some(retry),
+                    last,
+                )
                 .await
             {
-                let conn = Rc::new(conn);
-                connect_pool.insert(proxy_pass_domain.host().to_owned(), conn.clone());
-                // endpoint -> proxy -> client
-                let _connect_pool_cloned = connect_pool.clone();
-                let rx_clone = rx.clone();
-                monoio::spawn(async move {
-                    match conn.borrow() {
-                        ClientConnectionType::Http(i, _) => {
-                            let cloned = Rc::downgrade(&local_encoder_clone);
-                            monoio::select! {
-                                _ = copy_response_lock(i.clone(), local_encoder_clone, proxy_pass_domain.clone()) => {}
-                                _ = rx_clone.recv() => {
-                                    log::info!("client exit, now cancelling endpoint connection");
-                                    if let Some(sender) = cloned.upgrade() {
-                                        let sender = unsafe{&mut *sender.get()};
-                                        let _ = sender.close().await;
-                                    }
-                                }
-                            };
-                        }
-                        ClientConnectionType::Tls(i, _) => {
-                            let cloned = Rc::downgrade(&local_encoder_clone);
-                            monoio::select! {
-                                _ = copy_response_lock(i.clone(), local_encoder_clone, proxy_pass_domain.clone()) => {}
-                                _ = rx_clone.recv() => {
-                                    log::info!("client exit, now cancelling endpoint connection");
-                                    if let Some(sender) = cloned.upgrade() {
-                                        let sender = unsafe{&mut *sender.get()};
-                                        let _ = sender.close().await;
-                                    }
-                                }
-                            };
-                        }
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if let Upstream::Balanced(endpoint) = &proxy_pass {
+                tried.push(endpoint.endpoint().clone());
+            }
+            if last || !matches!(err.retry_on(), Some(condition) if retry.retries_on(condition)) {
+                return Err(err.status());
+            }
+            _retry = match retry.acquire_retry() {
+                Some(slot) => Some(slot),
+                None => {
+                    log::warn!("retry budget used up, {} is not retried", req.uri());
+                    return Err(err.status());
+                }
+            };
+            let backoff = retry.backoff(attempt);
+            log::info!(
+                "attempt {} of {} failed: {}, retry in {}ms",
+                attempt,
+                req.uri(),
+                err,
+                backoff.as_millis()
+            );
+            monoio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Send `req` to `proxy_pass` once and report the result to its group.
+    async fn attempt(
+        &mut self,
+        proxy_pass: &Upstream<'_, Domain>,
+        mut req: Request<Payload>,
+        captures: &PathCaptures,
+        retry: Option<&RetryPolicy>,
+        last: bool,
+    ) -> Result<(), ForwardError> {
+        Rewrite::rewrite_path(
+            &mut req,
+            proxy_pass.base_path(),
+            self.rule.rewrite.as_ref(),
+            captures,
+        );
+        req.extensions_mut().insert(captures.clone());
+        let started = Instant::now();
+        let result = self.forward(proxy_pass, req, retry, last).await;
+        // a balanced endpoint counts as outstanding until here,
+        // an open circuit or a slow client says nothing about the endpoint
+        if let (Some(group), Upstream::Balanced(endpoint)) = (self.rule.get_upstream(), proxy_pass)
+        {
+            if !matches!(
+                result,
+                Err(ForwardError::CircuitOpen | ForwardError::ClientTimeout)
+            ) {
+                let success = matches!(result, Ok(status) if !status.is_server_error());
+                group.report(endpoint.endpoint(), success, started.elapsed());
+            }
+        }
+        if let Err(err) = &result {
+            log::warn!("proxy to {} failed: {}", **proxy_pass, err);
+        }
+        result.map(|_| ())
+    }
+
+    /// Send `request` to `proxy_pass` and forward its response to the client.
+    ///
+    /// Returns the status of the forwarded response. Connections of the group
+    /// are limited by its circuit breaker, broken connections leave the pool.
+    async fn forward(
+        &mut self,
+        proxy_pass: &Domain,
+        mut request: Request<Payload>,
+        retry: Option<&RetryPolicy>,
+        last: bool,
+    ) -> Result<StatusCode, ForwardError> {
+        let params = EndpointRequestParams::new(proxy_pass.clone())
+            .with_tls(self.rule.get_upstream_tls().cloned())
+            .with_protocol(self.rule.get_upstream_protocol());
+        let key = params.pool_key();
+        let keepalive = self.rule.get_upstream_keepalive();
+        let pooled = self.connect_pool.borrow_mut().acquire(&key);
+        let mut conn = match pooled {
+            Some(conn) => {
+                log::info!("🚀 endpoint connection found for {}!", proxy_pass);
+                conn
+            }
+            None => {
+                let slot = match self.rule.get_upstream() {
+                    Some(group) => Some(
+                        group
+                            .acquire_connection()
+                            .ok_or(ForwardError::CircuitOpen)?,
+                    ),
+                    None => None,
+                };
+                log::info!(
+                    "{} endpoint connections not exists, try connect now.",
+                    proxy_pass.host()
+                );
+                let mut connect_svc = ConnectEndpoint::default();
+                let connect = connect_svc.call(params);
+                let connected = match duration(self.timeouts.upstream_connect) {
+                    Some(timeout) => monoio::time::timeout(timeout, connect)
+                        .await
+                        .map_err(|_| ForwardError::ConnectTimeout)?,
+                    None => connect.await,
+                };
+                let conn = match connected {
+                    Ok(Some(conn)) => conn,
+                    Ok(None) => {
+                        return Err(ForwardError::Connect(anyhow!(
+                            "{} is not resolved",
+                            proxy_pass
+                        )))
                     }
-                    // remove proxy pass endpoint
-                    connect_pool.remove(proxy_pass_domain.host());
-                    log::info!("🗑 remove {} from endpoint pool", &proxy_pass_domain);
-                });
-            } else {
-                // connect endpoint failed
-                let encoder = unsafe { &mut *encoder.get() };
-                let _ = encoder
-                    .send_and_flush(generate_response(StatusCode::NOT_FOUND))
-                    .await;
+                    Err(err) => return Err(ForwardError::Connect(err)),
+                };
+                let conn = PooledConnection::new(conn, slot);
+                self.connect_pool.borrow_mut().checkout(&key, conn)
             }
-        } else {
-            log::info!("🚀 endpoint connection found for {}!", proxy_pass);
-        }
-    }
-    let connect_pool = unsafe { &mut *connect_pool.get() };
-    if let Some(conn) = connect_pool.get(proxy_pass.host()) {
-        // send this request to endpoint
-        let conn = conn.clone();
-        let proxy_pass_domain = proxy_pass.clone();
-        monoio::spawn(async move {
-            Rewrite::rewrite_request(&mut request, &proxy_pass_domain);
-            match conn.borrow() {
-                ClientConnectionType::Http(_, sender) => {
-                    let sender = unsafe { &mut *sender.get() };
-                    let _ = sender.send_and_flush(request).await;
+        };
+        conn.start_request(duration(self.timeouts.upstream_idle));
+        Rewrite::rewrite_request(&mut request, proxy_pass);
+        strip_keepalive_headers(request.headers_mut());
+        let result = match &mut conn {
+            Checkout::Owned(owned) => match &mut owned.conn {
+                ClientConnectionType::Http(http) => {
+                    self.exchange(http, request, proxy_pass, retry, last).await
                 }
-                ClientConnectionType::Tls(_, sender) => {
-                    let sender = unsafe { &mut *sender.get() };
-                    let _ = sender.send_and_flush(request).await;
+                ClientConnectionType::Tls(http) => {
+                    self.exchange(http, request, proxy_pass, retry, last).await
                 }
-            }
-        });
+                ClientConnectionType::Unix(http) => {
+                    self.exchange(http, request, proxy_pass, retry, last).await
+                }
+                ClientConnectionType::H2(_) => unreachable!("h2 connections are shared"),
+            },
+            // a failed stream leaves its connection usable
+            Checkout::Shared(shared) => match &shared.conn {
+                ClientConnectionType::H2(h2) => self
+                    .exchange_h2(h2, request, proxy_pass, retry, last)
+                    .await
+                    .map(|status| (status, true)),
+                _ => unreachable!("HTTP/1 connections are owned"),
+            },
+        };
+        let reusable = match &result {
+            Ok((_, reusable)) => *reusable,
+            Err(_) => conn.is_shared(),
+        };
+        if !reusable {
+            log::info!("🗑 close connection of {}", proxy_pass);
+        }
+        self.connect_pool
+            .borrow_mut()
+            .release(&key, conn, &keepalive, reusable);
+        result.map(|(status, _)| status)
+    }
+
+    /// One request and its response on an upstream connection.
+    ///
+    /// Returns the status of the response and whether the connection can
+    /// send another request.
+    async fn exchange<R, W>(
+        &mut self,
+        http: &mut Http1Connection<R, W>,
+        request: Request<Payload>,
+        proxy_pass: &Domain,
+        retry: Option<&RetryPolicy>,
+        last: bool,
+    ) -> Result<(StatusCode, bool), ForwardError>
+    where
+        ResponseDecoder<R>: Stream<Item = Result<Response<Payload>, DecodeError>> + FillPayload,
+        GenericEncoder<W>: Sink<Request<Payload>>,
+    {
+        let Http1Connection {
+            decoder, sender, ..
+        } = http;
+        let head = response_head(
+            decoder,
+            sender,
+            &mut *self.body,
+            request,
+            proxy_pass,
+            &self.timeouts,
+        );
+        let mut response = match retry.and_then(|retry| retry.per_try_timeout()) {
+            Some(timeout) => match monoio::time::timeout(timeout, head).await {
+                Ok(response) => response?,
+                Err(_) => return Err(ForwardError::Timeout),
+            },
+            None => head.await?,
+        };
+        let status = response.status();
+        if !last && matches!(retry, Some(retry) if retry.retries_status(status)) {
+            // the body is not read, the connection leaves the pool
+            return Err(ForwardError::Status(status));
+        }
+        let upstream_close = closes_connection(response.headers(), response.version());
+        strip_keepalive_headers(response.headers_mut());
+        Rewrite::rewrite_response(&mut response, proxy_pass);
+        for (name, value) in self.extra_headers.iter() {
+            response.headers_mut().insert(name, value.clone());
+        }
+        log::info!("response code: {},{:?}", status, response.headers());
+        let (filled, sent) =
+            monoio::join!(decoder.fill_payload(), self.encoder.send_response(response));
+        // a body not read to its end is left on the connection
+        let complete = filled.is_ok() && sent.is_ok();
+        self.cut_short = !complete;
+        Ok((status, !upstream_close && complete))
+    }
+
+    /// one request and its response on a stream of an h2 connection
+    async fn exchange_h2(
+        &mut self,
+        h2: &H2Connection,
+        request: Request<Payload>,
+        proxy_pass: &Domain,
+        retry: Option<&RetryPolicy>,
+        last: bool,
+    ) -> Result<StatusCode, ForwardError> {
+        let head = h2_response_head(h2, &mut *self.body, request, proxy_pass, &self.timeouts);
+        let response = match retry.and_then(|retry| retry.per_try_timeout()) {
+            Some(timeout) => match monoio::time::timeout(timeout, head).await {
+                Ok(response) => response?,
+                Err(_) => return Err(ForwardError::Timeout),
+            },
+            None => head.await?,
+        };
+        let status = response.status();
+        if !last && matches!(retry, Some(retry) if retry.retries_status(status)) {
+            // dropping the response resets its stream only
+            return Err(ForwardError::Status(status));
+        }
+        let (mut response, body) = h1_response(response);
+        Rewrite::rewrite_response(&mut response, proxy_pass);
+        for (name, value) in self.extra_headers.iter() {
+            response.headers_mut().insert(name, value.clone());
+        }
+        log::info!("response code: {},{:?}", status, response.headers());
+        let sent = match body {
+            Some(body) => monoio::join!(body, self.encoder.send_response(response)).1,
+            None => self.encoder.send_response(response).await,
+        };
+        self.cut_short = sent.is_err();
+        Ok(status)
+    }
+}
+
+/// send `request` and wait for the head of its response
+async fn response_head<D, S, B>(
+    decoder: &mut D,
+    sender: &mut S,
+    body: &mut B,
+    request: Request<Payload>,
+    proxy_pass: &Domain,
+    timeouts: &Timeouts,
+) -> Result<Response<Payload>, ForwardError>
+where
+    D: Stream<Item = Result<Response<Payload>, DecodeError>>,
+    S: Sink<Request<Payload>>,
+    B: RequestBody,
+{
+    // the body is streamed from the client while it is sent
+    let client_body =
+        duration(timeouts.client_body).filter(|_| !matches!(request.body(), Payload::None));
+    let send = async {
+        let (_, sent) = monoio::join!(body.fill(), sender.send_and_flush(request));
+        sent
+    };
+    let sent = match client_body {
+        Some(timeout) => monoio::time::timeout(timeout, send)
+            .await
+            .map_err(|_| ForwardError::ClientTimeout)?,
+        None => send.await,
+    };
+    if sent.is_err() {
+        return Err(ForwardError::Upstream(anyhow!(
+            "failed to send request to {}",
+            proxy_pass
+        )));
+    }
+    let head = match duration(timeouts.upstream_header) {
+        Some(timeout) => monoio::time::timeout(timeout, decoder.next())
+            .await
+            .map_err(|_| ForwardError::Timeout)?,
+        None => decoder.next().await,
+    };
+    match head {
+        Some(Ok(response)) => Ok(response),
+        Some(Err(err)) => Err(ForwardError::Upstream(anyhow!(
+            "invalid response from {}: {}",
+            proxy_pass,
+            err
+        ))),
+        None => Err(ForwardError::Upstream(anyhow!(
+            "{} closed the connection",
+            proxy_pass
+        ))),
+    }
+}
+
+/// send `request` on a new h2 stream and wait for the head of its response
+async fn h2_response_head<B: RequestBody>(
+    h2: &H2Connection,
+    body: &mut B,
+    request: Request<Payload>,
+    proxy_pass: &Domain,
+    timeouts: &Timeouts,
+) -> Result<http::Response<h2::RecvStream>, ForwardError> {
+    let client_body =
+        duration(timeouts.client_body).filter(|_| !matches!(request.body(), Payload::None));
+    let (head, stream) = h2.send(request, proxy_pass).await.map_err(|err| {
+        ForwardError::Upstream(anyhow!("h2 stream to {} failed: {}", proxy_pass, err))
+    })?;
+    if let Some((payload, stream)) = stream {
+        let send = async {
+            let (_, sent) = monoio::join!(body.fill(), send_body(payload, stream));
+            sent
+        };
+        let sent = match client_body {
+            Some(timeout) => monoio::time::timeout(timeout, send)
+                .await
+                .map_err(|_| ForwardError::ClientTimeout)?,
+            None => send.await,
+        };
+        if let Err(err) = sent {
+            return Err(ForwardError::Upstream(anyhow!(
+                "failed to send request to {}: {}",
+                proxy_pass,
+                err
+            )));
+        }
+    }
+    let head = match duration(timeouts.upstream_header) {
+        Some(timeout) => monoio::time::timeout(timeout, head)
+            .await
+            .map_err(|_| ForwardError::Timeout)?,
+        None => head.await,
+    };
+    head.map_err(|err| {
+        ForwardError::Upstream(anyhow!("invalid response from {}: {}", proxy_pass, err))
+    })
+}
+
+/// `millis` after `start`, `None` for a disabled timeout
+fn deadline(start: Instant, millis: u64) -> Option<Instant> {
+    duration(millis).map(|wait| start + wait)
+}
+
+/// Read what is left of the body of the request being served, whether it was
+/// done within `timeout` milliseconds.
+async fn skip_body<B: RequestBody>(body: &mut B, timeout: u64) -> bool {
+    match duration(timeout) {
+        Some(timeout) => monoio::time::timeout(timeout, body.fill()).await.is_ok(),
+        None => {
+            body.fill().await;
+            true
+        }
+    }
+}
+
+/// Whether the sender of a message closes its connection after it.
+pub(super) fn closes_connection(headers: &HeaderMap, version: Version) -> bool {
+    let mut options = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    match version {
+        Version::HTTP_10 => !options.any(|option| option.eq_ignore_ascii_case("keep-alive")),
+        _ => options.any(|option| option.eq_ignore_ascii_case("close")),
+    }
+}
+
+/// Remove the headers about the connection a message came on, connections
+/// of clients and upstreams are kept alive on their own.
+fn strip_keepalive_headers(headers: &mut HeaderMap) {
+    headers.remove(CONNECTION);
+    headers.remove("keep-alive");
+}
+
+/// a response after which the connection is closed
+fn closing_response(status: StatusCode) -> Response<Payload> {
+    let mut resp = generate_response(status);
+    resp.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+    resp
+}
+
+/// a request without body sent again by a retry
+fn copy_request(req: &Request<Payload>) -> Request<Payload> {
+    let mut copy = Request::new(Payload::None);
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::LOCATION;
+    use monoio_gateway_core::http::action::Redirect;
+
+    use super::*;
+
+    fn request(uri: &str, host: Option<&str>) -> Request<Payload> {
+        let mut req = Request::new(Payload::None);
+        *req.uri_mut() = uri.parse().unwrap();
+        if let Some(host) = host {
+            req.headers_mut()
+                .insert(HOST, HeaderValue::from_str(host).unwrap());
+        }
+        req
+    }
+
+    #[test]
+    fn host_of_absolute_uri_wins() {
+        let req = request("http://a.example.com/x", Some("b.example.com"));
+        assert_eq!(get_host(&req), Some("a.example.com"));
+        let req = request("/x", Some("b.example.com"));
+        assert_eq!(get_host(&req), Some("b.example.com"));
+        let req = request("/x", Some(""));
+        assert_eq!(get_host(&req), None);
+    }
+
+    fn client(peer: &str, tls: bool) -> ClientInfo {
+        ClientInfo {
+            peer: peer.parse().unwrap(),
+            tls,
+            sni: None,
+            proxy: None,
+        }
+    }
+
+    #[test]
+    fn forwarded_headers() {
+        let mut req = request("/x", Some("example.com:8080"));
+        add_forwarded_headers(&mut req, &client("192.0.2.1:5000", false));
+        assert_eq!(req.headers()[&X_FORWARDED_FOR], "192.0.2.1");
+        assert_eq!(req.headers()[&X_REAL_IP], "192.0.2.1");
+        assert_eq!(
+            req.headers()[FORWARDED],
+            "for=192.0.2.1;proto=http;host=\"example.com:8080\""
+        );
+
+        // appended to those of proxies in front, the real ip is replaced
+        let mut req = request("/x", Some("example.com"));
+        let headers = req.headers_mut();
+        headers.append(&X_FORWARDED_FOR, HeaderValue::from_static("203.0.113.9"));
+        headers.append(&X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.1"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=203.0.113.9"));
+        headers.insert(&X_REAL_IP, HeaderValue::from_static("203.0.113.9"));
+        add_forwarded_headers(&mut req, &client("[2001:db8::1]:5000", true));
+        assert_eq!(
+            req.headers()[&X_FORWARDED_FOR],
+            "203.0.113.9, 10.0.0.1, 2001:db8::1"
+        );
+        assert_eq!(req.headers()[&X_REAL_IP], "2001:db8::1");
+        assert_eq!(
+            req.headers()[FORWARDED],
+            "for=203.0.113.9, for=\"[2001:db8::1]\";proto=https;host=example.com"
+        );
+    }
+
+    #[monoio::test]
+    async fn redirect_drops_default_port() {
+        let action = RuleAction::Redirect(Redirect::new(
+            "$scheme://$host$request_uri",
+            StatusCode::MOVED_PERMANENTLY,
+        ));
+        let captures = PathCaptures::default();
+        for (tls, host, location) in [
+            (false, "example.com:80", "http://example.com/a?b=1"),
+            (false, "example.com:443", "http://example.com:443/a?b=1"),
+            (false, "Example.com:8443", "http://example.com:8443/a?b=1"),
+            (true, "example.com:443", "https://example.com/a?b=1"),
+            (true, "example.com:80", "https://example.com:80/a?b=1"),
+            (true, "example.com:8443", "https://example.com:8443/a?b=1"),
+        ] {
+            let req = request("/a?b=1", Some(host));
+            let client = client("192.0.2.1:5000", tls);
+            let (response, file) = action_response(&req, &action, &captures, &client).await;
+            assert!(file.is_none());
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(response.headers()[LOCATION], location, "{}", host);
+        }
+
+        // the https url of a plain request has no port
+        let response = https_redirect(&request("/a?b=1", Some("example.com:8080")));
+        assert_eq!(response.headers()[LOCATION], "https://example.com/a?b=1");
+    }
+
+    #[test]
+    fn forward_error_status() {
+        let status = |err: ForwardError| err.status();
+        assert_eq!(
+            status(ForwardError::Connect(anyhow!("connection refused"))),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            status(ForwardError::Upstream(anyhow!("connection reset"))),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            status(ForwardError::CircuitOpen),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(ForwardError::ConnectTimeout),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(status(ForwardError::Timeout), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            status(ForwardError::ClientTimeout),
+            StatusCode::REQUEST_TIMEOUT
+        );
+        // a dropped response is replied as it was
+        assert_eq!(
+            status(ForwardError::Status(StatusCode::SERVICE_UNAVAILABLE)),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
     }
 }
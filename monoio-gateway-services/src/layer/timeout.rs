@@ -26,17 +26,10 @@ where
 
     fn call(&mut self, req: R) -> Self::Future<'_> {
         async {
-            monoio::select! {
-                _ = monoio::time::timeout(self.timeout, async {}) => {
-                    return Err(anyhow::anyhow!("timeout"))
-                }
-
-                ret = self.inner.call(req) => {
-                    return match ret {
-                        Ok(resp) => Ok(Some(resp)),
-                        Err(err) => Err(anyhow::anyhow!("{}", err)),
-                    }
-                }
+            match monoio::time::timeout(self.timeout, self.inner.call(req)).await {
+                Ok(Ok(resp)) => Ok(Some(resp)),
+                Ok(Err(err)) => Err(anyhow::anyhow!("{}", err)),
+                Err(_) => Err(anyhow::anyhow!("timeout after {:?}", self.timeout)),
             }
         }
     }
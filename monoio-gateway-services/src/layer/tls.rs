@@ -1,4 +1,4 @@
-use std::{future::Future, marker::PhantomData, net::SocketAddr};
+use std::{future::Future, net::SocketAddr, time::Duration};
 
 use anyhow::bail;
 use log::info;
@@ -15,13 +15,15 @@ use rustls::{Certificate, PrivateKey, ServerConfig};
 use super::accept::Accept;
 
 pub type CertItem = (Vec<Certificate>, PrivateKey);
-pub type TlsAccept<S> = (ServerTlsStream<S>, SocketAddr, PhantomData<S>);
+pub type TlsAccept<S> = (ServerTlsStream<S>, SocketAddr);
 
 #[derive(Clone)]
 pub struct TlsService<T> {
     // enable_client_auth: bool,
     // cert
     config: Option<ServerConfig>,
+    handshake_timeout: Option<Duration>,
+    alpn_protocols: Vec<Vec<u8>>,
     inner: T,
 }
 
@@ -49,31 +51,47 @@ where
 
     fn call(&mut self, accept: Accept<S>) -> Self::Future<'_> {
         let tls_config = self.config.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let alpn_protocols = self.alpn_protocols.clone();
         async move {
             info!("begin handshake");
             let tls_acceptor: TlsAcceptor;
             match tls_config {
-                Some(tls_config) => {
+                Some(mut tls_config) => {
+                    tls_config.alpn_protocols = alpn_protocols;
                     tls_acceptor = TlsAcceptor::from(tls_config);
                 }
                 None => {
                     // default acme cert
-                    let config = ServerConfig::builder()
+                    let mut config = ServerConfig::builder()
                         .with_safe_defaults()
                         .with_no_client_auth()
                         .with_cert_resolver(CERTIFICATE_RESOLVER.clone());
+                    config.alpn_protocols = alpn_protocols;
 
                     tls_acceptor = TlsAcceptor::from(config);
                 }
             }
-            match tls_acceptor.accept(accept.0).await {
-                Ok(stream) => match self.inner.call((stream, accept.1, PhantomData)).await {
-                    Ok(resp) => Ok(resp),
-                    Err(err) => {
-                        bail!("{}", err)
+            let (stream, socketaddr) = accept;
+            let handshake = tls_acceptor.accept(stream);
+            let handshake = match handshake_timeout {
+                Some(handshake_timeout) => {
+                    match monoio::time::timeout(handshake_timeout, handshake).await {
+                        Ok(res) => res,
+                        Err(_) => bail!("tls handshake timeout from {}", socketaddr),
                     }
-                },
+                }
+                None => handshake.await,
+            };
+            let stream = match handshake {
+                Ok(stream) => stream,
                 Err(err) => bail!("tls error: {:?}", err),
+            };
+            match self.inner.call((stream, socketaddr)).await {
+                Ok(resp) => Ok(resp),
+                Err(err) => {
+                    bail!("{}", err)
+                }
             }
         }
     }
@@ -84,6 +102,8 @@ pub struct TlsLayer {
     enable_client_auth: bool,
     // cert
     config: Option<ServerConfig>,
+    handshake_timeout: Option<Duration>,
+    alpn_protocols: Vec<Vec<u8>>,
 }
 
 impl TlsLayer {
@@ -107,6 +127,8 @@ impl TlsLayer {
         Ok(Self {
             config: Some(config),
             enable_client_auth: false,
+            handshake_timeout: None,
+            alpn_protocols: vec![],
         })
     }
 
@@ -115,10 +137,24 @@ impl TlsLayer {
         self
     }
 
+    /// Protocols selected by ALPN, in order of preference.
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    /// Close clients not done with the handshake within `timeout`.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
     pub fn new() -> Self {
         Self {
             config: None,
             enable_client_auth: false,
+            handshake_timeout: None,
+            alpn_protocols: vec![],
         }
     }
 }
@@ -130,6 +166,8 @@ impl<S> Layer<S> for TlsLayer {
         TlsService {
             // enable_client_auth: self.enable_client_auth,
             config: self.config.clone(),
+            handshake_timeout: self.handshake_timeout,
+            alpn_protocols: self.alpn_protocols.clone(),
             inner: service,
         }
     }
@@ -12,11 +12,20 @@ use monoio_gateway::{
     init_env,
 };
 use monoio_gateway_core::{
-    dns::{http::Domain, Resolvable},
+    balance::health::check_upstream,
+    dns::{
+        http::Domain,
+        resolver::{set_resolver, Resolver, ResolverConfig},
+        Resolvable,
+    },
     error::GError,
-    http::router::{Router, RouterConfig, RoutersConfig},
+    http::{
+        admin::serve_admin,
+        router::{Router, RouterConfig, RoutersConfig},
+    },
     max_parallel_count, print_logo, Builder, MAX_IOURING_ENTRIES,
 };
+use monoio_gateway_services::layer::pool::{sweep_idle, worker_pool};
 
 use serde::de::DeserializeOwned;
 
@@ -39,10 +48,20 @@ async fn main() -> Result<()> {
     // read config from file
     let configs = load_runtime::<Domain>(&args).await?;
     // build runtime
-    let router = Router::build_with_config(configs);
+    let router = match Router::build_with_config(configs) {
+        Ok(router) => router,
+        Err(err) => {
+            log::error!("invalid config: {}", err);
+            bail!("{}", err);
+        }
+    };
+    // read once here, workers must not block on files
+    let dns = ResolverConfig::system();
+    start_health_checks(&router, dns.clone());
+    start_admin(&router);
     // start service
     let gws = Gateway::from_router(router);
-    serve_gateway(gws);
+    serve_gateway(gws, dns);
     Ok(())
 }
 
@@ -60,8 +79,55 @@ where
     }
 }
 
+/// Probe upstream groups with health checks on a dedicated thread
+fn start_health_checks(router: &Router<Domain>, dns: ResolverConfig) {
+    let groups: Vec<_> = router
+        .upstreams()
+        .values()
+        .filter(|group| group.health_check().is_some())
+        .cloned()
+        .collect();
+    if groups.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut rt = RuntimeBuilder::<monoio::IoUringDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            set_resolver(Resolver::new(dns));
+            let checks: Vec<_> = groups
+                .into_iter()
+                .map(|group| monoio::spawn(check_upstream(group)))
+                .collect();
+            for check in checks {
+                check.await;
+            }
+        });
+    });
+}
+
+/// Serve the admin endpoint on a dedicated thread
+fn start_admin(router: &Router<Domain>) {
+    let config = match router.admin() {
+        Some(config) => config.clone(),
+        None => return,
+    };
+    thread::spawn(move || {
+        let mut rt = RuntimeBuilder::<monoio::IoUringDriver>::new()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            if let Err(err) = serve_admin(config).await {
+                log::error!("admin endpoint error: {}", err);
+            }
+        });
+    });
+}
+
 /// Serve Monoio-Gateway with maximum parallel count
-fn serve_gateway<A>(gws: Vec<Gateway<A>>)
+fn serve_gateway<A>(gws: Vec<Gateway<A>>, dns: ResolverConfig)
 where
     A: Resolvable + Send + 'static,
     Gateway<A>: Gatewayable<A>,
@@ -74,6 +140,7 @@ where
     );
     for _ in 0..parallel_cnt {
         let local_gws = gws.clone();
+        let local_dns = dns.clone();
         let handler = thread::spawn(move || {
             let mut rt = RuntimeBuilder::<monoio::IoUringDriver>::new()
                 .enable_timer()
@@ -81,6 +148,8 @@ where
                 .build()
                 .unwrap();
             rt.block_on(async move {
+                set_resolver(Resolver::new(local_dns));
+                monoio::spawn(sweep_idle(worker_pool()));
                 match local_gws.serve().await {
                     Ok(_) => {}
                     Err(err) => {
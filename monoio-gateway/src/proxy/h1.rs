@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::future::Future;
 use std::io::{self, Cursor};
 use std::path::Path;
@@ -14,12 +13,16 @@ use monoio_gateway_core::config::ProxyConfig;
 use monoio_gateway_core::dns::http::Domain;
 
 use monoio_gateway_core::error::GError;
+use monoio_gateway_core::http::host::VirtualHosts;
 use monoio_gateway_core::http::router::RouterConfig;
+use monoio_gateway_core::http::timeout::duration;
 
 use monoio_gateway_core::service::{Service, ServiceBuilder};
 
 use monoio_gateway_services::layer::accept::{Accept, TcpAcceptService};
 use monoio_gateway_services::layer::detect::DetectService;
+use monoio_gateway_services::layer::pool::worker_pool;
+use monoio_gateway_services::layer::proxy_protocol::ProxyProtocolService;
 use monoio_gateway_services::layer::router::RouterService;
 use monoio_gateway_services::layer::tls::TlsLayer;
 
@@ -37,12 +40,9 @@ impl Proxy for HttpProxy {
 
     fn io_loop(&self) -> Self::OutputFuture<'_> {
         async {
-            let mut route_map = HashMap::<String, RouterConfig<Domain>>::new();
-            for route in self.config.iter() {
-                route_map.insert(route.server_name.to_owned(), route.to_owned());
-            }
-            let route_wrapper = Rc::new(route_map);
-            let listen_addr = format!("0.0.0.0:{}", self.get_listen_port().unwrap());
+            let listen_port = self.get_listen_port().unwrap();
+            let route_wrapper = Rc::new(VirtualHosts::new(self.config.clone(), listen_port)?);
+            let listen_addr = format!("0.0.0.0:{}", listen_port);
             let listener = TcpListener::bind_with_config(listen_addr, &ListenerConfig::default());
             if let Err(e) = listener {
                 bail!("Error when binding address({})", e);
@@ -59,8 +59,19 @@ impl Proxy for HttpProxy {
                 match svc.call(listener_wrapper.clone()).await {
                     Ok(accept) => {
                         monoio::spawn(async move {
+                            let mut proxy_protocol =
+                                ProxyProtocolService::new(route_cloned.proxy_protocol())
+                                    .with_timeout(duration(route_cloned.timeouts().client_header));
+                            let (stream, socketaddr, proxy_header) =
+                                match proxy_protocol.call(accept).await {
+                                    Ok(accept) => accept,
+                                    Err(err) => {
+                                        log::warn!("{}", err);
+                                        return;
+                                    }
+                                };
                             let mut detect = DetectService::new_http_detect();
-                            match detect.call(accept).await {
+                            match detect.call((stream, socketaddr)).await {
                                 Ok(ty) => match ty {
                                     Some(detect) => {
                                         let (ty, stream, socketaddr) = detect;
@@ -69,8 +80,11 @@ impl Proxy for HttpProxy {
                                         match ty {
                                             monoio_gateway_core::http::version::Type::HTTP => {
                                                 info!("a http client detected");
-                                                let mut handler = handler
-                                                    .service(RouterService::new(route_cloned));
+                                                let mut handler = handler.service(
+                                                    RouterService::new(route_cloned)
+                                                        .with_connect_pool(worker_pool())
+                                                        .with_proxy_header(proxy_header),
+                                                );
                                                 match handler.call(acc).await {
                                                     Ok(_) => {
                                                         info!("✔ complete connection");
@@ -82,9 +96,23 @@ impl Proxy for HttpProxy {
                                             }
                                             monoio_gateway_core::http::version::Type::HTTPS => {
                                                 info!("a https client detected");
+                                                let handshake_timeout =
+                                                    duration(route_cloned.timeouts().tls_handshake);
                                                 let mut handler = ServiceBuilder::new()
-                                                    .layer(TlsLayer::new())
-                                                    .service(RouterService::new(route_cloned));
+                                                    .layer(
+                                                        TlsLayer::new()
+                                                            .with_handshake_timeout(
+                                                                handshake_timeout,
+                                                            )
+                                                            .with_alpn_protocols(
+                                                                route_cloned.http2().alpn(),
+                                                            ),
+                                                    )
+                                                    .service(
+                                                        RouterService::new(route_cloned)
+                                                            .with_connect_pool(worker_pool())
+                                                            .with_proxy_header(proxy_header),
+                                                    );
                                                 match handler.call(acc).await {
                                                     Ok(_) => {
                                                         info!("✔ complete connection");
@@ -162,15 +190,25 @@ fn configure_acme(config: &Vec<RouterConfig<Domain>>) {
                     "🚀 ssl certificates for {} existed, let's load it.",
                     conf.server_name
                 );
-                let content = key_content.unwrap();
-                update_certificate(
-                    conf.server_name.to_owned(),
-                    Cursor::new(pem_content.unwrap()),
-                    Cursor::new(content),
-                );
+                let (pem, key) = (pem_content.unwrap(), key_content.unwrap());
+                // regex names can not be looked up by sni
+                for name in conf.names().filter(|name| !name.starts_with('~')) {
+                    update_certificate(
+                        name.to_owned(),
+                        Cursor::new(pem.clone()),
+                        Cursor::new(key.clone()),
+                    );
+                }
                 info!("🚀 ssl certificates for {} loaded.", conf.server_name);
                 continue;
             }
+            if conf.server_name.starts_with(['*', '~']) {
+                log::warn!(
+                    "acme: {} is not a plain domain, please provide chain and private_key",
+                    conf.server_name
+                );
+                continue;
+            }
             info!(
                 "{} has no local ssl certificate, prepare requesting acme.",
                 conf.server_name
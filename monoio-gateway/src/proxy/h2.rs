@@ -1 +1,4 @@
-
+//! HTTP/2 clients are served by the listeners of [`super::h1::HttpProxy`]:
+//! tls ports offer `h2` by ALPN and plain ports detect the connection preface
+//! of prior knowledge h2c, then the streams go through the same router as
+//! HTTP/1 requests.
@@ -1,5 +1,6 @@
 use std::{future::Future, net::SocketAddr, str::FromStr};
 
+use anyhow::bail;
 use monoio::{
     io::Splitable,
     net::{ListenerConfig, TcpListener, TcpStream},
@@ -78,7 +79,10 @@ impl TcpProxy {
 
     #[inline]
     pub async fn outbound_addr(&self) -> Result<TcpAddress, GError> {
-        Ok(self.config.rules.first().unwrap().proxy_pass)
+        match self.config.rules.first().unwrap().proxy_pass {
+            Some(addr) => Ok(addr),
+            None => bail!("tcp proxy rule has no proxy_pass"),
+        }
     }
 
     pub fn configure(&mut self) {}